@@ -1,3 +1,92 @@
+use anyhow::{anyhow, Result};
+use std::str::FromStr;
+
 const AWS_DEFAULT_REGION: &str = "us-east-1";
+const AWS_US_GOV_DEFAULT_REGION: &str = "us-gov-west-1";
+const AWS_CN_DEFAULT_REGION: &str = "cn-north-1";
 
 pub mod role;
+pub mod sts;
+
+// The partitions of AWS, each with its own regions and STS endpoints
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Partition {
+    Aws,
+    AwsUsGov,
+    AwsCn,
+}
+
+impl FromStr for Partition {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "aws" => Ok(Partition::Aws),
+            "aws-us-gov" => Ok(Partition::AwsUsGov),
+            "aws-cn" => Ok(Partition::AwsCn),
+            _ => Err(anyhow!("Unsupported AWS partition {}", s)),
+        }
+    }
+}
+
+impl Partition {
+    // Reads the partition from an ARN such as
+    // arn:aws-us-gov:iam::123456789012:role/admin
+    pub fn from_arn(arn: &str) -> Result<Partition> {
+        match arn.split(':').collect::<Vec<&str>>().as_slice() {
+            ["arn", partition, ..] => partition.parse(),
+            _ => Err(anyhow!("Not an ARN: {}", arn)),
+        }
+    }
+
+    pub fn default_region(&self) -> &'static str {
+        match self {
+            Partition::Aws => AWS_DEFAULT_REGION,
+            Partition::AwsUsGov => AWS_US_GOV_DEFAULT_REGION,
+            Partition::AwsCn => AWS_CN_DEFAULT_REGION,
+        }
+    }
+
+    pub fn contains_region(&self, region: &str) -> bool {
+        match self {
+            Partition::Aws => !region.starts_with("us-gov-") && !region.starts_with("cn-"),
+            Partition::AwsUsGov => region.starts_with("us-gov-"),
+            Partition::AwsCn => region.starts_with("cn-"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn derives_partition_from_arn() -> Result<()> {
+        assert_eq!(
+            Partition::from_arn("arn:aws:iam::123456789012:role/admin")?,
+            Partition::Aws
+        );
+        assert_eq!(
+            Partition::from_arn("arn:aws-us-gov:iam::123456789012:role/admin")?,
+            Partition::AwsUsGov
+        );
+        assert_eq!(
+            Partition::from_arn("arn:aws-cn:iam::123456789012:role/admin")?,
+            Partition::AwsCn
+        );
+        assert!(Partition::from_arn("arn:aws-iso:iam::123456789012:role/admin").is_err());
+        assert!(Partition::from_arn("role/admin").is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn matches_regions_to_partitions() {
+        assert!(Partition::Aws.contains_region("eu-central-1"));
+        assert!(!Partition::Aws.contains_region("us-gov-east-1"));
+        assert!(Partition::AwsUsGov.contains_region("us-gov-east-1"));
+        assert!(!Partition::AwsUsGov.contains_region("us-east-1"));
+        assert!(Partition::AwsCn.contains_region("cn-northwest-1"));
+        assert_eq!(Partition::AwsUsGov.default_region(), "us-gov-west-1");
+    }
+}
@@ -1,8 +1,16 @@
-use crate::aws::AWS_DEFAULT_REGION;
-use anyhow::{anyhow, Error, Result};
+use crate::aws::sts::StsClient;
+use crate::aws::Partition;
+use crate::config::app::AppProfile;
+use crate::credentials::aws::AwsCredentials;
+use anyhow::{anyhow, Context, Error, Result};
 use aws_config::meta::region::RegionProviderChain;
-use aws_sdk_sts::output::AssumeRoleWithSamlOutput;
-use aws_sdk_sts::Region;
+use aws_config::SdkConfig;
+use aws_sdk_sts::error::AssumeRoleWithSAMLError;
+use aws_sdk_sts::output::{AssumeRoleOutput, AssumeRoleWithSamlOutput};
+use aws_sdk_sts::types::SdkError;
+use aws_sdk_sts::{Credentials, Region};
+use log::debug;
+use serde::{Deserialize, Serialize};
 
 use std::str::FromStr;
 use std::{fmt, str};
@@ -14,6 +22,72 @@ pub struct Role {
     pub role_arn: String,
 }
 
+const DEFAULT_SESSION_NAME: &str = "crowbar";
+// AWS caps sessions of chained roles at one hour
+pub const MAX_CHAINED_DURATION: i32 = 3600;
+
+// A role assumed with sts:AssumeRole after the role picked from the SAML
+// assertion, e.g. to reach a workload account
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Hash, Clone)]
+pub struct ChainedRole {
+    pub role_arn: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub external_id: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub session_name: Option<String>,
+}
+
+// Where STS is reached for a profile. Without a region, the one from the
+// environment is used if it belongs to the partition of the role, otherwise
+// the partition's default region
+#[derive(Debug, PartialEq, Eq, Clone, Default)]
+pub struct StsEndpoint {
+    pub region: Option<String>,
+    pub url: Option<String>,
+}
+
+impl From<&AppProfile> for StsEndpoint {
+    fn from(profile: &AppProfile) -> StsEndpoint {
+        StsEndpoint {
+            region: profile.region.clone(),
+            url: profile.sts_endpoint.clone(),
+        }
+    }
+}
+
+impl StsEndpoint {
+    async fn load_config(
+        &self,
+        role_arn: &str,
+        credentials: Option<Credentials>,
+    ) -> Result<SdkConfig> {
+        let partition = Partition::from_arn(role_arn)?;
+        let region = match &self.region {
+            Some(region) => Region::new(region.clone()),
+            None => match RegionProviderChain::default_provider().region().await {
+                Some(region) if partition.contains_region(region.as_ref()) => region,
+                _ => Region::new(partition.default_region()),
+            },
+        };
+
+        let mut loader = aws_config::from_env().region(region);
+        if let Some(url) = &self.url {
+            loader = loader.endpoint_url(url);
+        }
+        if let Some(credentials) = credentials {
+            loader = loader.credentials_provider(credentials);
+        }
+
+        Ok(loader.load().await)
+    }
+}
+
+impl fmt::Display for ChainedRole {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.role_arn)
+    }
+}
+
 impl fmt::Display for Role {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(f, "{}", self.role_arn)
@@ -44,25 +118,96 @@ pub fn assume_role(
         role_arn,
     }: &Role,
     saml_assertion: String,
+    duration: Option<i32>,
+    endpoint: &StsEndpoint,
 ) -> Result<AssumeRoleWithSamlOutput, Error> {
     let runtime = Runtime::new()?;
     runtime.block_on(async {
-        let region_provider =
-            RegionProviderChain::default_provider().or_else(Region::new(AWS_DEFAULT_REGION));
-        let config = aws_config::from_env().region(region_provider).load().await;
+        let config = endpoint.load_config(role_arn, None).await?;
         let client = aws_sdk_sts::Client::new(&config)
             .assume_role_with_saml()
             .principal_arn(provider_arn)
             .role_arn(role_arn)
-            .saml_assertion(saml_assertion);
+            .saml_assertion(saml_assertion)
+            .set_duration_seconds(duration);
+
+        client.send().await.map_err(|e| e.into())
+    })
+}
+
+// STS answers with a validation error when DurationSeconds exceeds the
+// maximum session duration of the role
+pub fn is_duration_rejected(error: &Error) -> bool {
+    match error.downcast_ref::<SdkError<AssumeRoleWithSAMLError>>() {
+        Some(SdkError::ServiceError(context)) => {
+            let error = context.err();
+            error.code() == Some("ValidationError")
+                && error
+                    .message()
+                    .is_some_and(|m| m.contains("DurationSeconds"))
+        }
+        _ => false,
+    }
+}
+
+pub fn assume_chained_role(
+    role: &ChainedRole,
+    credentials: &AwsCredentials,
+    duration: Option<i32>,
+    endpoint: &StsEndpoint,
+) -> Result<AssumeRoleOutput, Error> {
+    let credentials = Credentials::new(
+        credentials
+            .access_key_id
+            .clone()
+            .with_context(|| "Missing access key to assume chained role")?,
+        credentials
+            .secret_access_key
+            .clone()
+            .with_context(|| "Missing secret key to assume chained role")?,
+        credentials.session_token.clone(),
+        None,
+        "crowbar",
+    );
+
+    let runtime = Runtime::new()?;
+    runtime.block_on(async {
+        let config = endpoint
+            .load_config(&role.role_arn, Some(credentials))
+            .await?;
+        let client = aws_sdk_sts::Client::new(&config)
+            .assume_role()
+            .role_arn(&role.role_arn)
+            .role_session_name(role.session_name.as_deref().unwrap_or(DEFAULT_SESSION_NAME))
+            .set_external_id(role.external_id.clone())
+            .set_duration_seconds(duration);
 
         client.send().await.map_err(|e| e.into())
     })
 }
 
+// Assumes the chained roles in order, each with the credentials of the one
+// before, and returns the credentials of the last role
+pub fn assume_role_chain(
+    credentials: AwsCredentials,
+    chain: &[ChainedRole],
+    duration: Option<i32>,
+    sts: &dyn StsClient,
+) -> Result<AwsCredentials> {
+    let duration = duration.map(|d| d.min(MAX_CHAINED_DURATION));
+
+    chain.iter().try_fold(credentials, |credentials, role| {
+        debug!("Assuming chained role {}", role);
+
+        sts.assume_role(role, &credentials, duration)
+            .with_context(|| format!("Error assuming chained role {}", role))
+    })
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::aws::sts::FakeStsClient;
 
     #[test]
     fn parse_attribute() {
@@ -82,6 +227,104 @@ mod tests {
         )
     }
 
+    #[test]
+    fn returns_credentials_for_empty_chain() -> Result<()> {
+        let credentials = AwsCredentials {
+            access_key_id: Some("access-key".to_string()),
+            ..Default::default()
+        };
+
+        assert_eq!(
+            assume_role_chain(credentials.clone(), &[], None, &FakeStsClient::new())?,
+            credentials
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn assumes_chained_roles_in_order() -> Result<()> {
+        let sts = FakeStsClient::new();
+        let chain = ["workload", "admin"].map(|name| ChainedRole {
+            role_arn: format!("arn:aws:iam::123456789012:role/{}", name),
+            external_id: None,
+            session_name: None,
+        });
+        let credentials = sts.assume_role_with_saml(&create_role(), "assertion", None)?;
+
+        let credentials = assume_role_chain(credentials, &chain, Some(43200), &sts)?;
+
+        assert_eq!(
+            credentials.access_key_id.as_deref(),
+            Some("ASIAFAKE000000000003")
+        );
+        assert_eq!(
+            sts.calls()
+                .into_iter()
+                .map(|c| (c.role_arn, c.duration))
+                .collect::<Vec<(String, Option<i32>)>>(),
+            vec![
+                ("arn:aws:iam::123456789012:role/role1".to_string(), None),
+                (
+                    "arn:aws:iam::123456789012:role/workload".to_string(),
+                    Some(3600)
+                ),
+                (
+                    "arn:aws:iam::123456789012:role/admin".to_string(),
+                    Some(3600)
+                ),
+            ]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn assumes_role_through_custom_endpoint() -> Result<()> {
+        let mut server = mockito::Server::new();
+        let mock = server
+            .mock("POST", "/")
+            .match_body(mockito::Matcher::AllOf(vec![
+                mockito::Matcher::UrlEncoded("Action".into(), "AssumeRoleWithSAML".into()),
+                mockito::Matcher::UrlEncoded(
+                    "RoleArn".into(),
+                    "arn:aws-us-gov:iam::123456789012:role/role1".into(),
+                ),
+            ]))
+            .with_header("content-type", "text/xml")
+            .with_body(
+                r#"<AssumeRoleWithSAMLResponse xmlns="https://sts.amazonaws.com/doc/2011-06-15/">
+  <AssumeRoleWithSAMLResult>
+    <Credentials>
+      <AccessKeyId>ASIAEXAMPLE</AccessKeyId>
+      <SecretAccessKey>secret</SecretAccessKey>
+      <SessionToken>token</SessionToken>
+      <Expiration>2038-01-19T03:14:07Z</Expiration>
+    </Credentials>
+  </AssumeRoleWithSAMLResult>
+</AssumeRoleWithSAMLResponse>"#,
+            )
+            .create();
+
+        let role = Role {
+            provider_arn: "arn:aws-us-gov:iam::123456789012:saml-provider/okta-idp".to_string(),
+            role_arn: "arn:aws-us-gov:iam::123456789012:role/role1".to_string(),
+        };
+        let endpoint = StsEndpoint {
+            region: None,
+            url: Some(server.url()),
+        };
+        let credentials = assume_role(&role, "assertion".to_string(), None, &endpoint)?
+            .credentials
+            .map(AwsCredentials::from)
+            .unwrap();
+
+        mock.assert();
+        assert_eq!(credentials.access_key_id.as_deref(), Some("ASIAEXAMPLE"));
+
+        Ok(())
+    }
+
     fn create_role() -> Role {
         Role {
             provider_arn: "arn:aws:iam::123456789012:saml-provider/okta-idp".to_string(),
@@ -0,0 +1,286 @@
+use crate::aws::role::{self as RoleManager, ChainedRole, Role, StsEndpoint};
+use crate::config::app::AppProfile;
+use crate::credentials::aws::AwsCredentials;
+
+use anyhow::{anyhow, Context, Result};
+use chrono::{Duration, SecondsFormat, Utc};
+use std::env;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+
+// Setting this variable to 1 or true makes crowbar hand out fake credentials
+// instead of calling AWS, e.g. to try a new IdP setup
+pub const FAKE_STS_VARIABLE: &str = "CROWBAR_FAKE_STS";
+const DEFAULT_DURATION: i32 = 3600;
+
+// The STS operations crowbar relies on, so they can be served by something
+// other than AWS
+pub trait StsClient {
+    fn assume_role_with_saml(
+        &self,
+        role: &Role,
+        saml_assertion: &str,
+        duration: Option<i32>,
+    ) -> Result<AwsCredentials>;
+
+    fn assume_role(
+        &self,
+        role: &ChainedRole,
+        credentials: &AwsCredentials,
+        duration: Option<i32>,
+    ) -> Result<AwsCredentials>;
+}
+
+#[derive(PartialEq, Debug)]
+pub enum StsError {
+    // The duration exceeds the maximum session duration of the role
+    DurationRejected(i32),
+}
+
+impl fmt::Display for StsError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            StsError::DurationRejected(d) => write!(
+                f,
+                "Session duration of {} seconds exceeds the maximum session duration of the role",
+                d
+            ),
+        }
+    }
+}
+
+impl std::error::Error for StsError {}
+
+pub fn is_duration_rejected(error: &anyhow::Error) -> bool {
+    matches!(
+        error.downcast_ref::<StsError>(),
+        Some(StsError::DurationRejected(_))
+    )
+}
+
+// Picks the STS client for a profile, which is AWS unless the fake was asked
+// for through the environment
+pub fn default_client(profile: &AppProfile) -> Box<dyn StsClient> {
+    match fake_requested() {
+        true => Box::new(FakeStsClient::new()),
+        false => Box::new(AwsStsClient::from(profile)),
+    }
+}
+
+pub fn fake_requested() -> bool {
+    env::var(FAKE_STS_VARIABLE).is_ok_and(|v| requests_fake(&v))
+}
+
+fn requests_fake(value: &str) -> bool {
+    matches!(value.to_lowercase().as_str(), "1" | "true")
+}
+
+pub struct AwsStsClient {
+    endpoint: StsEndpoint,
+}
+
+impl From<&AppProfile> for AwsStsClient {
+    fn from(profile: &AppProfile) -> AwsStsClient {
+        AwsStsClient {
+            endpoint: StsEndpoint::from(profile),
+        }
+    }
+}
+
+impl StsClient for AwsStsClient {
+    fn assume_role_with_saml(
+        &self,
+        role: &Role,
+        saml_assertion: &str,
+        duration: Option<i32>,
+    ) -> Result<AwsCredentials> {
+        let response =
+            RoleManager::assume_role(role, saml_assertion.to_owned(), duration, &self.endpoint)
+                .map_err(|e| match RoleManager::is_duration_rejected(&e) {
+                    true => e.context(StsError::DurationRejected(duration.unwrap_or_default())),
+                    false => e,
+                })?;
+
+        Ok(AwsCredentials::from(response.credentials.with_context(
+            || "Error fetching credentials for selected AWS role from assumption response",
+        )?))
+    }
+
+    fn assume_role(
+        &self,
+        role: &ChainedRole,
+        credentials: &AwsCredentials,
+        duration: Option<i32>,
+    ) -> Result<AwsCredentials> {
+        let response =
+            RoleManager::assume_chained_role(role, credentials, duration, &self.endpoint)?;
+
+        Ok(AwsCredentials::from(response.credentials.with_context(
+            || format!("Error fetching credentials for chained role {}", role),
+        )?))
+    }
+}
+
+// An in-process stand-in for STS, handing out deterministic credentials for
+// tests and demos
+#[derive(Clone, Default)]
+pub struct FakeStsClient {
+    max_duration: Option<i32>,
+    error: Option<String>,
+    calls: Arc<Mutex<Vec<FakeStsCall>>>,
+}
+
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub struct FakeStsCall {
+    pub role_arn: String,
+    pub duration: Option<i32>,
+}
+
+impl FakeStsClient {
+    pub fn new() -> Self {
+        FakeStsClient::default()
+    }
+
+    // Rejects longer sessions like STS does for roles with a lower maximum
+    // session duration
+    pub fn with_max_duration(mut self, seconds: i32) -> Self {
+        self.max_duration = Some(seconds);
+        self
+    }
+
+    // Fails every call with the given message
+    pub fn with_error(mut self, message: &str) -> Self {
+        self.error = Some(message.to_owned());
+        self
+    }
+
+    // The calls made so far, shared between clones
+    pub fn calls(&self) -> Vec<FakeStsCall> {
+        self.calls.lock().map(|c| c.clone()).unwrap_or_default()
+    }
+
+    fn issue(&self, role_arn: &str, duration: Option<i32>) -> Result<AwsCredentials> {
+        let mut calls = self
+            .calls
+            .lock()
+            .map_err(|_| anyhow!("Fake STS was poisoned by a failed call"))?;
+        calls.push(FakeStsCall {
+            role_arn: role_arn.to_owned(),
+            duration,
+        });
+
+        if let Some(error) = &self.error {
+            return Err(anyhow!("{}", error));
+        }
+
+        let seconds = duration.unwrap_or(DEFAULT_DURATION);
+        if self.max_duration.is_some_and(|max| seconds > max) {
+            return Err(StsError::DurationRejected(seconds).into());
+        }
+
+        let serial = calls.len();
+        Ok(AwsCredentials {
+            version: 1,
+            access_key_id: Some(format!("ASIAFAKE{:012}", serial)),
+            secret_access_key: Some(format!("fake-secret-access-key-{}", serial)),
+            session_token: Some(format!("fake-session-token-{}-{}", serial, role_arn)),
+            expiration: Some(
+                (Utc::now() + Duration::seconds(seconds.into()))
+                    .to_rfc3339_opts(SecondsFormat::Secs, true),
+            ),
+        })
+    }
+}
+
+impl StsClient for FakeStsClient {
+    fn assume_role_with_saml(
+        &self,
+        role: &Role,
+        _saml_assertion: &str,
+        duration: Option<i32>,
+    ) -> Result<AwsCredentials> {
+        self.issue(&role.role_arn, duration)
+    }
+
+    fn assume_role(
+        &self,
+        role: &ChainedRole,
+        credentials: &AwsCredentials,
+        duration: Option<i32>,
+    ) -> Result<AwsCredentials> {
+        if !credentials.valid() {
+            return Err(anyhow!(
+                "Missing credentials to assume chained role {}",
+                role
+            ));
+        }
+
+        self.issue(&role.role_arn, duration)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_fakes_when_asked_explicitly() {
+        assert!(requests_fake("1"));
+        assert!(requests_fake("True"));
+        assert!(!requests_fake("0"));
+        assert!(!requests_fake("false"));
+        assert!(!requests_fake(""));
+    }
+
+    #[test]
+    fn fake_issues_credentials_for_requested_duration() -> Result<()> {
+        let sts = FakeStsClient::new();
+        let credentials = sts.assume_role_with_saml(&role(), "assertion", Some(7200))?;
+
+        assert!(credentials.valid());
+        assert_eq!(
+            credentials.access_key_id.as_deref(),
+            Some("ASIAFAKE000000000001")
+        );
+        assert!(!credentials.expires_within(7000));
+        assert!(credentials.expires_within(7300));
+        assert_eq!(
+            sts.clone().calls(),
+            vec![FakeStsCall {
+                role_arn: "arn:aws:iam::123456789012:role/role1".to_string(),
+                duration: Some(7200),
+            }]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn fake_injects_errors() {
+        let rejecting = FakeStsClient::new().with_max_duration(3600);
+        let error = rejecting
+            .assume_role_with_saml(&role(), "assertion", Some(43200))
+            .unwrap_err();
+        assert!(is_duration_rejected(&error));
+        assert!(rejecting
+            .assume_role_with_saml(&role(), "assertion", None)
+            .is_ok());
+
+        let failing = FakeStsClient::new().with_error("Access denied");
+        assert_eq!(
+            failing
+                .assume_role_with_saml(&role(), "assertion", None)
+                .unwrap_err()
+                .to_string(),
+            "Access denied"
+        );
+        assert_eq!(failing.calls().len(), 1);
+    }
+
+    fn role() -> Role {
+        Role {
+            provider_arn: "arn:aws:iam::123456789012:saml-provider/okta-idp".to_string(),
+            role_arn: "arn:aws:iam::123456789012:role/role1".to_string(),
+        }
+    }
+}
@@ -1,11 +1,22 @@
 use crate::config::app::AppProfile;
+use crate::exec::format::OutputFormat;
+use crate::providers::ProviderType;
 use crate::utils::LevelFilter;
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 use clap::{crate_description, crate_version, Arg, ArgAction, ArgMatches, Command};
 
+// Options of `profiles add` that describe the forms of the generic provider
+const FORM_RECIPE_ARGS: [&str; 4] = [
+    "username-field",
+    "password-field",
+    "submit-selector",
+    "mfa-field",
+];
+
 #[derive(Debug)]
 pub struct CliConfig {
     pub force: bool,
+    pub no_cache: bool,
     pub location: Option<String>,
     pub log_level: LevelFilter,
     pub action: CliAction,
@@ -19,21 +30,41 @@ pub enum CliAction {
     Exec {
         command: Vec<String>,
         profile: String,
+        role: Option<String>,
+        duration: Option<i32>,
     },
     Creds {
         profile: String,
+        role: Option<String>,
+        duration: Option<i32>,
         print: bool,
+        write: Option<String>,
+        format: Option<OutputFormat>,
+    },
+    Serve {
+        profile: String,
+        role: Option<String>,
+        duration: Option<i32>,
+        port: u16,
+    },
+    Saml {
+        action: SamlSubAction,
     },
 }
 
 #[derive(Debug)]
 pub enum CliSubAction {
-    Add { profile: AppProfile },
+    Add { profile: Box<AppProfile> },
     Delete { profile_name: String },
     List,
 }
 
-fn get_matches() -> ArgMatches {
+#[derive(Debug)]
+pub enum SamlSubAction {
+    Inspect { file: Option<String> },
+}
+
+fn command() -> Command {
     Command::new("crowbar")
       .version(crate_version!())
       .about(crate_description!())
@@ -47,6 +78,12 @@ fn get_matches() -> ArgMatches {
               .long("force")
               .help("Forces re-entering of your Okta credentials"),
       )
+      .arg(
+          Arg::new("no-cache")
+              .long("no-cache")
+              .action(ArgAction::SetTrue)
+              .help("Never stores passwords, sessions or credentials, logging in every time"),
+      )
       .arg(
           Arg::new("log-level")
               .short('l')
@@ -78,7 +115,7 @@ fn get_matches() -> ArgMatches {
                       .value_name("PROVIDER")
                       .required(true)
                       .help("The name of the provider to use")
-                      .value_parser(clap::builder::PossibleValuesParser::new(["okta","jumpcloud"]))
+                      .value_parser(clap::builder::PossibleValuesParser::new(["okta","jumpcloud","adfs","azure","google","generic","keycloak"]))
               )
               .arg(
                   Arg::new("username")
@@ -102,6 +139,57 @@ fn get_matches() -> ArgMatches {
                       .required(false)
                       .help("The AWS role to assume after a successful login (Optional)"),
               )
+              .arg(
+                  Arg::new("duration")
+                      .short('d')
+                      .long("duration")
+                      .value_name("SECONDS")
+                      .required(false)
+                      .help("The session duration to request from AWS, up to the maximum of the role (Optional)")
+                      .value_parser(clap::value_parser!(i32).range(900..=43200)),
+              )
+              .arg(
+                  Arg::new("region")
+                      .long("region")
+                      .value_name("REGION")
+                      .required(false)
+                      .help("The region of the STS endpoint, derived from the role ARN if omitted (Optional)"),
+              )
+              .arg(
+                  Arg::new("sts-endpoint")
+                      .long("sts-endpoint")
+                      .value_name("URL")
+                      .required(false)
+                      .help("The URL of a custom STS endpoint (Optional)"),
+              )
+              .arg(
+                  Arg::new("username-field")
+                      .long("username-field")
+                      .value_name("PATTERN")
+                      .required(false)
+                      .help("Pattern for the name of the username input of the login form (generic provider only, Optional)"),
+              )
+              .arg(
+                  Arg::new("password-field")
+                      .long("password-field")
+                      .value_name("PATTERN")
+                      .required(false)
+                      .help("Pattern for the name of the password input of the login form (generic provider only, Optional)"),
+              )
+              .arg(
+                  Arg::new("submit-selector")
+                      .long("submit-selector")
+                      .value_name("SELECTOR")
+                      .required(false)
+                      .help("CSS selector for the form to submit, defaults to the last form on the page (generic provider only, Optional)"),
+              )
+              .arg(
+                  Arg::new("mfa-field")
+                      .long("mfa-field")
+                      .value_name("NAME")
+                      .required(false)
+                      .help("Name of the input that takes the MFA code (generic provider only, Optional)"),
+              )
               .arg(
                   Arg::new("profile").required(true).help("The name of the profile"),
               ),
@@ -128,6 +216,35 @@ fn get_matches() -> ArgMatches {
               .long("print")
               .help("Print credentials to stdout"),
           )
+          .arg(
+              Arg::new("format")
+              .long("format")
+              .value_name("FORMAT")
+              .help("Print credentials to stdout in this format, e.g. for eval $(crowbar creds --format env ...)")
+              .value_parser(clap::builder::PossibleValuesParser::new(["json", "env", "fish", "powershell", "dotenv", "cmd"])),
+          )
+          .arg(
+              Arg::new("write")
+              .short('w')
+              .long("write")
+              .value_name("AWS_PROFILE")
+              .help("Write credentials to this profile of the shared credentials file, which must not be one crowbar provides through credential_process"),
+          )
+          .arg(
+              Arg::new("role")
+              .short('r')
+              .long("role")
+              .value_name("ROLE_ARN")
+              .help("Assume this role from the SAML assertion instead of the one of the profile"),
+          )
+          .arg(
+              Arg::new("duration")
+              .short('d')
+              .long("duration")
+              .value_name("SECONDS")
+              .help("The session duration to request from AWS instead of the one of the profile")
+              .value_parser(clap::value_parser!(i32).range(900..=43200)),
+          )
           .arg(
               Arg::new("profile").required(true)
           ),
@@ -138,23 +255,83 @@ fn get_matches() -> ArgMatches {
         .arg(
             Arg::new("profile").required(true)
         )
+        .arg(
+            Arg::new("role")
+            .short('r')
+            .long("role")
+            .value_name("ROLE_ARN")
+            .help("Assume this role from the SAML assertion instead of the one of the profile"),
+        )
+        .arg(
+            Arg::new("duration")
+            .short('d')
+            .long("duration")
+            .value_name("SECONDS")
+            .help("The session duration to request from AWS instead of the one of the profile")
+            .value_parser(clap::value_parser!(i32).range(900..=43200)),
+        )
         .arg(
             Arg::new("command")
             .last(true)
             .action(ArgAction::Append)
         ),
     )
-    .get_matches()
+      .subcommand(
+          Command::new("serve")
+          .about("Serve temporary credentials on localhost in the layout of the ECS container credentials endpoint")
+          .arg(
+              Arg::new("profile").required(true)
+          )
+          .arg(
+              Arg::new("role")
+              .short('r')
+              .long("role")
+              .value_name("ROLE_ARN")
+              .help("Assume this role from the SAML assertion instead of the one of the profile"),
+          )
+          .arg(
+              Arg::new("duration")
+              .short('d')
+              .long("duration")
+              .value_name("SECONDS")
+              .help("The session duration to request from AWS instead of the one of the profile")
+              .value_parser(clap::value_parser!(i32).range(900..=43200)),
+          )
+          .arg(
+              Arg::new("port")
+              .long("port")
+              .value_name("PORT")
+              .help("The port to listen on, picks a free one if omitted")
+              .value_parser(clap::value_parser!(u16))
+              .default_value("0"),
+          ),
+      )
+      .subcommand(
+          Command::new("saml")
+          .about("Work with SAML assertions")
+          .arg_required_else_help(true)
+          .disable_help_subcommand(true)
+          .subcommand(
+              Command::new("inspect")
+              .about("Decode a captured SAML assertion and show its AWS attributes")
+              .arg(
+                  Arg::new("file")
+                  .required(false)
+                  .help("The file holding the assertion, reads from stdin if omitted")
+              ),
+          )
+      )
 }
 
 pub fn config() -> Result<CliConfig> {
-    let matches = get_matches();
+    let matches = command().get_matches();
     let cli_action = select_action(&matches);
     let location = matches.get_one::<String>("location").map(|c| c.to_string());
     let log_level_from_matches = matches.get_one::<String>("log-level").unwrap();
 
     Ok(CliConfig {
         force: matches.get_flag("force"),
+        no_cache: matches.get_flag("no-cache"),
         location,
         log_level: select_log_level(log_level_from_matches),
         action: cli_action?,
@@ -172,17 +349,44 @@ fn select_action(matches: &ArgMatches) -> Result<CliAction> {
             Ok(CliAction::Exec {
                 command: parts,
                 profile: m.get_one::<String>("profile").unwrap().to_string(),
+                role: m.get_one::<String>("role").map(|r| r.to_string()),
+                duration: m.get_one::<i32>("duration").copied(),
             })
         }
         Some(("creds", m)) => Ok(CliAction::Creds {
             print: m.get_flag("print"),
+            write: m.get_one::<String>("write").map(|w| w.to_string()),
+            format: m
+                .get_one::<String>("format")
+                .map(|f| f.parse())
+                .transpose()?,
+            profile: m.get_one::<String>("profile").unwrap().to_string(),
+            role: m.get_one::<String>("role").map(|r| r.to_string()),
+            duration: m.get_one::<i32>("duration").copied(),
+        }),
+        Some(("serve", m)) => Ok(CliAction::Serve {
             profile: m.get_one::<String>("profile").unwrap().to_string(),
+            role: m.get_one::<String>("role").map(|r| r.to_string()),
+            duration: m.get_one::<i32>("duration").copied(),
+            port: *m.get_one::<u16>("port").unwrap(),
         }),
         Some(("profiles", action)) => Ok(CliAction::Profiles {
             action: match action.subcommand() {
-                Some(("add", action)) => CliSubAction::Add {
-                    profile: AppProfile::from(action),
-                },
+                Some(("add", action)) => {
+                    let profile = AppProfile::from(action);
+                    if profile.provider != ProviderType::Generic
+                        && FORM_RECIPE_ARGS
+                            .iter()
+                            .any(|arg| action.get_one::<String>(arg).is_some())
+                    {
+                        return Err(anyhow!(
+                            "Form options can only be used with the generic provider"
+                        ));
+                    }
+                    CliSubAction::Add {
+                        profile: Box::new(profile),
+                    }
+                }
                 Some(("delete", action)) => CliSubAction::Delete {
                     profile_name: action.get_one::<String>("profile").unwrap().to_string(),
                 },
@@ -190,6 +394,14 @@ fn select_action(matches: &ArgMatches) -> Result<CliAction> {
                 _ => unreachable!(),
             },
         }),
+        Some(("saml", action)) => Ok(CliAction::Saml {
+            action: match action.subcommand() {
+                Some(("inspect", action)) => SamlSubAction::Inspect {
+                    file: action.get_one::<String>("file").map(|f| f.to_string()),
+                },
+                _ => unreachable!(),
+            },
+        }),
         _ => unreachable!(),
     }
 }
@@ -205,6 +417,71 @@ fn select_log_level(selected_level: &str) -> LevelFilter {
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::providers::generic::FormRecipe;
+
+    fn add_profile(args: &[&str]) -> Result<AppProfile> {
+        let matches = command().try_get_matches_from(
+            [
+                "crowbar",
+                "profiles",
+                "add",
+                "-u",
+                "jdoe",
+                "--url",
+                "https://idp.example.com/sso/aws",
+            ]
+            .iter()
+            .chain(args),
+        )?;
+
+        match select_action(&matches)? {
+            CliAction::Profiles {
+                action: CliSubAction::Add { profile },
+            } => Ok(*profile),
+            action => Err(anyhow!("Unexpected action {:?}", action)),
+        }
+    }
+
+    #[test]
+    fn adds_generic_profile_with_form_recipe() -> Result<()> {
+        let profile = add_profile(&[
+            "-p",
+            "generic",
+            "--password-field",
+            "^secret$",
+            "--mfa-field",
+            "otp",
+            "sso",
+        ])?;
+
+        assert_eq!(
+            profile.form,
+            Some(FormRecipe {
+                password_field: "^secret$".to_string(),
+                mfa_field: Some("otp".to_string()),
+                ..Default::default()
+            })
+        );
+        assert_eq!(
+            add_profile(&["-p", "generic", "sso"])?.form,
+            Some(FormRecipe::default())
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn refuses_form_recipe_for_other_providers() -> Result<()> {
+        let error = add_profile(&["-p", "okta", "--mfa-field", "otp", "sso"]).unwrap_err();
+
+        assert_eq!(
+            error.to_string(),
+            "Form options can only be used with the generic provider"
+        );
+        assert_eq!(add_profile(&["-p", "okta", "sso"])?.form, None);
+
+        Ok(())
+    }
 
     #[test]
     fn log_levels_as_expected() {
@@ -2,6 +2,7 @@ pub mod app;
 pub mod aws;
 
 use crate::config::app::AppProfile;
+use crate::credentials::store::StoreBackend;
 use anyhow::{anyhow, Result};
 use clap::crate_name;
 use serde::{Deserialize, Serialize};
@@ -9,12 +10,17 @@ use std::default::Default;
 
 #[derive(Deserialize, Serialize, Debug, Clone, Default)]
 pub struct CrowbarConfig {
+    // Where passwords and cached credentials are kept
+    #[serde(default, skip_serializing_if = "StoreBackend::is_keyring")]
+    pub store: StoreBackend,
     pub profiles: Vec<AppProfile>,
     pub location: Option<String>,
 }
 
 #[derive(Serialize, Deserialize, Default)]
 struct AppProfiles {
+    #[serde(default, skip_serializing_if = "StoreBackend::is_keyring")]
+    store: StoreBackend,
     profiles: Vec<AppProfile>,
 }
 
@@ -34,6 +40,7 @@ impl CrowbarConfig {
             Some(l) => confy::load_path(l)?,
             _ => confy::load(crate_name!(), Some("crowbar"))?,
         };
+        self.store = app_profiles.store;
         self.profiles = app_profiles.profiles;
 
         Ok(self)
@@ -78,6 +85,7 @@ impl CrowbarConfig {
 
     pub fn write(self) -> Result<()> {
         let app_profiles = AppProfiles {
+            store: self.store,
             profiles: self.profiles,
         };
 
@@ -93,7 +101,6 @@ fn find_duplicate(vec: &[AppProfile], profile: &AppProfile) -> bool {
 }
 
 #[cfg(test)]
-
 mod test {
     use super::*;
     use crate::providers::ProviderType;
@@ -123,6 +130,25 @@ mod test {
         Ok(())
     }
 
+    #[test]
+    fn keeps_store_backend_when_writing() -> Result<()> {
+        let dir = tempfile::TempDir::new()?;
+        let location = Some(dir.path().join("crowbar.toml").to_str().unwrap().to_owned());
+        let store = StoreBackend::File {
+            path: Some("/tmp/secrets.json".to_string()),
+        };
+
+        let mut config = CrowbarConfig::with_location(location.clone()).add_profile(&profile_a())?;
+        config.store = store.clone();
+        config.write()?;
+
+        let config = CrowbarConfig::with_location(location).read()?;
+        assert_eq!(config.store, store);
+        assert_eq!(config.profiles.len(), 1);
+
+        Ok(())
+    }
+
     #[test]
     fn should_detect_profile_duplicate() {
         let profile_a_vec = vec![profile_a()];
@@ -145,6 +171,7 @@ mod test {
     #[test]
     fn refuses_to_add_duplicate_profile() -> Result<()> {
         let config = CrowbarConfig {
+            store: StoreBackend::default(),
             profiles: vec![profile_a()],
             location: None,
         };
@@ -158,6 +185,7 @@ mod test {
     #[test]
     fn removes_profile_from_configuration() -> Result<()> {
         let config = CrowbarConfig {
+            store: StoreBackend::default(),
             profiles: vec![profile_a(), profile_b()],
             location: None,
         };
@@ -174,6 +202,7 @@ mod test {
     #[test]
     fn error_on_profile_not_exist() -> Result<()> {
         let config = CrowbarConfig {
+            store: StoreBackend::default(),
             profiles: vec![profile_b()],
             location: None,
         };
@@ -192,6 +221,13 @@ mod test {
             provider: ProviderType::Okta,
             url: "https://www.example.com/example/saml".to_owned(),
             role: None,
+            duration: None,
+            okta_pipeline: None,
+            form: None,
+            role_chain: vec![],
+            idp_certificate: None,
+            region: None,
+            sts_endpoint: None,
         }
     }
     fn profile_b() -> AppProfile {
@@ -201,6 +237,13 @@ mod test {
             provider: ProviderType::Okta,
             url: "https://www.example.com/example/saml".to_owned(),
             role: None,
+            duration: None,
+            okta_pipeline: None,
+            form: None,
+            role_chain: vec![],
+            idp_certificate: None,
+            region: None,
+            sts_endpoint: None,
         }
     }
 }
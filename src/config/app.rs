@@ -1,3 +1,6 @@
+use crate::aws::role::{ChainedRole, MAX_CHAINED_DURATION};
+use crate::providers::generic::FormRecipe;
+use crate::providers::okta::Pipeline;
 use crate::providers::ProviderType;
 use anyhow::{anyhow, Result};
 use clap::ArgMatches;
@@ -14,12 +17,33 @@ pub struct AppProfile {
     pub username: String,
     pub url: String,
     pub role: Option<String>,
+    // Session duration in seconds requested from STS
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub duration: Option<i32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub okta_pipeline: Option<Pipeline>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub form: Option<FormRecipe>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub role_chain: Vec<ChainedRole>,
+    // PEM certificate of the IdP that SAML assertions have to be signed with
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub idp_certificate: Option<String>,
+    // Region of the STS endpoint, derived from the role ARN if missing
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub region: Option<String>,
+    // URL of a custom STS endpoint, e.g. a VPC endpoint
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sts_endpoint: Option<String>,
 }
 
 impl fmt::Display for AppProfile {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         let identifier = match self.base_url() {
-            Ok(url) => {
+            // The identifier keys secrets in the store and has always been
+            // derived without the port, so it is left out to keep them
+            Ok(mut url) => {
+                let _ = url.set_port(None);
                 let identifier = format!("{}-{}", url.as_str(), self.username);
                 format!("{:x}", sha2::Sha256::digest(identifier.as_bytes()))
             }
@@ -32,17 +56,49 @@ impl fmt::Display for AppProfile {
 
 impl From<&ArgMatches> for AppProfile {
     fn from(action: &ArgMatches) -> AppProfile {
+        let provider =
+            ProviderType::from_str(action.get_one::<String>("provider").unwrap()).unwrap();
+
         AppProfile {
             name: action.get_one::<String>("profile").unwrap().to_string(),
             username: action.get_one::<String>("username").unwrap().to_string(),
             url: action.get_one::<String>("url").unwrap().to_string(),
             role: action.get_one::<String>("role").map(|r| r.to_string()),
-            provider: ProviderType::from_str(action.get_one::<String>("provider").unwrap())
-                .unwrap(),
+            duration: action.get_one::<i32>("duration").copied(),
+            okta_pipeline: None,
+            form: match provider {
+                ProviderType::Generic => Some(form_recipe(action)),
+                _ => None,
+            },
+            role_chain: vec![],
+            idp_certificate: None,
+            region: action.get_one::<String>("region").map(|r| r.to_string()),
+            sts_endpoint: action
+                .get_one::<String>("sts-endpoint")
+                .map(|e| e.to_string()),
+            provider,
         }
     }
 }
 
+// Options that aren't given keep their defaults, which are still written to
+// the profile so the recipe in use shows up in the configuration
+fn form_recipe(action: &ArgMatches) -> FormRecipe {
+    let mut recipe = FormRecipe::default();
+    if let Some(field) = action.get_one::<String>("username-field") {
+        recipe.username_field = field.to_string();
+    }
+    if let Some(field) = action.get_one::<String>("password-field") {
+        recipe.password_field = field.to_string();
+    }
+    recipe.submit_selector = action
+        .get_one::<String>("submit-selector")
+        .map(|s| s.to_string());
+    recipe.mfa_field = action.get_one::<String>("mfa-field").map(|f| f.to_string());
+
+    recipe
+}
+
 impl AppProfile {
     pub fn request_url(&self) -> Result<Url> {
         let url = self.url.clone();
@@ -54,13 +110,23 @@ impl AppProfile {
 
     pub fn base_url(&self) -> Result<Url> {
         let url = self.request_url()?;
-        let base_url = &format!("{}://{}", url.scheme(), url.host().unwrap());
+        let port = url.port().map(|p| format!(":{}", p)).unwrap_or_default();
+        let base_url = &format!("{}://{}{}", url.scheme(), url.host().unwrap(), port);
         match Url::from_str(base_url) {
             Ok(u) => Ok(u),
             Err(e) => Err(anyhow!("Unable to create base URL: {}", e)),
         }
     }
 
+    // The duration the final credentials are issued for, as chained roles
+    // can't exceed an hour
+    pub fn session_duration(&self) -> Option<i32> {
+        match self.role_chain.is_empty() {
+            true => self.duration,
+            false => self.duration.map(|d| d.min(MAX_CHAINED_DURATION)),
+        }
+    }
+
     pub fn is_profile(&self, profile: &str) -> bool {
         self.name == profile
     }
@@ -97,11 +163,93 @@ mod test {
         Ok(())
     }
 
+    #[test]
+    fn returns_base_of_url_with_port() -> Result<()> {
+        let mut profile = short_profile();
+        profile.url = "http://127.0.0.1:8080/example/url".to_string();
+
+        assert_eq!(Url::from_str("http://127.0.0.1:8080")?, profile.base_url()?);
+        Ok(())
+    }
+
+    #[test]
+    fn identifies_profile_without_port() {
+        let mut profile = short_profile();
+        let identifier = profile.to_string();
+        profile.url = "https://example.com:8443/example/url".to_string();
+
+        assert_eq!(identifier, profile.to_string());
+        assert_eq!(
+            identifier,
+            format!(
+                "{:x}",
+                sha2::Sha256::digest("https://example.com/-username".as_bytes())
+            )
+        );
+    }
+
     #[test]
     fn validates_profile_name() {
         assert_eq!("profile", short_profile().name)
     }
 
+    #[test]
+    fn parses_role_chain() -> Result<()> {
+        let profile: AppProfile = toml::from_str(
+            r#"
+            name = "profile"
+            provider = "okta"
+            url = "https://example.com/example/url"
+            username = "username"
+
+            [[role_chain]]
+            role_arn = "arn:aws:iam::123456789012:role/workload"
+            external_id = "external-id"
+
+            [[role_chain]]
+            role_arn = "arn:aws:iam::210987654321:role/admin"
+            session_name = "jdoe"
+        "#,
+        )?;
+
+        assert_eq!(
+            profile.role_chain,
+            vec![
+                ChainedRole {
+                    role_arn: "arn:aws:iam::123456789012:role/workload".to_string(),
+                    external_id: Some("external-id".to_string()),
+                    session_name: None,
+                },
+                ChainedRole {
+                    role_arn: "arn:aws:iam::210987654321:role/admin".to_string(),
+                    external_id: None,
+                    session_name: Some("jdoe".to_string()),
+                },
+            ]
+        );
+        assert!(short_profile().role_chain.is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn caps_session_duration_of_chained_roles() -> Result<()> {
+        let mut profile = short_profile();
+        assert_eq!(None, profile.session_duration());
+
+        profile.duration = Some(43200);
+        assert_eq!(Some(43200), profile.session_duration());
+
+        profile.role_chain = vec![ChainedRole {
+            role_arn: "arn:aws:iam::123456789012:role/workload".to_string(),
+            external_id: None,
+            session_name: None,
+        }];
+        assert_eq!(Some(3600), profile.session_duration());
+
+        Ok(())
+    }
+
     fn long_profile() -> AppProfile {
         toml::from_str(
             r#"
@@ -1,15 +1,32 @@
 use crate::config::app::AppProfile;
+use crate::credentials::aws::AwsCredentials;
 use crate::utils;
 use anyhow::{anyhow, Context, Result};
 use dirs::home_dir;
 use ini::Ini;
 use std::fs;
-use std::fs::File;
+use std::fs::{File, OpenOptions};
 use std::io::ErrorKind;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
 pub const AWS_CONFIG_FILE: &str = "AWS_CONFIG_FILE";
+pub const AWS_SHARED_CREDENTIALS_FILE: &str = "AWS_SHARED_CREDENTIALS_FILE";
 pub const PROFILE_KEY: &str = "credential_process";
+pub const ACCESS_KEY_ID_KEY: &str = "aws_access_key_id";
+pub const SECRET_ACCESS_KEY_KEY: &str = "aws_secret_access_key";
+pub const SESSION_TOKEN_KEY: &str = "aws_session_token";
+// Not read by the SDKs, but by older tools to tell when to refresh
+pub const EXPIRATION_KEY: &str = "x_security_token_expires";
+// Names the crowbar profile the credentials of a section came from, so they
+// can be cleaned up along with it
+pub const CROWBAR_PROFILE_KEY: &str = "x_crowbar_profile";
+const CREDENTIALS_KEYS: [&str; 5] = [
+    ACCESS_KEY_ID_KEY,
+    SECRET_ACCESS_KEY_KEY,
+    SESSION_TOKEN_KEY,
+    EXPIRATION_KEY,
+    CROWBAR_PROFILE_KEY,
+];
 
 #[derive(Clone)]
 pub struct AwsConfig {
@@ -20,18 +37,7 @@ pub struct AwsConfig {
 impl AwsConfig {
     pub fn new() -> Result<AwsConfig> {
         let location = default_config_location()?;
-        let profiles: Ini = match File::open(&location) {
-            Ok(mut file) => Ini::read_from(&mut file)?,
-            Err(ref e) if e.kind() == ErrorKind::NotFound => {
-                if let Some(parent) = location.parent() {
-                    fs::create_dir_all(parent).with_context(|| {
-                        format!("Unable to read configuration from {:?}", location)
-                    })?;
-                }
-                Ini::new()
-            }
-            Err(_e) => return Err(anyhow!("Unable to create configuration structure!")),
-        };
+        let profiles = read_ini(&location)?;
 
         Ok(AwsConfig { profiles, location })
     }
@@ -62,21 +68,149 @@ impl AwsConfig {
 
         Ok(self)
     }
+
+    // Whether the profile gets its credentials from crowbar through a
+    // credential_process
+    pub fn runs_crowbar(&self, profile_name: &str) -> bool {
+        let section = match profile_name {
+            "default" => profile_name.to_string(),
+            _ => format!("profile {}", profile_name),
+        };
+
+        self.profiles
+            .get_from(Some(section), PROFILE_KEY)
+            .is_some_and(|process| process.contains("crowbar creds"))
+    }
+}
+
+// The shared credentials file, for tools that can't run a credential_process
+#[derive(Clone)]
+pub struct AwsCredentialsFile {
+    pub profiles: Ini,
+    pub location: PathBuf,
+}
+
+impl AwsCredentialsFile {
+    pub fn new() -> Result<AwsCredentialsFile> {
+        let location = default_credentials_location()?;
+        let profiles = read_ini(&location)?;
+
+        Ok(AwsCredentialsFile { profiles, location })
+    }
+
+    pub fn write(self) -> Result<AwsCredentialsFile> {
+        let location = &self.location;
+        let mut options = OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+        // The file holds secrets, so only its owner gets to read it
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::OpenOptionsExt;
+            options.mode(0o600);
+        }
+
+        options
+            .open(location)
+            .and_then(|mut file| self.profiles.write_to(&mut file))
+            .with_context(|| format!("Unable to write AWS credentials at {:?}", location))?;
+
+        Ok(self)
+    }
+
+    // Writes the credentials of the crowbar profile `source` to the profile
+    // `target` of the file
+    pub fn set_credentials(
+        mut self,
+        target: &str,
+        source: &str,
+        credentials: &AwsCredentials,
+    ) -> Result<AwsCredentialsFile> {
+        let values = [
+            &credentials.access_key_id,
+            &credentials.secret_access_key,
+            &credentials.session_token,
+            &credentials.expiration,
+            &Some(source.to_string()),
+        ];
+
+        for (key, value) in CREDENTIALS_KEYS.iter().zip(values) {
+            let value = value
+                .clone()
+                .with_context(|| format!("Missing {} in credentials", key))?;
+            self.profiles.set_to(Some(target), key.to_string(), value);
+        }
+
+        Ok(self)
+    }
+
+    pub fn has_profile(&self, profile_name: &str) -> bool {
+        self.profiles.section(Some(profile_name)).is_some()
+    }
+
+    // The profiles of the file holding credentials of the crowbar profile
+    pub fn written_for(&self, profile_name: &str) -> Vec<String> {
+        self.profiles
+            .iter()
+            .filter(|(_, properties)| properties.get(CROWBAR_PROFILE_KEY) == Some(profile_name))
+            .filter_map(|(section, _)| section.map(|s| s.to_string()))
+            .collect()
+    }
+
+    // Removes what crowbar wrote for the crowbar profile and the sections with
+    // it, unless they hold settings of their own
+    pub fn delete_profile(mut self, profile_name: &str) -> Result<AwsCredentialsFile> {
+        for target in self.written_for(profile_name) {
+            for key in CREDENTIALS_KEYS {
+                self.profiles.delete_from(Some(target.as_str()), key);
+            }
+            if self
+                .profiles
+                .section(Some(target.as_str()))
+                .is_some_and(|s| s.is_empty())
+            {
+                self.profiles.delete(Some(target.as_str()));
+            }
+        }
+
+        Ok(self)
+    }
+}
+
+fn read_ini(location: &Path) -> Result<Ini> {
+    match File::open(location) {
+        Ok(mut file) => Ok(Ini::read_from(&mut file)?),
+        Err(ref e) if e.kind() == ErrorKind::NotFound => {
+            if let Some(parent) = location.parent() {
+                fs::create_dir_all(parent)
+                    .with_context(|| format!("Unable to read configuration from {:?}", location))?;
+            }
+            Ok(Ini::new())
+        }
+        Err(_e) => Err(anyhow!("Unable to create configuration structure!")),
+    }
 }
 
 fn default_config_location() -> Result<PathBuf> {
     let env = utils::non_empty_env_var(AWS_CONFIG_FILE);
     match env {
         Some(path) => Ok(PathBuf::from(path)),
-        None => hardcoded_config_location(),
+        None => hardcoded_location("config"),
+    }
+}
+
+fn default_credentials_location() -> Result<PathBuf> {
+    let env = utils::non_empty_env_var(AWS_SHARED_CREDENTIALS_FILE);
+    match env {
+        Some(path) => Ok(PathBuf::from(path)),
+        None => hardcoded_location("credentials"),
     }
 }
 
-fn hardcoded_config_location() -> Result<PathBuf> {
+fn hardcoded_location(file_name: &str) -> Result<PathBuf> {
     match home_dir() {
         Some(mut home_path) => {
             home_path.push(".aws");
-            home_path.push("config");
+            home_path.push(file_name);
             Ok(home_path)
         }
         None => Err(anyhow!("Failed to determine home directory.")),
@@ -1,5 +1,7 @@
 pub mod aws;
 pub mod config;
+pub mod session;
+pub mod store;
 
 use anyhow::Result;
 use std::fmt;
@@ -8,6 +10,8 @@ use std::fmt;
 pub enum CredentialType {
     Config,
     Aws,
+    Session,
+    Saml,
 }
 
 impl fmt::Display for CredentialType {
@@ -15,6 +19,8 @@ impl fmt::Display for CredentialType {
         match self {
             CredentialType::Config => write!(f, "config"),
             CredentialType::Aws => write!(f, "aws"),
+            CredentialType::Session => write!(f, "session"),
+            CredentialType::Saml => write!(f, "saml"),
         }
     }
 }
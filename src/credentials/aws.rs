@@ -1,15 +1,18 @@
+use crate::aws::role as RoleManager;
+use crate::aws::sts::StsClient;
 use crate::config::app::AppProfile;
 use crate::config::CrowbarConfig;
 use crate::credentials::config::ConfigCredentials;
+use crate::credentials::session::IdpSession;
+use crate::credentials::store;
 use crate::credentials::Credential;
 use crate::credentials::CredentialType;
-use crate::providers::adfs::AdfsProvider;
-use crate::providers::jumpcloud::JumpcloudProvider;
-use crate::providers::okta::OktaProvider;
-use crate::providers::ProviderType;
+use crate::providers::ProviderRegistry;
+use crate::saml;
 use aws_smithy_types::date_time::Format;
 use log::debug;
 use serde::{Deserialize, Serialize};
+use sha2::Digest;
 
 use anyhow::{anyhow, Result};
 use aws_sdk_sts::model::Credentials;
@@ -18,6 +21,7 @@ use std::collections::HashMap;
 use std::{fmt, str};
 
 const SECONDS_TO_EXPIRATION: i64 = 900; // 15 minutes
+const DEFAULT_SESSION_DURATION: i64 = 3600; // STS default of one hour
 
 #[derive(Serialize, Deserialize, Debug, PartialEq, Hash, Eq, Clone)]
 #[serde(rename_all = "PascalCase")]
@@ -31,10 +35,14 @@ pub struct AwsCredentials {
 
 impl AwsCredentials {
     pub fn is_expired(&self) -> bool {
+        self.expires_within(SECONDS_TO_EXPIRATION)
+    }
+
+    pub fn expires_within(&self, seconds: i64) -> bool {
         match &self.expiration {
             Some(dt) => {
                 let expiration = DateTime::parse_from_rfc3339(dt).unwrap();
-                expiration.signed_duration_since(Utc::now()).num_seconds() < SECONDS_TO_EXPIRATION
+                expiration.signed_duration_since(Utc::now()).num_seconds() < seconds
             }
             _ => false,
         }
@@ -113,26 +121,10 @@ impl Credential<AppProfile, AwsCredentials> for AwsCredentials {
     }
 
     fn load(profile: &AppProfile) -> Result<AwsCredentials> {
-        let default_map: HashMap<String, Option<String>> = AwsCredentials::default().into();
-        let mut credential_map: HashMap<String, Option<String>> = AwsCredentials::default().into();
         let service = credentials_as_service(profile);
 
         debug!("Trying to fetch cached AWS credentials for ID {}", &service);
-
-        for key in default_map.keys() {
-            let _res = credential_map.insert(
-                key.clone(),
-                match keyring::Entry::new(&service, key).get_password() {
-                    Ok(s) => Some(s),
-                    Err(e) => {
-                        debug!("Error while fetching credentials: {}", e);
-                        break;
-                    }
-                },
-            );
-        }
-
-        Ok(AwsCredentials::from(credential_map))
+        Ok(load_from_service(&service))
     }
 
     fn write(self, profile: &AppProfile) -> Result<AwsCredentials> {
@@ -140,11 +132,10 @@ impl Credential<AppProfile, AwsCredentials> for AwsCredentials {
         let service = credentials_as_service(profile);
         debug!("Saving AWS credentials for {}", &service);
 
+        let store = store::current();
         for (key, secret) in credential_map.iter() {
             if let Some(s) = secret {
-                keyring::Entry::new(&service, key)
-                    .set_password(s)
-                    .map_err(|e| anyhow!("{}", e))?;
+                store.set(&service, key, s)?;
             }
         }
 
@@ -152,80 +143,300 @@ impl Credential<AppProfile, AwsCredentials> for AwsCredentials {
     }
 
     fn delete(self, profile: &AppProfile) -> Result<AwsCredentials> {
-        let credential_map: HashMap<String, Option<String>> = self.clone().into();
-        let service = credentials_as_service(profile);
+        delete_from_service(&credentials_as_service(profile))?;
+        delete_from_service(&legacy_credentials_as_service(profile))?;
 
-        for (key, _) in credential_map.iter() {
-            let keyring = keyring::Entry::new(&service, key);
-            let pass = keyring.get_password();
+        Ok(self)
+    }
+}
 
-            if pass.is_ok() {
-                debug!("Deleting secret for {} at service {}", &key, &service);
-                keyring.delete_password().map_err(|e| anyhow!("{}", e))?
-            }
-        }
+// Moves credentials earlier versions cached by profile name to the key of
+// the profile as configured, before a role or duration given on the command
+// line changes it
+pub fn migrate_legacy_credentials(profile: &AppProfile) -> Result<()> {
+    let service = credentials_as_service(profile);
+    if load_from_service(&service).valid() {
+        return Ok(());
+    }
 
-        Ok(self)
+    let legacy_service = legacy_credentials_as_service(profile);
+    let legacy_credentials = load_from_service(&legacy_service);
+    if !legacy_credentials.valid() {
+        return Ok(());
+    }
+
+    debug!(
+        "Migrating cached AWS credentials from {} to {}",
+        &legacy_service, &service
+    );
+    legacy_credentials.write(profile)?;
+    delete_from_service(&legacy_service)
+}
+
+// Deletes the cached credentials of a profile unless another of the given
+// profiles still shares them
+pub fn delete_credentials(profile: &AppProfile, profiles: &[AppProfile]) -> Result<()> {
+    let service = credentials_as_service(profile);
+    let shared = profiles
+        .iter()
+        .any(|p| !p.is_profile(&profile.name) && credentials_as_service(p) == service);
+
+    match shared {
+        true => delete_from_service(&legacy_credentials_as_service(profile)),
+        false => AwsCredentials::default().delete(profile).map(|_| ()),
     }
 }
 
+fn load_from_service(service: &str) -> AwsCredentials {
+    let default_map: HashMap<String, Option<String>> = AwsCredentials::default().into();
+    let mut credential_map: HashMap<String, Option<String>> = AwsCredentials::default().into();
+
+    let store = store::current();
+
+    for key in default_map.keys() {
+        let _res = credential_map.insert(
+            key.clone(),
+            match store.get(service, key) {
+                Ok(s) => Some(s),
+                Err(e) => {
+                    debug!("Error while fetching credentials: {}", e);
+                    break;
+                }
+            },
+        );
+    }
+
+    AwsCredentials::from(credential_map)
+}
+
+fn delete_from_service(service: &str) -> Result<()> {
+    let default_map: HashMap<String, Option<String>> = AwsCredentials::default().into();
+
+    let store = store::current();
+
+    for key in default_map.keys() {
+        debug!("Deleting secret for {} at service {}", &key, service);
+        store.delete(service, key)?;
+    }
+
+    Ok(())
+}
+
+// Fetches credentials for the role of the profile, or for the given role if
+// it's listed in the SAML assertion of the profile's IdP app, optionally for
+// a session duration other than the profile's
 pub fn fetch_aws_credentials(
     profile: String,
+    role: Option<String>,
+    duration: Option<i32>,
     crowbar_config: CrowbarConfig,
     force_new_credentials: bool,
 ) -> Result<AwsCredentials> {
+    fetch_aws_credentials_with_registry(
+        profile,
+        role,
+        duration,
+        crowbar_config,
+        force_new_credentials,
+        &ProviderRegistry::default(),
+    )
+}
+
+// Like fetch_aws_credentials, but creates the IdP client from a registry that
+// may carry additional providers
+pub fn fetch_aws_credentials_with_registry(
+    profile: String,
+    role: Option<String>,
+    duration: Option<i32>,
+    crowbar_config: CrowbarConfig,
+    force_new_credentials: bool,
+    registry: &ProviderRegistry,
+) -> Result<AwsCredentials> {
+    let profile = &find_profile(&profile, role, duration, &crowbar_config)?;
+
+    if force_new_credentials {
+        let _creds = ConfigCredentials::load(profile)
+            .map_err(|e| debug!("Couldn't reset credentials: {}", e))
+            .and_then(|creds| creds.delete(profile).map_err(|e| debug!("{}", e)));
+        let _session = IdpSession::default()
+            .delete(profile)
+            .map_err(|e| debug!("Couldn't reset IdP session: {}", e));
+        let _assertion = saml::Response::load(profile)
+            .and_then(|a| a.delete(profile))
+            .map_err(|e| debug!("Couldn't reset cached SAML assertion: {}", e));
+    }
+
+    let mut aws_credentials = AwsCredentials::load(profile).unwrap_or_default();
+
+    let refresh_window = seconds_to_expiration(profile.session_duration());
+
+    if !aws_credentials.valid() || aws_credentials.expires_within(refresh_window) {
+        let sts = registry.sts(profile);
+        let cached = match force_new_credentials {
+            true => None,
+            false => saml::Response::load(profile)
+                .map_err(|e| debug!("No usable cached SAML assertion: {}", e))
+                .ok(),
+        };
+
+        if let Some(credentials) = cached.and_then(|saml| {
+            assume_role_from_cache(&saml, profile, sts.as_ref())
+                .map_err(|e| debug!("Unable to use cached SAML assertion: {}", e))
+                .ok()
+        }) {
+            return credentials.write(profile);
+        }
+
+        let mut provider = registry.create(profile)?;
+        let resumed = !force_new_credentials
+            && provider.resume_session().unwrap_or_else(|e| {
+                debug!("Unable to resume IdP session: {}", e);
+                false
+            });
+        if !resumed {
+            provider.new_session()?;
+            let _pipeline = remember_okta_pipeline(&crowbar_config, profile, provider.profile())
+                .map_err(|e| debug!("Unable to keep detected Okta pipeline: {}", e));
+        }
+        aws_credentials = provider.fetch_aws_credentials(sts.as_ref())?;
+
+        aws_credentials = aws_credentials.write(profile)?;
+    }
+
+    Ok(aws_credentials)
+}
+
+// Writes the Okta pipeline detected while logging in to the configured
+// profile, so later logins can skip detecting it again
+fn remember_okta_pipeline(
+    crowbar_config: &CrowbarConfig,
+    profile: &AppProfile,
+    logged_in: &AppProfile,
+) -> Result<()> {
+    if profile.okta_pipeline.is_some() || logged_in.okta_pipeline.is_none() {
+        return Ok(());
+    }
+
+    let mut crowbar_config = crowbar_config.clone().read()?;
+    if let Some(configured) = crowbar_config
+        .profiles
+        .iter_mut()
+        .find(|p| p.is_profile(&profile.name))
+    {
+        configured.okta_pipeline = logged_in.okta_pipeline.clone();
+        crowbar_config.write()?;
+    }
+
+    Ok(())
+}
+
+// Looks up a profile by name, with the role and duration given on the command
+// line in place of its own
+pub fn find_profile(
+    profile: &str,
+    role: Option<String>,
+    duration: Option<i32>,
+    crowbar_config: &CrowbarConfig,
+) -> Result<AppProfile> {
     let profiles = crowbar_config
+        .clone()
         .read()?
         .profiles
         .into_iter()
-        .filter(|p| p.clone().is_profile(&profile))
+        .filter(|p| p.clone().is_profile(profile))
         .collect::<Vec<AppProfile>>();
 
     if profiles.is_empty() {
         return Err(anyhow!("No profiles available or empty configuration."));
     }
 
-    let profile = match profiles.first() {
+    let mut profile = match profiles.into_iter().next() {
         Some(profile) => Ok(profile),
         None => Err(anyhow!("Unable to use parsed profile")),
     }?;
+    if let Err(e) = migrate_legacy_credentials(&profile) {
+        debug!("Unable to migrate cached AWS credentials: {}", e);
+    }
 
-    if force_new_credentials {
-        let _creds = ConfigCredentials::load(profile)
-            .map_err(|e| debug!("Couldn't reset credentials: {}", e))
-            .and_then(|creds| creds.delete(profile).map_err(|e| debug!("{}", e)));
+    if role.is_some() {
+        profile.role = role;
+    }
+    if duration.is_some() {
+        profile.duration = duration;
     }
 
-    let mut aws_credentials = AwsCredentials::load(profile).unwrap_or_default();
+    Ok(profile)
+}
 
-    if !aws_credentials.valid() || aws_credentials.is_expired() {
-        aws_credentials = match profile.provider {
-            ProviderType::Okta => {
-                let mut provider = OktaProvider::new(profile)?;
-                provider.new_session()?;
-                provider.fetch_aws_credentials()?
-            }
-            ProviderType::Jumpcloud => {
-                let mut provider = JumpcloudProvider::new(profile)?;
-                provider.new_session()?;
-                provider.fetch_aws_credentials()?
-            }
-            ProviderType::Adfs => {
-                let mut provider = AdfsProvider::new(profile)?;
-                provider.fetch_aws_credentials()?
-            }
-        };
+fn assume_role_from_cache(
+    saml: &saml::Response,
+    profile: &AppProfile,
+    sts: &dyn StsClient,
+) -> Result<AwsCredentials> {
+    debug!("Assuming role with the cached SAML assertion");
 
-        aws_credentials = aws_credentials.write(profile)?;
-    }
+    let credentials = saml::assume_role_from_saml(saml, profile, sts)?;
+    RoleManager::assume_role_chain(credentials, &profile.role_chain, profile.duration, sts)
+}
 
-    Ok(aws_credentials)
+// Longer sessions are refreshed earlier, keeping the same share of the
+// session as for the STS default
+pub fn seconds_to_expiration(duration: Option<i32>) -> i64 {
+    duration.map_or(SECONDS_TO_EXPIRATION, |d| {
+        i64::from(d) * SECONDS_TO_EXPIRATION / DEFAULT_SESSION_DURATION
+    })
 }
 
+// Profiles logging into the same IdP app as the same user and ending up in
+// the same role share their credentials
 pub fn credentials_as_service(profile: &AppProfile) -> String {
+    format!("crowbar::{}::{}", CredentialType::Aws, cache_key(profile))
+}
+
+pub fn legacy_credentials_as_service(profile: &AppProfile) -> String {
     format!("crowbar::{}::{}", CredentialType::Aws, profile.name)
 }
 
+fn cache_key(profile: &AppProfile) -> String {
+    let base_url = profile
+        .base_url()
+        .map(|u| u.to_string())
+        .unwrap_or_else(|_| profile.url.clone());
+    // Without a role the one picked at login depends on the app
+    let role = match &profile.role {
+        Some(role) => role.clone(),
+        None => format!("app:{}", profile.url),
+    };
+    let chain = profile
+        .role_chain
+        .iter()
+        .map(|r| {
+            format!(
+                "{}/{}/{}",
+                r.role_arn,
+                r.external_id.as_deref().unwrap_or_default(),
+                r.session_name.as_deref().unwrap_or_default()
+            )
+        })
+        .collect::<Vec<String>>()
+        .join(",");
+    let duration = profile
+        .session_duration()
+        .map(|d| d.to_string())
+        .unwrap_or_default();
+
+    let identifier = [
+        base_url.as_str(),
+        profile.username.as_str(),
+        role.as_str(),
+        chain.as_str(),
+        duration.as_str(),
+    ]
+    .join("\n");
+
+    format!("{:x}", sha2::Sha256::digest(identifier.as_bytes()))
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -246,6 +457,57 @@ mod test {
         assert!(!create_credentials().is_expired())
     }
 
+    #[test]
+    fn scales_refresh_window_with_duration() {
+        assert_eq!(900, seconds_to_expiration(None));
+        assert_eq!(900, seconds_to_expiration(Some(3600)));
+        assert_eq!(10800, seconds_to_expiration(Some(43200)));
+        assert_eq!(225, seconds_to_expiration(Some(900)));
+    }
+
+    #[test]
+    fn shares_cache_between_profiles_with_same_target() -> Result<()> {
+        let profile = |name: &str, extra: &str| -> AppProfile {
+            toml::from_str(&format!(
+                r#"
+                name = "{}"
+                provider = "okta"
+                url = "https://example.okta.com/home/amazon_aws/0oa1/272"
+                username = "jdoe"
+                {}
+            "#,
+                name, extra
+            ))
+            .unwrap()
+        };
+        let role = r#"role = "arn:aws:iam::123456789012:role/admin""#;
+        let service = |p: AppProfile| credentials_as_service(&p);
+
+        assert_eq!(service(profile("a", role)), service(profile("b", role)));
+        assert_eq!(service(profile("a", "")), service(profile("b", "")));
+        assert_ne!(service(profile("a", role)), service(profile("a", "")));
+        assert_ne!(
+            service(profile("a", role)),
+            service(profile("a", &format!("{}\nduration = 7200", role)))
+        );
+        assert_ne!(
+            service(profile("a", role)),
+            service(profile(
+                "a",
+                &format!(
+                    "{}\n[[role_chain]]\nrole_arn = \"arn:aws:iam::210987654321:role/workload\"",
+                    role
+                )
+            ))
+        );
+        assert_eq!(
+            legacy_credentials_as_service(&profile("a", role)),
+            "crowbar::aws::a"
+        );
+
+        Ok(())
+    }
+
     #[test]
     fn should_render_proper_json() {
         let json = format!(
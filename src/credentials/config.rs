@@ -1,7 +1,8 @@
 use crate::config::app::AppProfile;
+use crate::credentials::store;
 use crate::credentials::{Credential, CredentialType};
 use crate::utils;
-use anyhow::{anyhow, Result};
+use anyhow::Result;
 use log::debug;
 
 #[derive(Clone)]
@@ -27,9 +28,7 @@ impl Credential<AppProfile, ConfigCredentials> for ConfigCredentials {
 
         debug!("Trying to load credentials from ID {}", &service);
 
-        let password = keyring::Entry::new(&service, &profile.username)
-            .get_password()
-            .map_err(|e| anyhow!("{}", e))?;
+        let password = store::current().get(&service, &profile.username)?;
 
         Ok(ConfigCredentials {
             credential_type,
@@ -45,27 +44,20 @@ impl Credential<AppProfile, ConfigCredentials> for ConfigCredentials {
             profile.base_url()?.host().unwrap()
         );
 
-        keyring::Entry::new(&service, &profile.username)
-            .set_password(&self.password)
-            .map_err(|e| anyhow!("{}", e))?;
+        store::current().set(&service, &profile.username, &self.password)?;
 
         Ok(self)
     }
 
     fn delete(self, profile: &AppProfile) -> Result<ConfigCredentials> {
         let service = format!("crowbar::{}::{}", self.credential_type, profile);
-        let keyring = keyring::Entry::new(&service, &profile.username);
 
         debug!(
             "Deleting credentials for {} at {}",
             &profile.username, &service
         );
 
-        let pass = keyring.get_password();
-
-        if pass.is_ok() {
-            keyring.delete_password().map_err(|e| anyhow!("{}", e))?
-        }
+        store::current().delete(&service, &profile.username)?;
 
         Ok(self)
     }
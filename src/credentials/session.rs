@@ -0,0 +1,189 @@
+use crate::config::app::AppProfile;
+use crate::credentials::store;
+use crate::credentials::{Credential, CredentialType};
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use cookie_store::{Cookie, CookieStore};
+use log::debug;
+use reqwest::header::HeaderValue;
+use reqwest::Url;
+use serde::{Deserialize, Serialize};
+use std::sync::Mutex;
+
+// The session an IdP hands out after login, kept so later logins can fetch a
+// fresh SAML assertion without asking for the password or MFA again
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Default)]
+pub struct IdpSession {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expires_at: Option<DateTime<Utc>>,
+    #[serde(default)]
+    pub cookies: Vec<Cookie<'static>>,
+}
+
+impl IdpSession {
+    // Sessions without a known expiry are checked with the IdP instead
+    pub fn is_expired(&self) -> bool {
+        self.expires_at.is_some_and(|e| e <= Utc::now())
+    }
+}
+
+impl Credential<AppProfile, IdpSession> for IdpSession {
+    fn create(_profile: &AppProfile) -> Result<IdpSession> {
+        Ok(IdpSession::default())
+    }
+
+    fn load(profile: &AppProfile) -> Result<IdpSession> {
+        let service = session_as_service(profile);
+
+        debug!("Trying to load IdP session from ID {}", &service);
+
+        let session = store::current().get(&service, &profile.username)?;
+
+        Ok(serde_json::from_str(&session)?)
+    }
+
+    fn write(self, profile: &AppProfile) -> Result<IdpSession> {
+        let service = session_as_service(profile);
+
+        debug!("Saving IdP session for {}", &service);
+
+        store::current().set(&service, &profile.username, &serde_json::to_string(&self)?)?;
+
+        Ok(self)
+    }
+
+    fn delete(self, profile: &AppProfile) -> Result<IdpSession> {
+        let service = session_as_service(profile);
+
+        debug!("Deleting IdP session at {}", &service);
+        store::current().delete(&service, &profile.username)?;
+
+        Ok(self)
+    }
+}
+
+// Sessions belong to a user of an IdP, so profiles logging in as the same
+// user share them
+pub fn session_as_service(profile: &AppProfile) -> String {
+    format!("crowbar::{}::{}", CredentialType::Session, profile)
+}
+
+// A cookie jar for the HTTP clients of providers which, unlike the one of
+// reqwest, can be saved with the session and restored from it
+#[derive(Debug, Default)]
+pub struct SessionJar(Mutex<CookieStore>);
+
+impl SessionJar {
+    pub fn restore(&self, session: &IdpSession) {
+        let store =
+            CookieStore::from_cookies(session.cookies.iter().cloned().map(Ok::<_, ()>), false)
+                .unwrap_or_default();
+
+        if let Ok(mut jar) = self.0.lock() {
+            *jar = store
+        }
+    }
+
+    pub fn clear(&self) {
+        if let Ok(mut jar) = self.0.lock() {
+            jar.clear()
+        }
+    }
+
+    // Includes cookies lasting for the browser session only, as that's how
+    // IdPs usually hand out their session
+    pub fn session_cookies(&self) -> Vec<Cookie<'static>> {
+        self.0
+            .lock()
+            .map(|jar| jar.iter_unexpired().cloned().collect())
+            .unwrap_or_default()
+    }
+}
+
+impl reqwest::cookie::CookieStore for SessionJar {
+    fn set_cookies(&self, cookie_headers: &mut dyn Iterator<Item = &HeaderValue>, url: &Url) {
+        if let Ok(mut jar) = self.0.lock() {
+            for header in cookie_headers {
+                if let Some(e) = header.to_str().ok().and_then(|h| jar.parse(h, url).err()) {
+                    debug!("Ignoring cookie from {}: {}", url, e)
+                }
+            }
+        }
+    }
+
+    fn cookies(&self, url: &Url) -> Option<HeaderValue> {
+        let cookies = self
+            .0
+            .lock()
+            .ok()?
+            .get_request_values(url)
+            .map(|(name, value)| format!("{}={}", name, value))
+            .collect::<Vec<String>>()
+            .join("; ");
+
+        match cookies.is_empty() {
+            true => None,
+            false => HeaderValue::from_str(&cookies).ok(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use chrono::Duration;
+    use reqwest::cookie::CookieStore as _;
+
+    #[test]
+    fn restores_session_cookies() -> Result<()> {
+        let url = Url::parse("https://example.okta.com/app/saml")?;
+        let jar = SessionJar::default();
+        jar.set_cookies(
+            &mut [
+                HeaderValue::from_static("sid=abc; Path=/; Secure; HttpOnly"),
+                HeaderValue::from_static("old=gone; Path=/; Max-Age=0"),
+            ]
+            .iter(),
+            &url,
+        );
+
+        let session = IdpSession {
+            id: Some("abc".to_string()),
+            expires_at: Some(Utc::now() + Duration::hours(2)),
+            cookies: jar.session_cookies(),
+        };
+        let session: IdpSession = serde_json::from_str(&serde_json::to_string(&session)?)?;
+
+        let restored = SessionJar::default();
+        restored.restore(&session);
+
+        assert!(!session.is_expired());
+        assert_eq!(
+            restored.cookies(&url),
+            Some(HeaderValue::from_static("sid=abc"))
+        );
+        assert_eq!(
+            restored.cookies(&Url::parse("https://other.okta.com/")?),
+            None
+        );
+
+        restored.clear();
+        assert_eq!(restored.cookies(&url), None);
+
+        Ok(())
+    }
+
+    #[test]
+    fn shows_if_expired() {
+        let session = |expires_at| IdpSession {
+            expires_at,
+            ..Default::default()
+        };
+
+        assert!(session(Some(Utc::now() - Duration::minutes(1))).is_expired());
+        assert!(!session(Some(Utc::now() + Duration::minutes(1))).is_expired());
+        assert!(!session(None).is_expired());
+    }
+}
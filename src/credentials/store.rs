@@ -0,0 +1,618 @@
+use crate::credentials::CredentialType;
+use crate::utils;
+
+use anyhow::{anyhow, Context, Result};
+use base64::{engine::general_purpose::STANDARD as b64, Engine as _};
+use log::debug;
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, AES_256_GCM, NONCE_LEN};
+use ring::pbkdf2;
+use ring::rand::{SecureRandom, SystemRandom};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::num::NonZeroU32;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, RwLock};
+
+// A base64 encoded key of 32 bytes unlocking the encrypted file store
+pub const STORE_KEY_VARIABLE: &str = "CROWBAR_STORE_KEY";
+pub const STORE_PASSPHRASE_VARIABLE: &str = "CROWBAR_STORE_PASSPHRASE";
+const STORE_FILE_NAME: &str = "secrets.json";
+const STORE_FILE_VERSION: u32 = 1;
+const KEY_LEN: usize = 32;
+const SALT_LEN: usize = 16;
+const PBKDF2_ITERATIONS: u32 = 600_000;
+// Encrypted with the key of the store, so a wrong passphrase is noticed
+// before anything gets written with it
+const CHECK_VALUE: &str = "crowbar";
+const CHECK_ENTRY: &str = "check";
+
+static STORE: RwLock<Option<Arc<dyn SecretStore>>> = RwLock::new(None);
+
+// Where passwords, sessions and cached credentials are kept. Each secret is
+// identified by a service and a key within that service.
+pub trait SecretStore: Send + Sync {
+    fn get(&self, service: &str, key: &str) -> Result<String>;
+    fn set(&self, service: &str, key: &str, secret: &str) -> Result<()>;
+    // Deleting a secret that isn't stored is not an error
+    fn delete(&self, service: &str, key: &str) -> Result<()>;
+}
+
+// Makes all credentials go to the given store from now on
+pub fn use_store(store: Arc<dyn SecretStore>) {
+    if let Ok(mut current) = STORE.write() {
+        *current = Some(store)
+    }
+}
+
+// The store credentials go to, which is the OS keyring unless another one
+// was selected
+pub fn current() -> Arc<dyn SecretStore> {
+    STORE
+        .read()
+        .ok()
+        .and_then(|s| s.clone())
+        .unwrap_or_else(|| Arc::new(KeyringStore))
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Default)]
+#[serde(tag = "backend", rename_all = "lowercase")]
+pub enum StoreBackend {
+    #[default]
+    Keyring,
+    File {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        path: Option<String>,
+    },
+}
+
+impl StoreBackend {
+    pub fn is_keyring(&self) -> bool {
+        *self == StoreBackend::Keyring
+    }
+
+    pub fn open(&self) -> Result<Arc<dyn SecretStore>> {
+        match self {
+            StoreBackend::Keyring => Ok(Arc::new(KeyringStore)),
+            StoreBackend::File { path } => {
+                let path = match path {
+                    Some(path) => PathBuf::from(path),
+                    None => dirs::config_dir()
+                        .with_context(|| "Unable to determine the configuration directory")?
+                        .join("crowbar")
+                        .join(STORE_FILE_NAME),
+                };
+
+                Ok(Arc::new(EncryptedFileStore::new(path, Unlock::from_env()?)))
+            }
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct KeyringStore;
+
+impl SecretStore for KeyringStore {
+    fn get(&self, service: &str, key: &str) -> Result<String> {
+        keyring::Entry::new(service, key)
+            .get_password()
+            .map_err(|e| anyhow!("{}", e))
+    }
+
+    fn set(&self, service: &str, key: &str, secret: &str) -> Result<()> {
+        keyring::Entry::new(service, key)
+            .set_password(secret)
+            .map_err(|e| anyhow!("{}", e))
+    }
+
+    fn delete(&self, service: &str, key: &str) -> Result<()> {
+        let entry = keyring::Entry::new(service, key);
+
+        if entry.get_password().is_ok() {
+            entry.delete_password().map_err(|e| anyhow!("{}", e))?
+        }
+
+        Ok(())
+    }
+}
+
+// Keeps secrets for the lifetime of the process only, e.g. for tests
+#[derive(Default)]
+pub struct MemoryStore {
+    secrets: Mutex<HashMap<(String, String), String>>,
+}
+
+impl SecretStore for MemoryStore {
+    fn get(&self, service: &str, key: &str) -> Result<String> {
+        self.secrets
+            .lock()
+            .map_err(|_| anyhow!("Secret store was poisoned by a failed call"))?
+            .get(&(service.to_owned(), key.to_owned()))
+            .cloned()
+            .with_context(|| format!("No secret stored for {} of {}", key, service))
+    }
+
+    fn set(&self, service: &str, key: &str, secret: &str) -> Result<()> {
+        let _previous = self
+            .secrets
+            .lock()
+            .map_err(|_| anyhow!("Secret store was poisoned by a failed call"))?
+            .insert((service.to_owned(), key.to_owned()), secret.to_owned());
+
+        Ok(())
+    }
+
+    fn delete(&self, service: &str, key: &str) -> Result<()> {
+        let _previous = self
+            .secrets
+            .lock()
+            .map_err(|_| anyhow!("Secret store was poisoned by a failed call"))?
+            .remove(&(service.to_owned(), key.to_owned()));
+
+        Ok(())
+    }
+}
+
+// Forgets everything it's given, so nothing outlives a single run
+#[derive(Default)]
+pub struct NullStore;
+
+impl SecretStore for NullStore {
+    fn get(&self, service: &str, key: &str) -> Result<String> {
+        Err(anyhow!(
+            "Not caching secrets, nothing stored for {} of {}",
+            key,
+            service
+        ))
+    }
+
+    fn set(&self, _service: &str, _key: &str, _secret: &str) -> Result<()> {
+        Ok(())
+    }
+
+    fn delete(&self, _service: &str, _key: &str) -> Result<()> {
+        Ok(())
+    }
+}
+
+// Keeps secrets of the given types in memory and hands all others to another
+// store, e.g. so made-up credentials never mix with the real ones
+pub struct ScratchStore {
+    store: Arc<dyn SecretStore>,
+    scratch: MemoryStore,
+    prefixes: Vec<String>,
+}
+
+impl ScratchStore {
+    pub fn new(store: Arc<dyn SecretStore>, types: &[CredentialType]) -> ScratchStore {
+        ScratchStore {
+            store,
+            scratch: MemoryStore::default(),
+            prefixes: types.iter().map(|t| format!("crowbar::{}::", t)).collect(),
+        }
+    }
+
+    fn store_for(&self, service: &str) -> &dyn SecretStore {
+        match self.prefixes.iter().any(|p| service.starts_with(p)) {
+            true => &self.scratch,
+            false => self.store.as_ref(),
+        }
+    }
+}
+
+impl SecretStore for ScratchStore {
+    fn get(&self, service: &str, key: &str) -> Result<String> {
+        self.store_for(service).get(service, key)
+    }
+
+    fn set(&self, service: &str, key: &str, secret: &str) -> Result<()> {
+        self.store_for(service).set(service, key, secret)
+    }
+
+    fn delete(&self, service: &str, key: &str) -> Result<()> {
+        self.store_for(service).delete(service, key)
+    }
+}
+
+// How the encrypted file store gets its key
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Unlock {
+    Key(Vec<u8>),
+    Passphrase(String),
+    Prompt,
+}
+
+impl Unlock {
+    pub fn from_env() -> Result<Unlock> {
+        if let Some(key) = utils::non_empty_env_var(STORE_KEY_VARIABLE) {
+            let key = b64
+                .decode(key.trim())
+                .with_context(|| format!("{} is not valid base64", STORE_KEY_VARIABLE))?;
+            return Ok(Unlock::Key(key));
+        }
+
+        Ok(match utils::non_empty_env_var(STORE_PASSPHRASE_VARIABLE) {
+            Some(passphrase) => Unlock::Passphrase(passphrase),
+            None => Unlock::Prompt,
+        })
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct StoreFile {
+    version: u32,
+    salt: String,
+    iterations: u32,
+    check: String,
+    #[serde(default)]
+    secrets: BTreeMap<String, String>,
+}
+
+// Keeps secrets in a file, each encrypted with AES-256-GCM under a key
+// derived from a passphrase with PBKDF2, or given directly. The file is only
+// unlocked once a secret is needed.
+pub struct EncryptedFileStore {
+    path: PathBuf,
+    unlock: Unlock,
+    key: Mutex<Option<[u8; KEY_LEN]>>,
+    rng: SystemRandom,
+}
+
+impl EncryptedFileStore {
+    pub fn new(path: PathBuf, unlock: Unlock) -> Self {
+        EncryptedFileStore {
+            path,
+            unlock,
+            key: Mutex::new(None),
+            rng: SystemRandom::new(),
+        }
+    }
+
+    fn read(&self) -> Result<Option<(StoreFile, LessSafeKey)>> {
+        if !self.path.exists() {
+            return Ok(None);
+        }
+
+        let content = fs::read_to_string(&self.path)
+            .with_context(|| format!("Unable to read {}", self.path.display()))?;
+        let file: StoreFile = serde_json::from_str(&content)
+            .with_context(|| format!("{} is not a crowbar secret store", self.path.display()))?;
+
+        if file.version != STORE_FILE_VERSION {
+            return Err(anyhow!(
+                "Unsupported version {} of secret store {}",
+                file.version,
+                self.path.display()
+            ));
+        }
+
+        let key = self.unlock(&file, false)?;
+        if decrypt(&key, CHECK_ENTRY, &file.check).ok().as_deref() != Some(CHECK_VALUE) {
+            self.forget_key();
+            return Err(anyhow!(
+                "Unable to unlock {}, please check the passphrase or key",
+                self.path.display()
+            ));
+        }
+
+        Ok(Some((file, key)))
+    }
+
+    fn read_or_create(&self) -> Result<(StoreFile, LessSafeKey)> {
+        if let Some(opened) = self.read()? {
+            return Ok(opened);
+        }
+
+        debug!("Creating secret store at {}", self.path.display());
+
+        let mut salt = [0u8; SALT_LEN];
+        self.rng
+            .fill(&mut salt)
+            .map_err(|_| anyhow!("Unable to generate salt"))?;
+        let mut file = StoreFile {
+            version: STORE_FILE_VERSION,
+            salt: b64.encode(salt),
+            iterations: PBKDF2_ITERATIONS,
+            check: String::new(),
+            secrets: BTreeMap::new(),
+        };
+        let key = self.unlock(&file, true)?;
+        file.check = self.encrypt(&key, CHECK_ENTRY, CHECK_VALUE)?;
+
+        Ok((file, key))
+    }
+
+    fn write(&self, file: &StoreFile) -> Result<()> {
+        if let Some(dir) = self.path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+
+        // Written next to the store and moved over it, so an interrupted
+        // write doesn't lose the secrets
+        let temporary = self.path.with_extension("tmp");
+        let mut options = OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::OpenOptionsExt;
+            options.mode(0o600);
+        }
+
+        options
+            .open(&temporary)?
+            .write_all(serde_json::to_string_pretty(file)?.as_bytes())?;
+        fs::rename(&temporary, &self.path)
+            .with_context(|| format!("Unable to write {}", self.path.display()))
+    }
+
+    fn unlock(&self, file: &StoreFile, new: bool) -> Result<LessSafeKey> {
+        let mut cached = self
+            .key
+            .lock()
+            .map_err(|_| anyhow!("Secret store was poisoned by a failed call"))?;
+
+        let key = match *cached {
+            Some(key) => key,
+            None => {
+                let key = match &self.unlock {
+                    Unlock::Key(key) => {
+                        <[u8; KEY_LEN]>::try_from(key.as_slice()).map_err(|_| {
+                            anyhow!("{} has to be {} bytes long", STORE_KEY_VARIABLE, KEY_LEN)
+                        })?
+                    }
+                    Unlock::Passphrase(passphrase) => derive_key(file, passphrase)?,
+                    Unlock::Prompt => {
+                        derive_key(file, &utils::prompt_passphrase(&self.path, new)?)?
+                    }
+                };
+                *cached = Some(key);
+                key
+            }
+        };
+
+        Ok(LessSafeKey::new(
+            UnboundKey::new(&AES_256_GCM, &key).map_err(|_| anyhow!("Invalid store key"))?,
+        ))
+    }
+
+    fn forget_key(&self) {
+        if let Ok(mut cached) = self.key.lock() {
+            *cached = None
+        }
+    }
+
+    fn encrypt(&self, key: &LessSafeKey, entry: &str, secret: &str) -> Result<String> {
+        let mut nonce = [0u8; NONCE_LEN];
+        self.rng
+            .fill(&mut nonce)
+            .map_err(|_| anyhow!("Unable to generate nonce"))?;
+
+        let mut sealed = secret.as_bytes().to_vec();
+        key.seal_in_place_append_tag(
+            Nonce::assume_unique_for_key(nonce),
+            Aad::from(entry.as_bytes()),
+            &mut sealed,
+        )
+        .map_err(|_| anyhow!("Unable to encrypt secret"))?;
+
+        Ok(b64.encode([nonce.as_slice(), sealed.as_slice()].concat()))
+    }
+}
+
+impl SecretStore for EncryptedFileStore {
+    fn get(&self, service: &str, key: &str) -> Result<String> {
+        let entry = entry_name(service, key);
+        let (file, store_key) = self
+            .read()?
+            .with_context(|| format!("No secret store at {}", self.path.display()))?;
+
+        match file.secrets.get(&entry) {
+            Some(secret) => decrypt(&store_key, &entry, secret),
+            None => Err(anyhow!("No secret stored for {} of {}", key, service)),
+        }
+    }
+
+    fn set(&self, service: &str, key: &str, secret: &str) -> Result<()> {
+        let entry = entry_name(service, key);
+        let (mut file, store_key) = self.read_or_create()?;
+        let sealed = self.encrypt(&store_key, &entry, secret)?;
+
+        let _previous = file.secrets.insert(entry, sealed);
+        self.write(&file)
+    }
+
+    fn delete(&self, service: &str, key: &str) -> Result<()> {
+        let entry = entry_name(service, key);
+
+        match self.read()? {
+            Some((mut file, _)) => match file.secrets.remove(&entry) {
+                Some(_) => self.write(&file),
+                None => Ok(()),
+            },
+            None => Ok(()),
+        }
+    }
+}
+
+fn entry_name(service: &str, key: &str) -> String {
+    format!("{}/{}", service, key)
+}
+
+fn derive_key(file: &StoreFile, passphrase: &str) -> Result<[u8; KEY_LEN]> {
+    let salt = b64.decode(&file.salt)?;
+    let iterations =
+        NonZeroU32::new(file.iterations).with_context(|| "Invalid iteration count in store")?;
+
+    let mut key = [0u8; KEY_LEN];
+    pbkdf2::derive(
+        pbkdf2::PBKDF2_HMAC_SHA256,
+        iterations,
+        &salt,
+        passphrase.as_bytes(),
+        &mut key,
+    );
+
+    Ok(key)
+}
+
+fn decrypt(key: &LessSafeKey, entry: &str, sealed: &str) -> Result<String> {
+    let mut sealed = b64.decode(sealed)?;
+    if sealed.len() < NONCE_LEN {
+        return Err(anyhow!("Secret for {} is truncated", entry));
+    }
+
+    let mut secret = sealed.split_off(NONCE_LEN);
+    let nonce = Nonce::try_assume_unique_for_key(&sealed)
+        .map_err(|_| anyhow!("Invalid nonce for {}", entry))?;
+    let secret = key
+        .open_in_place(nonce, Aad::from(entry.as_bytes()), &mut secret)
+        .map_err(|_| anyhow!("Unable to decrypt secret for {}", entry))?;
+
+    Ok(String::from_utf8(secret.to_vec())?)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tempfile::TempDir;
+
+    const KEY: [u8; KEY_LEN] = [7; KEY_LEN];
+
+    #[test]
+    fn keeps_secrets_encrypted_in_file() -> Result<()> {
+        let dir = TempDir::new()?;
+        let path = dir.path().join("store").join(STORE_FILE_NAME);
+        let store = EncryptedFileStore::new(path.clone(), Unlock::Key(KEY.to_vec()));
+
+        assert!(store.get("crowbar::config::abc", "jdoe").is_err());
+        store.set("crowbar::config::abc", "jdoe", "hunter2")?;
+        store.set("crowbar::aws::abc", "session_token", "token")?;
+
+        let reopened = EncryptedFileStore::new(path.clone(), Unlock::Key(KEY.to_vec()));
+        assert_eq!(reopened.get("crowbar::config::abc", "jdoe")?, "hunter2");
+        assert!(!fs::read_to_string(&path)?.contains("hunter2"));
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            assert_eq!(fs::metadata(&path)?.permissions().mode() & 0o777, 0o600);
+        }
+
+        reopened.delete("crowbar::config::abc", "jdoe")?;
+        reopened.delete("crowbar::config::abc", "jdoe")?;
+        assert!(store.get("crowbar::config::abc", "jdoe").is_err());
+        assert_eq!(store.get("crowbar::aws::abc", "session_token")?, "token");
+
+        Ok(())
+    }
+
+    #[test]
+    fn unlocks_with_passphrase_only() -> Result<()> {
+        let dir = TempDir::new()?;
+        let path = dir.path().join(STORE_FILE_NAME);
+        let store = EncryptedFileStore::new(path.clone(), Unlock::Passphrase("open".to_string()));
+        store.set("crowbar::config::abc", "jdoe", "hunter2")?;
+
+        let wrong = EncryptedFileStore::new(path.clone(), Unlock::Passphrase("shut".to_string()));
+        assert_eq!(
+            wrong
+                .get("crowbar::config::abc", "jdoe")
+                .unwrap_err()
+                .to_string(),
+            format!(
+                "Unable to unlock {}, please check the passphrase or key",
+                path.display()
+            )
+        );
+        assert!(wrong.set("crowbar::config::abc", "jdoe", "other").is_err());
+
+        let right = EncryptedFileStore::new(path, Unlock::Passphrase("open".to_string()));
+        assert_eq!(right.get("crowbar::config::abc", "jdoe")?, "hunter2");
+
+        Ok(())
+    }
+
+    #[test]
+    fn binds_secrets_to_their_entry() -> Result<()> {
+        let dir = TempDir::new()?;
+        let path = dir.path().join(STORE_FILE_NAME);
+        let store = EncryptedFileStore::new(path.clone(), Unlock::Key(KEY.to_vec()));
+        store.set("crowbar::config::abc", "jdoe", "hunter2")?;
+
+        // Moving a secret to another entry doesn't let it be read there
+        let content = fs::read_to_string(&path)?;
+        let mut file: StoreFile = serde_json::from_str(&content)?;
+        let secret = file.secrets["crowbar::config::abc/jdoe"].clone();
+        let _previous = file
+            .secrets
+            .insert("crowbar::config::abc/mallory".to_string(), secret);
+        store.write(&file)?;
+
+        assert!(store.get("crowbar::config::abc", "mallory").is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn keeps_secrets_in_memory_only() -> Result<()> {
+        let memory = MemoryStore::default();
+        memory.set("crowbar::config::abc", "jdoe", "hunter2")?;
+
+        assert_eq!(memory.get("crowbar::config::abc", "jdoe")?, "hunter2");
+        assert!(memory.get("crowbar::config::abc", "mallory").is_err());
+        assert!(MemoryStore::default()
+            .get("crowbar::config::abc", "jdoe")
+            .is_err());
+
+        memory.delete("crowbar::config::abc", "jdoe")?;
+        memory.delete("crowbar::config::abc", "jdoe")?;
+        assert!(memory.get("crowbar::config::abc", "jdoe").is_err());
+
+        let null = NullStore;
+        null.set("crowbar::config::abc", "jdoe", "hunter2")?;
+        assert!(null.get("crowbar::config::abc", "jdoe").is_err());
+        null.delete("crowbar::config::abc", "jdoe")?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn keeps_scratch_types_apart() -> Result<()> {
+        let memory = Arc::new(MemoryStore::default());
+        memory.set("crowbar::aws::abc", "access_key_id", "AKIAREAL")?;
+        let scratch = ScratchStore::new(memory.clone(), &[CredentialType::Aws]);
+
+        assert!(scratch.get("crowbar::aws::abc", "access_key_id").is_err());
+        scratch.set("crowbar::aws::abc", "access_key_id", "ASIAFAKE")?;
+        scratch.set("crowbar::config::abc", "jdoe", "hunter2")?;
+
+        assert_eq!(
+            scratch.get("crowbar::aws::abc", "access_key_id")?,
+            "ASIAFAKE"
+        );
+        assert_eq!(
+            memory.get("crowbar::aws::abc", "access_key_id")?,
+            "AKIAREAL"
+        );
+        assert_eq!(memory.get("crowbar::config::abc", "jdoe")?, "hunter2");
+
+        Ok(())
+    }
+
+    #[test]
+    fn parses_backend_from_config() -> Result<()> {
+        assert_eq!(
+            toml::from_str::<StoreBackend>(r#"backend = "file""#)?,
+            StoreBackend::File { path: None }
+        );
+        assert_eq!(
+            toml::from_str::<StoreBackend>("backend = \"file\"\npath = \"/tmp/secrets.json\"")?,
+            StoreBackend::File {
+                path: Some("/tmp/secrets.json".to_string())
+            }
+        );
+        assert!(toml::from_str::<StoreBackend>(r#"backend = "keyring""#)?.is_keyring());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,655 @@
+use crate::utils;
+
+use anyhow::{anyhow, Context, Result};
+use chrono::Utc;
+use log::{debug, trace};
+use reqwest::blocking::{Client as HttpClient, Response};
+use reqwest::Url;
+use select::document::Document;
+use select::node::Node;
+use select::predicate::{Attr, Name, Predicate};
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fmt;
+use std::{thread, time::Duration};
+
+const FRAME_VERSION: &str = "2.6";
+const FRAMELESS_PATH: &str = "/frame/frameless/";
+const OIDC_EXIT: &str = "OIDC_EXIT";
+const DEFAULT_DEVICE: &str = "phone1";
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+pub const DUO_WAIT_TIMEOUT: i64 = 60;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum DuoFactor {
+    Push,
+    Passcode(String),
+    PhoneCall,
+}
+
+// What an IdP hands out to start a Duo verification: the API host and the
+// signed request in the form `TX|...:APP|...`
+#[derive(Debug, Clone, PartialEq)]
+pub struct DuoChallenge {
+    pub host: String,
+    pub signature: String,
+    // The page Duo's frame would be embedded in
+    pub parent: String,
+}
+
+#[derive(Deserialize, Debug)]
+struct DuoResponse<T> {
+    stat: String,
+    message: Option<String>,
+    response: Option<T>,
+}
+
+#[derive(Deserialize, Debug)]
+struct PromptResponse {
+    txid: String,
+}
+
+#[derive(Deserialize, Debug)]
+struct StatusResponse {
+    status: Option<String>,
+    status_code: Option<String>,
+    result: Option<String>,
+    result_url: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+struct ResultResponse {
+    cookie: String,
+}
+
+#[derive(Deserialize, Debug)]
+struct PromptData {
+    #[serde(default)]
+    phones: Vec<Phone>,
+}
+
+#[derive(Deserialize, Debug)]
+struct Phone {
+    key: String,
+}
+
+impl fmt::Display for DuoFactor {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // Duo expects these exact names in the prompt request
+        match self {
+            DuoFactor::Push => write!(f, "Duo Push"),
+            DuoFactor::Passcode(_) => write!(f, "Passcode"),
+            DuoFactor::PhoneCall => write!(f, "Phone Call"),
+        }
+    }
+}
+
+pub fn prompt_factor() -> Result<DuoFactor> {
+    let factors = ["Duo Push", "Passcode", "Phone Call"];
+
+    eprintln!("Please select the Duo factor to use:");
+    let selection = dialoguer::Select::new()
+        .items(&factors)
+        .default(0)
+        .interact()?;
+
+    Ok(match selection {
+        0 => DuoFactor::Push,
+        1 => DuoFactor::Passcode(utils::prompt_mfa()?),
+        _ => DuoFactor::PhoneCall,
+    })
+}
+
+impl DuoChallenge {
+    fn api_url(&self) -> Result<Url> {
+        let url = if self.host.contains("://") {
+            self.host.clone()
+        } else {
+            format!("https://{}", self.host)
+        };
+
+        Url::parse(&url).with_context(|| format!("Invalid Duo API host {}", self.host))
+    }
+
+    fn split_signature(&self) -> Result<(&str, &str)> {
+        self.signature
+            .split_once(':')
+            .with_context(|| "Invalid Duo signature in MFA challenge")
+    }
+}
+
+// Runs a verification through Duo's frame protocol and returns the signed
+// response (`AUTH|...:APP|...`) the IdP expects back
+pub fn verify(challenge: &DuoChallenge, factor: &DuoFactor) -> Result<String> {
+    let client = HttpClient::builder().cookie_store(true).build()?;
+    let api_url = challenge.api_url()?;
+    let (tx, app) = challenge.split_signature()?;
+
+    let mut auth_url = api_url.join("frame/web/v1/auth")?;
+    auth_url
+        .query_pairs_mut()
+        .append_pair("tx", tx)
+        .append_pair("parent", &challenge.parent)
+        .append_pair("v", FRAME_VERSION);
+
+    let response = client
+        .post(auth_url)
+        .form(&[("parent", challenge.parent.as_str())])
+        .send()?
+        .error_for_status()?;
+    let prompt_url = response.url().clone();
+
+    // Applications switched to the Universal Prompt are sent on to its
+    // frameless pages instead
+    if prompt_url.path().starts_with(FRAMELESS_PATH) {
+        return verify_frameless(&client, &api_url, response, factor, app);
+    }
+
+    let document = Document::from(response.text()?.as_str());
+
+    let sid = fetch_input_value(&document, "sid")
+        .or_else(|| fetch_query_value(&prompt_url, "sid"))
+        .with_context(|| "Missing Duo session in prompt")?;
+    let device = fetch_device(&document).unwrap_or_else(|| DEFAULT_DEVICE.to_string());
+    debug!("Duo session {} with device {}", sid, device);
+
+    let mut prompt_form = HashMap::from([
+        ("sid", sid.clone()),
+        ("device", device),
+        ("factor", factor.to_string()),
+        ("out_of_date", "False".to_string()),
+    ]);
+    if let DuoFactor::Passcode(passcode) = factor {
+        let _ = prompt_form.insert("passcode", passcode.clone());
+    }
+
+    let prompt: PromptResponse = post(&client, api_url.join("frame/prompt")?, &prompt_form)?;
+    let status_form = HashMap::from([("sid", sid.clone()), ("txid", prompt.txid)]);
+
+    let status = wait_for_approval(&client, api_url.join("frame/status")?, &status_form)?;
+    let result_url = status
+        .result_url
+        .with_context(|| "Missing result URL in Duo status")?;
+
+    let result: ResultResponse = post(
+        &client,
+        api_url.join(&result_url)?,
+        &HashMap::from([("sid", sid)]),
+    )?;
+
+    Ok(format!("{}:{}", result.cookie, app))
+}
+
+// Runs a verification through the pages of the Universal Prompt, which take
+// the same factors as the frame but hand the signed response to the parent
+// page once the prompt exits
+fn verify_frameless(
+    client: &HttpClient,
+    api_url: &Url,
+    response: Response,
+    factor: &DuoFactor,
+    app: &str,
+) -> Result<String> {
+    let page_url = response.url().clone();
+    let document = Document::from(response.text()?.as_str());
+
+    // The landing page submits the browser details before the prompt starts
+    let form = document
+        .find(Name("form"))
+        .next()
+        .with_context(|| "Missing form on Duo Universal Prompt page")?;
+    let form_content = fetch_form_inputs(&form);
+    let xsrf = form_content.get("_xsrf").cloned().unwrap_or_default();
+    let submit_url = page_url.join(form.attr("action").unwrap_or(page_url.path()))?;
+
+    let response = client
+        .post(submit_url)
+        .form(&form_content)
+        .send()?
+        .error_for_status()?;
+    let sid = fetch_query_value(response.url(), "sid")
+        .with_context(|| "Missing Duo session in Universal Prompt")?;
+
+    let mut data_url = api_url.join("frame/v4/auth/prompt/data")?;
+    data_url
+        .query_pairs_mut()
+        .append_pair("post_auth_action", OIDC_EXIT)
+        .append_pair("sid", &sid);
+    let data: PromptData = read(client.get(data_url).send()?)?;
+    let device = data
+        .phones
+        .into_iter()
+        .next()
+        .map(|phone| phone.key)
+        .unwrap_or_else(|| DEFAULT_DEVICE.to_string());
+    debug!(
+        "Duo Universal Prompt session {} with device {}",
+        sid, device
+    );
+
+    let mut prompt_form = HashMap::from([
+        ("sid", sid.clone()),
+        ("device", device.clone()),
+        ("factor", factor.to_string()),
+        ("postAuthDestination", OIDC_EXIT.to_string()),
+    ]);
+    if let DuoFactor::Passcode(passcode) = factor {
+        let _ = prompt_form.insert("passcode", passcode.clone());
+    }
+
+    let prompt: PromptResponse = post(client, api_url.join("frame/v4/prompt")?, &prompt_form)?;
+    let status_form = HashMap::from([("sid", sid.clone()), ("txid", prompt.txid.clone())]);
+
+    wait_for_approval(client, api_url.join("frame/v4/status")?, &status_form)?;
+
+    let exit_form = HashMap::from([
+        ("sid", sid),
+        ("txid", prompt.txid),
+        ("factor", factor.to_string()),
+        ("device_key", device),
+        ("_xsrf", xsrf),
+        ("dampen_choice", "true".to_string()),
+    ]);
+    let response = client
+        .post(api_url.join("frame/v4/oidc/exit")?)
+        .form(&exit_form)
+        .send()?
+        .error_for_status()?;
+
+    // Duo either posts the response to the parent page or redirects to it
+    let exit_url = response.url().clone();
+    let document = Document::from(response.text()?.as_str());
+    let sig_response = fetch_input_value(&document, "sig_response")
+        .or_else(|| fetch_query_value(&exit_url, "sig_response"))
+        .with_context(|| "Missing signed response after Duo Universal Prompt")?;
+
+    if sig_response.contains(":APP|") {
+        Ok(sig_response)
+    } else {
+        Ok(format!("{}:{}", sig_response, app))
+    }
+}
+
+// Polls the status of a prompt until the user approved it
+fn wait_for_approval(
+    client: &HttpClient,
+    status_url: Url,
+    status_form: &HashMap<&str, String>,
+) -> Result<StatusResponse> {
+    let time_at_execution = Utc::now();
+
+    loop {
+        let status: StatusResponse = post(client, status_url.clone(), status_form)?;
+        trace!("Duo status: {:?}", status);
+
+        match status.result.as_deref() {
+            Some("SUCCESS") => return Ok(status),
+            Some("FAILURE") => {
+                return Err(anyhow!(
+                    "Duo verification failed: {}",
+                    status
+                        .status
+                        .or(status.status_code)
+                        .unwrap_or_else(|| "denied".to_string())
+                ))
+            }
+            _ => {
+                if let Some(message) = status.status {
+                    eprintln!("{}", message);
+                }
+            }
+        }
+
+        if Utc::now()
+            .signed_duration_since(time_at_execution)
+            .num_seconds()
+            > DUO_WAIT_TIMEOUT
+        {
+            return Err(anyhow!(
+                "No verification after {} seconds",
+                DUO_WAIT_TIMEOUT
+            ));
+        }
+
+        thread::sleep(POLL_INTERVAL);
+    }
+}
+
+fn post<O: DeserializeOwned>(
+    client: &HttpClient,
+    url: Url,
+    form: &HashMap<&str, String>,
+) -> Result<O> {
+    read(client.post(url).form(form).send()?)
+}
+
+fn read<O: DeserializeOwned>(response: Response) -> Result<O> {
+    let response: DuoResponse<O> = response.error_for_status()?.json()?;
+
+    match (response.stat.as_str(), response.response) {
+        ("OK", Some(content)) => Ok(content),
+        _ => Err(anyhow!(
+            "Duo request failed: {}",
+            response.message.unwrap_or(response.stat)
+        )),
+    }
+}
+
+fn fetch_input_value(document: &Document, name: &str) -> Option<String> {
+    document
+        .find(Name("input").and(Attr("name", name)))
+        .next()
+        .and_then(|input| input.attr("value"))
+        .map(|value| value.to_owned())
+}
+
+fn fetch_form_inputs(form: &Node) -> HashMap<String, String> {
+    form.find(Name("input"))
+        .filter_map(|input| {
+            input.attr("name").map(|name| {
+                (
+                    name.to_owned(),
+                    input.attr("value").unwrap_or_default().to_owned(),
+                )
+            })
+        })
+        .collect()
+}
+
+fn fetch_query_value(url: &Url, name: &str) -> Option<String> {
+    url.query_pairs()
+        .find(|(key, _)| key == name)
+        .map(|(_, value)| value.into_owned())
+}
+
+fn fetch_device(document: &Document) -> Option<String> {
+    document
+        .find(
+            Name("select")
+                .and(Attr("name", "device"))
+                .descendant(Name("option")),
+        )
+        .next()
+        .and_then(|option| option.attr("value"))
+        .map(|value| value.to_owned())
+        .or_else(|| fetch_input_value(document, "device"))
+}
+
+// A stand-in for Duo's API that accepts any verification, used by the tests
+// of the providers that support Duo
+#[cfg(test)]
+pub mod mock {
+    use mockito::{Matcher, Mock, ServerGuard};
+
+    pub const SIGNATURE: &str = "TX|dHg=|signature:APP|YXBw|signature";
+    pub const SIGNED_RESPONSE: &str = "AUTH|YXV0aA==|signature:APP|YXBw|signature";
+
+    pub fn server() -> (ServerGuard, Vec<Mock>) {
+        server_with(Matcher::Any, "tests/fixtures/duo/status_success.json")
+    }
+
+    // Checks the prompt request against the given matcher and answers status
+    // requests with the given fixture
+    pub fn server_with(prompt: Matcher, status_path: &str) -> (ServerGuard, Vec<Mock>) {
+        let mut server = mockito::Server::new();
+
+        let mocks = vec![
+            server
+                .mock("POST", "/frame/web/v1/auth")
+                .match_query(Matcher::UrlEncoded(
+                    "tx".to_string(),
+                    "TX|dHg=|signature".to_string(),
+                ))
+                .with_status(302)
+                .with_header("Location", "/frame/prompt?sid=session-id")
+                .create(),
+            server
+                .mock("GET", "/frame/prompt")
+                .match_query(Matcher::UrlEncoded(
+                    "sid".to_string(),
+                    "session-id".to_string(),
+                ))
+                .with_body_from_file("tests/fixtures/duo/prompt_page.html")
+                .create(),
+            server
+                .mock("POST", "/frame/prompt")
+                .match_body(Matcher::AllOf(vec![
+                    Matcher::UrlEncoded("sid".to_string(), "session-id".to_string()),
+                    Matcher::UrlEncoded("device".to_string(), "phone1".to_string()),
+                    prompt,
+                ]))
+                .with_body_from_file("tests/fixtures/duo/prompt.json")
+                .create(),
+            server
+                .mock("POST", "/frame/status")
+                .match_body(Matcher::UrlEncoded(
+                    "txid".to_string(),
+                    "transaction-id".to_string(),
+                ))
+                .with_body_from_file(status_path)
+                .create(),
+            server
+                .mock("POST", "/frame/status/transaction-id")
+                .match_body(Matcher::UrlEncoded(
+                    "sid".to_string(),
+                    "session-id".to_string(),
+                ))
+                .with_body_from_file("tests/fixtures/duo/result.json")
+                .create(),
+        ];
+
+        (server, mocks)
+    }
+
+    // Sends the verification on to the Universal Prompt, checking the prompt
+    // request against the given matcher
+    pub fn frameless_server(prompt: Matcher) -> (ServerGuard, Vec<Mock>) {
+        let mut server = mockito::Server::new();
+
+        let mocks = vec![
+            server
+                .mock("POST", "/frame/web/v1/auth")
+                .match_query(Matcher::UrlEncoded(
+                    "tx".to_string(),
+                    "TX|dHg=|signature".to_string(),
+                ))
+                .with_status(302)
+                .with_header(
+                    "Location",
+                    "/frame/frameless/v4/auth?sid=frameless-id&tx=TX%7CdHg%3D%7Csignature",
+                )
+                .create(),
+            server
+                .mock("GET", "/frame/frameless/v4/auth")
+                .match_query(Matcher::UrlEncoded(
+                    "sid".to_string(),
+                    "frameless-id".to_string(),
+                ))
+                .with_body_from_file("tests/fixtures/duo/frameless_page.html")
+                .create(),
+            server
+                .mock("POST", "/frame/frameless/v4/auth")
+                .match_query(Matcher::UrlEncoded(
+                    "sid".to_string(),
+                    "frameless-id".to_string(),
+                ))
+                .match_body(Matcher::AllOf(vec![
+                    Matcher::UrlEncoded("tx".to_string(), "TX|dHg=|signature".to_string()),
+                    Matcher::UrlEncoded("_xsrf".to_string(), "xsrf-token".to_string()),
+                ]))
+                .with_status(302)
+                .with_header("Location", "/frame/v4/auth/prompt?sid=session-id")
+                .create(),
+            server
+                .mock("GET", "/frame/v4/auth/prompt")
+                .match_query(Matcher::UrlEncoded(
+                    "sid".to_string(),
+                    "session-id".to_string(),
+                ))
+                .with_body("<html></html>")
+                .create(),
+            server
+                .mock("GET", "/frame/v4/auth/prompt/data")
+                .match_query(Matcher::AllOf(vec![
+                    Matcher::UrlEncoded("sid".to_string(), "session-id".to_string()),
+                    Matcher::UrlEncoded("post_auth_action".to_string(), "OIDC_EXIT".to_string()),
+                ]))
+                .with_body_from_file("tests/fixtures/duo/prompt_data.json")
+                .create(),
+            server
+                .mock("POST", "/frame/v4/prompt")
+                .match_body(Matcher::AllOf(vec![
+                    Matcher::UrlEncoded("sid".to_string(), "session-id".to_string()),
+                    Matcher::UrlEncoded("device".to_string(), "phone-key".to_string()),
+                    prompt,
+                ]))
+                .with_body_from_file("tests/fixtures/duo/prompt.json")
+                .create(),
+            server
+                .mock("POST", "/frame/v4/status")
+                .match_body(Matcher::UrlEncoded(
+                    "txid".to_string(),
+                    "transaction-id".to_string(),
+                ))
+                .with_body_from_file("tests/fixtures/duo/status_success.json")
+                .create(),
+            server
+                .mock("POST", "/frame/v4/oidc/exit")
+                .match_body(Matcher::AllOf(vec![
+                    Matcher::UrlEncoded("sid".to_string(), "session-id".to_string()),
+                    Matcher::UrlEncoded("txid".to_string(), "transaction-id".to_string()),
+                    Matcher::UrlEncoded("device_key".to_string(), "phone-key".to_string()),
+                    Matcher::UrlEncoded("_xsrf".to_string(), "xsrf-token".to_string()),
+                ]))
+                .with_body_from_file("tests/fixtures/duo/exit_page.html")
+                .create(),
+        ];
+
+        (server, mocks)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use mockito::Matcher;
+
+    #[test]
+    fn verifies_push_through_duo_frame() -> Result<()> {
+        let (server, mocks) = mock::server();
+        let challenge = DuoChallenge {
+            host: server.url(),
+            signature: mock::SIGNATURE.to_string(),
+            parent: "https://example.okta.com/signin/verify/duo/web".to_string(),
+        };
+
+        let signed = verify(&challenge, &DuoFactor::Push)?;
+
+        assert_eq!(signed, mock::SIGNED_RESPONSE);
+        for mock in mocks {
+            mock.assert();
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn sends_passcode_to_duo() -> Result<()> {
+        let (server, mocks) = mock::server_with(
+            Matcher::AllOf(vec![
+                Matcher::UrlEncoded("factor".to_string(), "Passcode".to_string()),
+                Matcher::UrlEncoded("passcode".to_string(), "123456".to_string()),
+            ]),
+            "tests/fixtures/duo/status_success.json",
+        );
+        let challenge = DuoChallenge {
+            host: server.url(),
+            signature: mock::SIGNATURE.to_string(),
+            parent: "https://example.okta.com/signin/verify/duo/web".to_string(),
+        };
+
+        verify(&challenge, &DuoFactor::Passcode("123456".to_string()))?;
+
+        for mock in mocks {
+            mock.assert();
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn fails_on_denied_request() -> Result<()> {
+        let (server, _mocks) = mock::server_with(
+            Matcher::UrlEncoded("factor".to_string(), "Phone Call".to_string()),
+            "tests/fixtures/duo/status_denied.json",
+        );
+        let challenge = DuoChallenge {
+            host: server.url(),
+            signature: mock::SIGNATURE.to_string(),
+            parent: "https://example.okta.com/signin/verify/duo/web".to_string(),
+        };
+
+        let error = verify(&challenge, &DuoFactor::PhoneCall).unwrap_err();
+
+        assert_eq!(
+            error.to_string(),
+            "Duo verification failed: Login request denied."
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn verifies_push_through_universal_prompt() -> Result<()> {
+        let (server, mocks) = mock::frameless_server(Matcher::UrlEncoded(
+            "factor".to_string(),
+            "Duo Push".to_string(),
+        ));
+        let challenge = DuoChallenge {
+            host: server.url(),
+            signature: mock::SIGNATURE.to_string(),
+            parent: "https://example.okta.com/signin/verify/duo/web".to_string(),
+        };
+
+        let signed = verify(&challenge, &DuoFactor::Push)?;
+
+        assert_eq!(signed, mock::SIGNED_RESPONSE);
+        for mock in mocks {
+            mock.assert();
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn sends_passcode_through_universal_prompt() -> Result<()> {
+        let (server, mocks) = mock::frameless_server(Matcher::AllOf(vec![
+            Matcher::UrlEncoded("factor".to_string(), "Passcode".to_string()),
+            Matcher::UrlEncoded("passcode".to_string(), "123456".to_string()),
+        ]));
+        let challenge = DuoChallenge {
+            host: server.url(),
+            signature: mock::SIGNATURE.to_string(),
+            parent: "https://example.okta.com/signin/verify/duo/web".to_string(),
+        };
+
+        verify(&challenge, &DuoFactor::Passcode("123456".to_string()))?;
+
+        for mock in mocks {
+            mock.assert();
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn names_factors_as_duo_expects() {
+        assert_eq!(DuoFactor::Push.to_string(), "Duo Push");
+        assert_eq!(DuoFactor::PhoneCall.to_string(), "Phone Call");
+        assert_eq!(
+            DuoFactor::Passcode("123456".to_string()).to_string(),
+            "Passcode"
+        );
+    }
+}
@@ -1,6 +1,8 @@
+pub mod format;
+
 use crate::credentials::aws::AwsCredentials;
 use anyhow::Result;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::env;
 use std::process::Child as ExecutorResult;
 use std::process::{Command, Stdio};
@@ -37,15 +39,7 @@ impl Executor {
     }
 
     pub fn run(self) -> Result<ExecutorResult> {
-        let mut variables: HashMap<String, Option<String>> = self.credentials.into();
-        // We don't want to pollute the environment with the expiration time
-        variables.remove_entry("expiration");
-
-        let variables: HashMap<String, String> = variables
-            .iter()
-            .map(|(k, v)| (format!("AWS_{}", k.to_uppercase()), v.clone().unwrap()))
-            .collect();
-
+        let variables = variables(self.credentials);
         let command = self.command.unwrap().join(" ");
         let shell = shell()?;
 
@@ -61,6 +55,19 @@ impl Executor {
     }
 }
 
+// The environment variables the AWS SDKs pick credentials up from, sorted by
+// name so that renderings of them are stable
+pub fn variables(credentials: AwsCredentials) -> BTreeMap<String, String> {
+    let mut variables: HashMap<String, Option<String>> = credentials.into();
+    // We don't want to pollute the environment with the expiration time
+    variables.remove_entry("expiration");
+
+    variables
+        .into_iter()
+        .filter_map(|(k, v)| v.map(|v| (format!("AWS_{}", k.to_uppercase()), v)))
+        .collect()
+}
+
 fn shell() -> Result<Vec<String>> {
     if cfg!(windows) {
         Ok(vec!["cmd.exe".into(), "/C".into()])
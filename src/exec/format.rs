@@ -0,0 +1,170 @@
+use crate::credentials::aws::AwsCredentials;
+use crate::exec;
+use anyhow::{anyhow, Result};
+use std::str::FromStr;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OutputFormat {
+    // The credential_process layout
+    #[default]
+    Json,
+    // One line setting each of the variables crowbar exec sets
+    Variables(Shell),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Shell {
+    Env,
+    Fish,
+    Powershell,
+    Dotenv,
+    Cmd,
+}
+
+impl FromStr for OutputFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "json" => Ok(OutputFormat::Json),
+            "env" => Ok(OutputFormat::Variables(Shell::Env)),
+            "fish" => Ok(OutputFormat::Variables(Shell::Fish)),
+            "powershell" => Ok(OutputFormat::Variables(Shell::Powershell)),
+            "dotenv" => Ok(OutputFormat::Variables(Shell::Dotenv)),
+            "cmd" => Ok(OutputFormat::Variables(Shell::Cmd)),
+            _ => Err(anyhow!("Unknown output format {}", s)),
+        }
+    }
+}
+
+impl OutputFormat {
+    pub fn render(self, credentials: AwsCredentials) -> Result<String> {
+        match self {
+            OutputFormat::Json => Ok(credentials.to_string()),
+            OutputFormat::Variables(shell) => Ok(exec::variables(credentials)
+                .iter()
+                .map(|(name, value)| shell.render_variable(name, value))
+                .collect::<Result<Vec<String>>>()?
+                .join("\n")),
+        }
+    }
+}
+
+impl Shell {
+    fn render_variable(self, name: &str, value: &str) -> Result<String> {
+        Ok(match self {
+            // Single quotes keep everything literal, so only they need care
+            Shell::Env => format!("export {}='{}'", name, value.replace('\'', r"'\''")),
+            Shell::Fish => format!(
+                "set -gx {} '{}';",
+                name,
+                value.replace('\\', r"\\").replace('\'', r"\'")
+            ),
+            Shell::Powershell => {
+                format!("$Env:{} = '{}'", name, value.replace('\'', "''"))
+            }
+            Shell::Dotenv => format!(
+                "{}=\"{}\"",
+                name,
+                value
+                    .replace('\\', r"\\")
+                    .replace('"', "\\\"")
+                    .replace('\n', r"\n")
+            ),
+            // Quoting the whole assignment keeps cmd from acting on & | < > ^,
+            // but there's no escaping a quote inside of it
+            Shell::Cmd if value.contains('"') => {
+                return Err(anyhow!("{} contains a quote, which cmd can't set", name))
+            }
+            Shell::Cmd => format!("set \"{}={}\"", name, value.replace('%', "%%")),
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn credentials(secret: &str) -> AwsCredentials {
+        AwsCredentials {
+            version: 1,
+            access_key_id: Some("some_key".to_string()),
+            secret_access_key: Some(secret.to_string()),
+            session_token: Some("some_token".to_string()),
+            expiration: Some("2038-01-01T10:10:10Z".to_string()),
+        }
+    }
+
+    #[test]
+    fn renders_all_variables_but_expiration() -> Result<()> {
+        assert_eq!(
+            OutputFormat::Variables(Shell::Env).render(credentials("some_secret"))?,
+            "export AWS_ACCESS_KEY_ID='some_key'\n\
+             export AWS_SECRET_ACCESS_KEY='some_secret'\n\
+             export AWS_SESSION_TOKEN='some_token'"
+        );
+        assert_eq!(
+            OutputFormat::Json.render(credentials("some_secret"))?,
+            credentials("some_secret").to_string()
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn quotes_values_for_each_shell() {
+        let secret = r#"it's a "se\cret" & more"#;
+        let rendered = |shell: Shell| {
+            OutputFormat::Variables(shell)
+                .render(credentials(secret))
+                .unwrap()
+                .lines()
+                .nth(1)
+                .unwrap()
+                .to_string()
+        };
+
+        assert_eq!(
+            rendered(Shell::Env),
+            r#"export AWS_SECRET_ACCESS_KEY='it'\''s a "se\cret" & more'"#
+        );
+        assert_eq!(
+            rendered(Shell::Fish),
+            r#"set -gx AWS_SECRET_ACCESS_KEY 'it\'s a "se\\cret" & more';"#
+        );
+        assert_eq!(
+            rendered(Shell::Powershell),
+            r#"$Env:AWS_SECRET_ACCESS_KEY = 'it''s a "se\cret" & more'"#
+        );
+        assert_eq!(
+            rendered(Shell::Dotenv),
+            r#"AWS_SECRET_ACCESS_KEY="it's a \"se\\cret\" & more""#
+        );
+        assert!(OutputFormat::Variables(Shell::Cmd)
+            .render(credentials(secret))
+            .is_err());
+        assert_eq!(
+            OutputFormat::Variables(Shell::Cmd)
+                .render(credentials(r"it's 100% se\cret & more"))
+                .unwrap()
+                .lines()
+                .nth(1),
+            Some(r#"set "AWS_SECRET_ACCESS_KEY=it's 100%% se\cret & more""#)
+        );
+    }
+
+    #[test]
+    fn parses_format_names() -> Result<()> {
+        assert_eq!(
+            "env".parse::<OutputFormat>()?,
+            OutputFormat::Variables(Shell::Env)
+        );
+        assert_eq!(
+            "PowerShell".parse::<OutputFormat>()?,
+            OutputFormat::Variables(Shell::Powershell)
+        );
+        assert!("yaml".parse::<OutputFormat>().is_err());
+
+        Ok(())
+    }
+}
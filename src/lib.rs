@@ -1,21 +1,32 @@
-mod aws;
+pub mod aws;
 mod cli;
 pub mod config;
 pub mod credentials;
+mod duo;
 mod exec;
 pub mod exit;
-mod providers;
+pub mod providers;
 mod saml;
+mod serve;
 mod utils;
+pub mod webauthn;
 
-use crate::cli::{CliAction, CliSubAction};
-use crate::config::{aws::AwsConfig, CrowbarConfig};
+use crate::aws::sts;
+use crate::cli::{CliAction, CliSubAction, SamlSubAction};
+use crate::config::aws::{AwsConfig, AwsCredentialsFile};
+use crate::config::CrowbarConfig;
 use crate::credentials::aws as CredentialsProvider;
+use crate::credentials::store;
+use crate::credentials::CredentialType;
 use crate::exec::Executor;
+use crate::serve::Server;
 
-use anyhow::Result;
+use anyhow::{anyhow, Context, Result};
 use env_logger::{Builder, WriteStyle};
 use log::info;
+use std::io::Read;
+use std::sync::Arc;
+use std::{fs, io};
 
 pub fn run() -> Result<()> {
     let cli = cli::config()?;
@@ -32,6 +43,19 @@ pub fn run() -> Result<()> {
     let cli_action = cli.action;
     let location = cli.location;
     let crowbar_config = CrowbarConfig::with_location(location).read()?;
+    let secret_store = match cli.no_cache {
+        true => Arc::new(store::NullStore),
+        false => crowbar_config.store.open()?,
+    };
+    // Fake credentials and the assertions they came from stay out of the
+    // store, so they can't be mistaken for real ones later on
+    store::use_store(match sts::fake_requested() {
+        true => Arc::new(store::ScratchStore::new(
+            secret_store,
+            &[CredentialType::Aws, CredentialType::Saml],
+        )),
+        false => secret_store,
+    });
     let aws_config = AwsConfig::new()?;
     let executor = Executor::default();
 
@@ -44,17 +68,35 @@ pub fn run() -> Result<()> {
                     println!("Profile {} added successfully!", profile.name)
                 }
                 CliSubAction::Delete { profile_name } => {
+                    if let Some(profile) = crowbar_config
+                        .profiles
+                        .iter()
+                        .find(|p| p.is_profile(&profile_name))
+                    {
+                        CredentialsProvider::delete_credentials(profile, &crowbar_config.profiles)?;
+                    }
                     crowbar_config.delete_profile(&profile_name)?.write()?;
                     aws_config.delete_profile(&profile_name)?.write()?;
+                    let credentials_file = AwsCredentialsFile::new()?;
+                    if !credentials_file.written_for(&profile_name).is_empty() {
+                        credentials_file.delete_profile(&profile_name)?.write()?;
+                    }
                     println!("Profile {} deleted successfully", profile_name)
                 }
-                CliSubAction::List {} => crowbar_config.list_profiles()?,
+                CliSubAction::List => crowbar_config.list_profiles()?,
             }
             Ok(())
         }
-        CliAction::Exec { command, profile } => {
+        CliAction::Exec {
+            command,
+            profile,
+            role,
+            duration,
+        } => {
             let credentials = CredentialsProvider::fetch_aws_credentials(
                 profile,
+                role,
+                duration,
                 crowbar_config,
                 force_new_credentials,
             )?;
@@ -64,20 +106,109 @@ pub fn run() -> Result<()> {
 
             Ok(())
         }
-        CliAction::Creds { profile, print } => {
+        CliAction::Creds {
+            profile,
+            role,
+            duration,
+            print,
+            write,
+            format,
+        } => {
+            // The SDKs prefer static keys over credential_process, so expired
+            // ones would break a profile crowbar provides
+            if let Some(target) = write.as_ref().filter(|t| aws_config.runs_crowbar(t)) {
+                return Err(anyhow!(
+                    "Profile {} gets its credentials from crowbar through credential_process, please write them to another profile",
+                    target
+                ));
+            }
+
             let aws_credentials = CredentialsProvider::fetch_aws_credentials(
-                profile,
+                profile.clone(),
+                role,
+                duration,
                 crowbar_config,
                 force_new_credentials,
             )?;
 
-            if print {
-                println!("{}", aws_credentials);
-            } else {
+            if let Some(target) = &write {
+                let credentials_file = AwsCredentialsFile::new()?
+                    .set_credentials(target, &profile, &aws_credentials)?
+                    .write()?;
+                info!(
+                    "Credentials for {} written to profile {} in {}",
+                    profile,
+                    target,
+                    credentials_file.location.display()
+                )
+            }
+
+            if print || format.is_some() {
+                println!("{}", format.unwrap_or_default().render(aws_credentials)?);
+            } else if write.is_none() {
                 info!("Please run with the -p switch to print the credentials to stdout")
             }
 
             Ok(())
         }
+        CliAction::Serve {
+            profile,
+            role,
+            duration,
+            port,
+        } => {
+            let refresh_window = CredentialsProvider::seconds_to_expiration(
+                CredentialsProvider::find_profile(
+                    &profile,
+                    role.clone(),
+                    duration,
+                    &crowbar_config,
+                )?
+                .session_duration(),
+            );
+            let mut force = force_new_credentials;
+            let server = Server::bind(
+                port,
+                refresh_window,
+                Box::new(move || {
+                    let credentials = CredentialsProvider::fetch_aws_credentials(
+                        profile.clone(),
+                        role.clone(),
+                        duration,
+                        crowbar_config.clone(),
+                        force,
+                    );
+                    // Forcing applies to the first login only
+                    force = false;
+                    credentials
+                }),
+            )?;
+
+            println!(
+                "{}=http://{}/",
+                serve::FULL_URI_VARIABLE,
+                server.local_addr()?
+            );
+            println!("{}={}", serve::AUTHORIZATION_TOKEN_VARIABLE, server.token());
+
+            server.run()
+        }
+        CliAction::Saml { action } => match action {
+            SamlSubAction::Inspect { file } => {
+                let input = match file {
+                    Some(file) => fs::read_to_string(&file)
+                        .with_context(|| format!("Unable to read assertion from {}", file))?,
+                    None => {
+                        let mut input = String::new();
+                        io::stdin().read_to_string(&mut input)?;
+                        input
+                    }
+                };
+
+                println!("{}", saml::decode_assertion(&input)?);
+
+                Ok(())
+            }
+        },
     }
 }
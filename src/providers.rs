@@ -1,12 +1,32 @@
 pub mod adfs;
+pub mod azure;
+mod form;
+pub mod generic;
+pub mod google;
 pub mod jumpcloud;
+pub mod keycloak;
 pub mod okta;
 
+use crate::aws::role as RoleManager;
+use crate::aws::sts::{self, StsClient};
+use crate::config::app::AppProfile;
+use crate::credentials::aws::AwsCredentials;
+use crate::providers::adfs::AdfsProvider;
+use crate::providers::azure::AzureProvider;
+use crate::providers::generic::GenericProvider;
+use crate::providers::google::GoogleProvider;
+use crate::providers::jumpcloud::JumpcloudProvider;
+use crate::providers::keycloak::KeycloakProvider;
+use crate::providers::okta::OktaProvider;
+use crate::saml;
+
 use anyhow::{anyhow, Result};
+use log::trace;
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 use std::str::FromStr;
 
-#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, Hash)]
 pub enum ProviderType {
     #[serde(alias = "okta", alias = "OKTA")]
     Okta,
@@ -14,6 +34,17 @@ pub enum ProviderType {
     Jumpcloud,
     #[serde(alias = "ADFS", alias = "adfs")]
     Adfs,
+    #[serde(alias = "azure", alias = "AZURE", alias = "entra")]
+    Azure,
+    #[serde(alias = "google", alias = "GOOGLE")]
+    Google,
+    #[serde(alias = "generic", alias = "GENERIC")]
+    Generic,
+    #[serde(alias = "keycloak", alias = "KEYCLOAK")]
+    Keycloak,
+    // Providers registered by library users, named as in the configuration
+    #[serde(untagged)]
+    Custom(String),
 }
 
 impl FromStr for ProviderType {
@@ -25,7 +56,183 @@ impl FromStr for ProviderType {
             "okta" => Ok(ProviderType::Okta),
             "jumpcloud" => Ok(ProviderType::Jumpcloud),
             "adfs" => Ok(ProviderType::Adfs),
+            "azure" | "entra" => Ok(ProviderType::Azure),
+            "google" => Ok(ProviderType::Google),
+            "generic" => Ok(ProviderType::Generic),
+            "keycloak" => Ok(ProviderType::Keycloak),
             _ => Err(anyhow!("Unable to determine provider type")),
         }
     }
 }
+
+pub trait IdentityProvider {
+    fn profile(&self) -> &AppProfile;
+
+    // Logs into the IdP, answering any MFA challenges on the way
+    fn new_session(&mut self) -> Result<()>;
+
+    // Picks up the session saved by an earlier login if the IdP still accepts
+    // it, returning false when a new session is needed
+    fn resume_session(&mut self) -> Result<bool> {
+        Ok(false)
+    }
+
+    // Returns the page or document carrying the SAML response for the AWS app
+    fn fetch_saml_assertion(&self) -> Result<String>;
+
+    fn fetch_aws_credentials(&self, sts: &dyn StsClient) -> Result<AwsCredentials> {
+        let input = self.fetch_saml_assertion()?;
+        let profile = self.profile();
+        let credentials = saml::get_credentials_from_saml(input, profile, sts)?;
+        let credentials = RoleManager::assume_role_chain(
+            credentials,
+            &profile.role_chain,
+            profile.duration,
+            sts,
+        )?;
+
+        trace!("Credentials: {:?}", credentials);
+        Ok(credentials)
+    }
+}
+
+pub type ProviderFactory = Box<dyn Fn(&AppProfile) -> Result<Box<dyn IdentityProvider>>>;
+pub type StsFactory = Box<dyn Fn(&AppProfile) -> Box<dyn StsClient>>;
+
+pub struct ProviderRegistry {
+    factories: HashMap<ProviderType, ProviderFactory>,
+    sts: StsFactory,
+}
+
+impl Default for ProviderRegistry {
+    fn default() -> Self {
+        let mut registry = ProviderRegistry::new();
+        registry
+            .register(ProviderType::Okta, |p| Ok(Box::new(OktaProvider::new(p)?)))
+            .register(ProviderType::Jumpcloud, |p| {
+                Ok(Box::new(JumpcloudProvider::new(p)?))
+            })
+            .register(ProviderType::Adfs, |p| Ok(Box::new(AdfsProvider::new(p)?)))
+            .register(ProviderType::Azure, |p| {
+                Ok(Box::new(AzureProvider::new(p)?))
+            })
+            .register(ProviderType::Google, |p| {
+                Ok(Box::new(GoogleProvider::new(p)?))
+            })
+            .register(ProviderType::Generic, |p| {
+                Ok(Box::new(GenericProvider::new(p)?))
+            })
+            .register(ProviderType::Keycloak, |p| {
+                Ok(Box::new(KeycloakProvider::new(p)?))
+            });
+
+        registry
+    }
+}
+
+impl ProviderRegistry {
+    pub fn new() -> Self {
+        ProviderRegistry {
+            factories: HashMap::new(),
+            sts: Box::new(sts::default_client),
+        }
+    }
+
+    // Registering a provider type again replaces the previous factory
+    pub fn register<F>(&mut self, provider_type: ProviderType, factory: F) -> &mut Self
+    where
+        F: Fn(&AppProfile) -> Result<Box<dyn IdentityProvider>> + 'static,
+    {
+        let _ = self.factories.insert(provider_type, Box::new(factory));
+        self
+    }
+
+    // Replaces the STS client the credentials of all providers come from
+    pub fn register_sts<F>(&mut self, factory: F) -> &mut Self
+    where
+        F: Fn(&AppProfile) -> Box<dyn StsClient> + 'static,
+    {
+        self.sts = Box::new(factory);
+        self
+    }
+
+    pub fn sts(&self, profile: &AppProfile) -> Box<dyn StsClient> {
+        (self.sts)(profile)
+    }
+
+    pub fn create(&self, profile: &AppProfile) -> Result<Box<dyn IdentityProvider>> {
+        match self.factories.get(&profile.provider) {
+            Some(factory) => factory(profile),
+            None => Err(anyhow!(
+                "No identity provider registered for {:?}",
+                profile.provider
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    struct StaticProvider {
+        profile: AppProfile,
+    }
+
+    impl IdentityProvider for StaticProvider {
+        fn profile(&self) -> &AppProfile {
+            &self.profile
+        }
+
+        fn new_session(&mut self) -> Result<()> {
+            Ok(())
+        }
+
+        fn fetch_saml_assertion(&self) -> Result<String> {
+            Ok("assertion".to_string())
+        }
+    }
+
+    fn profile(provider: &str) -> Result<AppProfile> {
+        Ok(toml::from_str(&format!(
+            r#"
+            name = "profile"
+            provider = "{}"
+            url = "https://idp.example.com/app/saml"
+            username = "jdoe"
+        "#,
+            provider
+        ))?)
+    }
+
+    #[test]
+    fn parses_custom_provider_type() -> Result<()> {
+        assert_eq!(profile("okta")?.provider, ProviderType::Okta);
+        assert_eq!(
+            profile("internal-idp")?.provider,
+            ProviderType::Custom("internal-idp".to_string())
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn creates_registered_custom_provider() -> Result<()> {
+        let profile = profile("internal-idp")?;
+        let mut registry = ProviderRegistry::default();
+
+        assert!(registry.create(&profile).is_err());
+
+        registry.register(ProviderType::Custom("internal-idp".to_string()), |p| {
+            Ok(Box::new(StaticProvider { profile: p.clone() }))
+        });
+
+        let mut provider = registry.create(&profile)?;
+        provider.new_session()?;
+
+        assert_eq!(provider.fetch_saml_assertion()?, "assertion");
+        assert_eq!(provider.profile().name, "profile");
+
+        Ok(())
+    }
+}
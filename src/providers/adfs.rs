@@ -1,29 +1,36 @@
 use crate::config::app::AppProfile;
-use crate::credentials::aws::AwsCredentials;
 use crate::credentials::config::ConfigCredentials;
 use crate::credentials::Credential;
-use crate::providers::adfs::client::Client;
+use crate::providers::form::client::Client;
+use crate::providers::form::{
+    build_form_elements, build_matching_form_elements, fetch_submit_url, MAX_FORM_STEPS,
+    PASSWORD_FIELD_PATTERN, USERNAME_FIELD_PATTERN,
+};
+use crate::providers::IdentityProvider;
 use crate::saml;
+use crate::utils;
 
 use anyhow::{anyhow, Context, Result};
+use chrono::Utc;
+use log::debug;
 use regex::Regex;
+use reqwest::blocking::Response;
+use reqwest::Url;
 use select::document::Document;
 use select::predicate::{Attr, Name};
 use std::collections::HashMap;
-
-mod client;
+use std::{thread, time::Duration};
 
 const ADFS_URL_SUFFIX: &str = "/adfs/ls/IdpInitiatedSignOn.aspx?loginToRp=urn:amazon:webservices";
+const MFA_CODE_FIELD: &str = "VerificationCode";
+const MFA_POLL_INTERVAL: Duration = Duration::from_secs(2);
+pub const MFA_WAIT_TIMEOUT: i64 = 60;
 
 pub struct AdfsProvider {
     client: Client,
     profile: AppProfile,
-}
-
-#[derive(PartialEq, Debug)]
-struct AdfsResponse {
-    state: ResponseState,
-    credentials: Option<AwsCredentials>,
+    saml_response: Option<String>,
+    mfa_poll_interval: Duration,
 }
 
 #[derive(PartialEq, Debug)]
@@ -34,31 +41,30 @@ enum ResponseState {
     Error,
 }
 
-impl Default for AdfsResponse {
-    fn default() -> Self {
-        AdfsResponse {
-            state: ResponseState::Error,
-            credentials: None,
-        }
-    }
-}
-
 impl AdfsProvider {
     pub fn new(profile: &AppProfile) -> Result<Self> {
         Ok(AdfsProvider {
             client: Client::new()?,
             profile: profile.clone(),
+            saml_response: None,
+            mfa_poll_interval: MFA_POLL_INTERVAL,
         })
     }
+}
+
+impl IdentityProvider for AdfsProvider {
+    fn profile(&self) -> &AppProfile {
+        &self.profile
+    }
 
-    pub fn fetch_aws_credentials(&mut self) -> Result<AwsCredentials> {
+    fn new_session(&mut self) -> Result<()> {
         let profile = &self.profile;
 
         let config_credentials =
             ConfigCredentials::load(profile).or_else(|_| ConfigCredentials::create(profile))?;
 
         let username = self.profile.username.clone();
-        let password = config_credentials.password;
+        let password = config_credentials.password.clone();
         let mut url = self.profile.url.clone();
         url.push_str(ADFS_URL_SUFFIX);
 
@@ -67,21 +73,94 @@ impl AdfsProvider {
             .get(&url)
             .with_context(|| "Unable to reach login form")?;
 
+        let page_url = response.url().clone();
         let document = Document::from(response.text()?.as_str());
         let form_content = build_login_form_elements(&username, &password, &document);
-        let submit_url = fetch_submit_url(&document);
+        let submit_url = page_url.join(fetch_submit_url(&document)?)?;
 
         let response = self.client.post(submit_url, &form_content)?;
-        let adfs_response = evaluate_response_state(response.text()?, profile.role.clone())?;
+        let saml_response = self.complete_login(response)?;
+
+        config_credentials.write(profile)?;
+
+        self.saml_response = Some(saml_response);
+        Ok(())
+    }
+
+    fn fetch_saml_assertion(&self) -> Result<String> {
+        self.saml_response
+            .clone()
+            .with_context(|| "Missing SAML response, no session established")
+    }
+}
+
+impl AdfsProvider {
+    // Walks through the forms ADFS presents after the initial login until
+    // it hands out the SAML response
+    fn complete_login(&self, response: Response) -> Result<String> {
+        let (mut page_url, mut page) = (response.url().clone(), response.text()?);
+
+        for _ in 0..MAX_FORM_STEPS {
+            let document = Document::from(page.as_str());
+
+            let form_content = match evaluate_response_state(&page) {
+                ResponseState::Success => return Ok(page),
+                ResponseState::MfaPrompt => {
+                    build_mfa_form_elements(&utils::prompt_mfa()?, &document)
+                }
+                // Waiting doesn't count as a step, it's bounded by its timeout
+                ResponseState::MfaWait => {
+                    (page_url, page) = self.wait_for_mfa(page_url, page)?;
+                    continue;
+                }
+                ResponseState::Error => {
+                    return Err(match fetch_error_text(&document) {
+                        Some(error) => anyhow!("Unable to acquire credentials: {}", error),
+                        None => anyhow!("Unable to acquire credentials"),
+                    })
+                }
+            };
+
+            let submit_url = page_url.join(fetch_submit_url(&document)?)?;
+            debug!("Submitting ADFS form to {}", submit_url);
+
+            let response = self.client.post(submit_url, &form_content)?;
+            (page_url, page) = (response.url().clone(), response.text()?);
+        }
+
+        Err(anyhow!(
+            "ADFS login did not complete after {} steps",
+            MAX_FORM_STEPS
+        ))
+    }
+
+    // Resubmits the waiting page until the user confirmed the login on their
+    // device, returning the page that follows
+    fn wait_for_mfa(&self, mut page_url: Url, mut page: String) -> Result<(Url, String)> {
+        eprintln!("Waiting for confirmation on your device");
+        let started = Utc::now();
+
+        while evaluate_response_state(&page) == ResponseState::MfaWait {
+            if Utc::now().signed_duration_since(started).num_seconds() > MFA_WAIT_TIMEOUT {
+                return Err(anyhow!(
+                    "No verification after {} seconds",
+                    MFA_WAIT_TIMEOUT
+                ));
+            }
+            thread::sleep(self.mfa_poll_interval);
 
-        let credentials = match adfs_response.state {
-            ResponseState::Success => adfs_response.credentials.unwrap(),
-            ResponseState::MfaPrompt => AwsCredentials::default(),
-            ResponseState::MfaWait => AwsCredentials::default(),
-            _ => return Err(anyhow!("Unable to acquire credentials")),
-        };
+            let document = Document::from(page.as_str());
+            let submit_url = page_url.join(fetch_submit_url(&document)?)?;
+            debug!("Polling ADFS for MFA confirmation at {}", submit_url);
 
-        Ok(credentials)
+            let response = self.client.post(
+                submit_url,
+                &build_form_elements(document.find(Name("input"))),
+            )?;
+            (page_url, page) = (response.url().clone(), response.text()?);
+        }
+
+        Ok((page_url, page))
     }
 }
 
@@ -90,77 +169,49 @@ fn build_login_form_elements<'a>(
     password: &'a str,
     document: &'a Document,
 ) -> HashMap<String, String> {
-    let ur = Regex::new(r"(^email.*|^[Uu]ser.*)").unwrap();
-    let pr = Regex::new(r"(^[Pp]ass.*)").unwrap();
-    let mut form_content: HashMap<String, String> = HashMap::new();
-    let elements = document.find(Name("input"));
-
-    for element in elements {
-        match element.attr("name") {
-            Some(name) if ur.is_match(name) => {
-                let _ = form_content.insert(name.to_owned(), username.to_owned());
-            }
-            Some(name) if pr.is_match(name) => {
-                let _ = form_content.insert(name.to_owned(), password.to_owned());
-            }
-            _ => {
-                let name = element.attr("name");
-                let value = element.attr("value");
-                if let Some(n) = name {
-                    if let Some(v) = value {
-                        let _ = form_content.insert(n.to_owned(), v.to_owned());
-                    }
-                }
-            }
-        };
-    }
+    let ur = Regex::new(USERNAME_FIELD_PATTERN).unwrap();
+    let pr = Regex::new(PASSWORD_FIELD_PATTERN).unwrap();
 
-    form_content
+    build_matching_form_elements(username, password, &ur, &pr, document)
 }
 
-fn fetch_submit_url(document: &Document) -> &str {
-    let forms = document.find(Name("form"));
-    let mut url = None;
+fn build_mfa_form_elements(code: &str, document: &Document) -> HashMap<String, String> {
+    let mut form_content = build_form_elements(document.find(Name("input")));
+    let _ = form_content.insert(MFA_CODE_FIELD.to_owned(), code.to_owned());
 
-    for form in forms {
-        url = form.attr("action")
-    }
+    form_content
+}
 
-    url.expect("Missing submission URL for authentication form")
+fn fetch_error_text(document: &Document) -> Option<String> {
+    document
+        .find(Attr("id", "errorText"))
+        .next()
+        .map(|node| node.text().trim().to_owned())
+        .filter(|text| !text.is_empty())
 }
 
-fn evaluate_response_state(response: String, role: Option<String>) -> Result<AdfsResponse> {
-    let mut adfs_response = AdfsResponse::default();
+fn evaluate_response_state(response: &str) -> ResponseState {
+    if saml::extract_saml_assertion(response).is_ok() {
+        return ResponseState::Success;
+    }
 
-    match saml::get_credentials_from_saml(response.clone(), role) {
-        Ok(credentials) => {
-            adfs_response.credentials = Some(credentials);
-            adfs_response.state = ResponseState::Success;
-        }
-        Err(_) => {
-            let document = Document::from(response.as_str());
-
-            if let Some(node) = document.find(Attr("name", "AuthMethod")).next() {
-                match node.attr("value") {
-                    Some("VIPAuthenticationProviderWindowsAccountName") => {
-                        adfs_response.state = ResponseState::MfaPrompt
-                    }
-                    Some("AzureMfaAuthentication") | Some("AzureMfaServerAuthentication") => {
-                        adfs_response.state = ResponseState::MfaWait
-                    }
-                    _ => (),
-                }
-            } else if document
-                .find(Attr("name", "VerificationCode"))
-                .next()
-                .is_some()
-            {
-                adfs_response.state = ResponseState::MfaPrompt
+    let document = Document::from(response);
+
+    if let Some(node) = document.find(Attr("name", "AuthMethod")).next() {
+        match node.attr("value") {
+            Some("VIPAuthenticationProviderWindowsAccountName") => return ResponseState::MfaPrompt,
+            Some("AzureMfaAuthentication") | Some("AzureMfaServerAuthentication") => {
+                return ResponseState::MfaWait
             }
+            _ => (),
         }
     }
 
-    Ok(adfs_response)
+    if document.find(Attr("name", MFA_CODE_FIELD)).next().is_some() {
+        ResponseState::MfaPrompt
+    } else {
+        ResponseState::Error
+    }
 }
 
 #[cfg(test)]
@@ -211,7 +262,7 @@ mod test {
         let body = fs::read_to_string("tests/fixtures/adfs/initial_login_form.html")?;
         let body = Document::from(body.as_str());
 
-        let submit_url = fetch_submit_url(&body);
+        let submit_url = fetch_submit_url(&body)?;
         assert_eq!("https://adfs.example.com:443/adfs/ls/IdpInitiatedSignOn.aspx?loginToRp=urn:amazon:webservices".to_owned(), submit_url);
 
         Ok(())
@@ -224,40 +275,140 @@ mod test {
         "#
         .to_string();
 
-        let adfs_response = evaluate_response_state(response, None)?;
-        assert_eq!(adfs_response.state, ResponseState::MfaPrompt);
+        assert_eq!(evaluate_response_state(&response), ResponseState::MfaPrompt);
 
         let response = r#"
             <input name="AuthMethod" value="AzureMfaAuthentication" />
         "#
         .to_string();
 
-        let adfs_response = evaluate_response_state(response, None)?;
-        assert_eq!(adfs_response.state, ResponseState::MfaWait);
+        assert_eq!(evaluate_response_state(&response), ResponseState::MfaWait);
 
         let response = r#"
             <input name="AuthMethod" value="AzureMfaServerAuthentication" />
         "#
         .to_string();
 
-        let adfs_response = evaluate_response_state(response, None)?;
-        assert_eq!(adfs_response.state, ResponseState::MfaWait);
+        assert_eq!(evaluate_response_state(&response), ResponseState::MfaWait);
 
         let response = r#"
             <input name="VerificationCode" value="" />
         "#
         .to_string();
 
-        let adfs_response = evaluate_response_state(response, None)?;
-        assert_eq!(adfs_response.state, ResponseState::MfaPrompt);
+        assert_eq!(evaluate_response_state(&response), ResponseState::MfaPrompt);
 
         let response = r#"
             <input name="SomeOtherInput" value="Value" />
         "#
         .to_string();
 
-        let adfs_response = evaluate_response_state(response, None)?;
-        assert_eq!(adfs_response.state, ResponseState::Error);
+        assert_eq!(evaluate_response_state(&response), ResponseState::Error);
+
+        Ok(())
+    }
+
+    #[test]
+    fn evaluates_state_of_fixture_pages() -> Result<()> {
+        let states = vec![
+            ("tests/fixtures/adfs/login_error.html", ResponseState::Error),
+            (
+                "tests/fixtures/adfs/mfa_prompt.html",
+                ResponseState::MfaPrompt,
+            ),
+            (
+                "tests/fixtures/adfs/azure_mfa_wait.html",
+                ResponseState::MfaWait,
+            ),
+            (
+                "tests/fixtures/adfs/saml_response.html",
+                ResponseState::Success,
+            ),
+        ];
+
+        for (path, state) in states {
+            assert_eq!(evaluate_response_state(&fs::read_to_string(path)?), state);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn builds_mfa_form_with_context() -> Result<()> {
+        let body = fs::read_to_string("tests/fixtures/adfs/mfa_prompt.html")?;
+        let body = Document::from(body.as_str());
+
+        let form_content = build_mfa_form_elements("123456", &body);
+
+        assert_eq!(*form_content.get("VerificationCode").unwrap(), "123456");
+        assert_eq!(*form_content.get("Context").unwrap(), "context-token");
+        assert_eq!(
+            *form_content.get("AuthMethod").unwrap(),
+            "VIPAuthenticationProviderWindowsAccountName"
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn reports_login_error_text() -> Result<()> {
+        let body = fs::read_to_string("tests/fixtures/adfs/login_error.html")?;
+        let body = Document::from(body.as_str());
+
+        assert_eq!(
+            fetch_error_text(&body).unwrap(),
+            "Incorrect user ID or password. Type the correct user ID and password, and try again."
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn polls_azure_mfa_until_saml_response() -> Result<()> {
+        let mut server = mockito::Server::new();
+        let path = "/adfs/ls/IdpInitiatedSignOn.aspx";
+
+        let wait = server
+            .mock("POST", path)
+            .match_query(mockito::Matcher::Any)
+            .with_body_from_file("tests/fixtures/adfs/azure_mfa_wait.html")
+            // More polls than there are form steps
+            .expect(MAX_FORM_STEPS + 2)
+            .create();
+        let success = server
+            .mock("POST", path)
+            .match_query(mockito::Matcher::Any)
+            .match_body(mockito::Matcher::AllOf(vec![
+                mockito::Matcher::UrlEncoded(
+                    "AuthMethod".to_string(),
+                    "AzureMfaAuthentication".to_string(),
+                ),
+                mockito::Matcher::UrlEncoded("Context".to_string(), "context-token".to_string()),
+            ]))
+            .with_body_from_file("tests/fixtures/adfs/saml_response.html")
+            .create();
+
+        let profile: AppProfile = toml::from_str(&format!(
+            r#"
+            name = "profile"
+            provider = "adfs"
+            url = "{}"
+            username = "jdoe"
+        "#,
+            server.url()
+        ))?;
+        let mut provider = AdfsProvider::new(&profile)?;
+        provider.mfa_poll_interval = Duration::ZERO;
+
+        let response = provider.client.post(
+            format!("{}{}", server.url(), ADFS_URL_SUFFIX),
+            &HashMap::<String, String>::new(),
+        )?;
+        let page = provider.complete_login(response)?;
+
+        assert!(saml::extract_saml_assertion(&page).is_ok());
+        wait.assert();
+        success.assert();
 
         Ok(())
     }
@@ -0,0 +1,451 @@
+mod response;
+
+use crate::config::app::AppProfile;
+use crate::credentials::config::ConfigCredentials;
+use crate::credentials::Credential;
+use crate::providers::azure::response::{AuthRequest, AuthResponse, PageConfig, UserProof};
+use crate::providers::form::{self, client::Client};
+use crate::providers::IdentityProvider;
+use crate::utils;
+
+use anyhow::{anyhow, Context, Result};
+use chrono::Utc;
+use log::{debug, info, trace};
+use reqwest::blocking::Response;
+use reqwest::Url;
+use std::collections::HashMap;
+use std::{thread, time::Duration};
+
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+pub const PUSH_WAIT_TIMEOUT: i64 = 60;
+
+const SIGN_IN_PAGE: &str = "ConvergedSignIn";
+const KMSI_PAGE: &str = "KmsiInterrupt";
+const PUSH_METHODS: [&str; 2] = ["PhoneAppNotification", "TwoWayVoiceMobile"];
+
+#[derive(PartialEq, Debug)]
+enum ResponseState {
+    Credentials,
+    Mfa,
+    Kmsi,
+    Error(String),
+}
+
+pub struct AzureProvider {
+    client: Client,
+    profile: AppProfile,
+    saml_response: Option<String>,
+}
+
+impl AzureProvider {
+    pub fn new(profile: &AppProfile) -> Result<Self> {
+        Ok(AzureProvider {
+            client: Client::new()?,
+            profile: profile.clone(),
+            saml_response: None,
+        })
+    }
+}
+
+impl IdentityProvider for AzureProvider {
+    fn profile(&self) -> &AppProfile {
+        &self.profile
+    }
+
+    fn new_session(&mut self) -> Result<()> {
+        let profile = &self.profile;
+
+        let config_credentials =
+            ConfigCredentials::load(profile).or_else(|_| ConfigCredentials::create(profile))?;
+
+        let response = self
+            .client
+            .get(profile.request_url()?)
+            .with_context(|| "Unable to reach Azure AD login page")?;
+
+        let saml_response =
+            self.complete_login(response, &profile.username, &config_credentials.password)?;
+
+        config_credentials.write(profile)?;
+
+        self.saml_response = Some(saml_response);
+        Ok(())
+    }
+
+    fn fetch_saml_assertion(&self) -> Result<String> {
+        self.saml_response
+            .clone()
+            .with_context(|| "Missing SAML response, no session established")
+    }
+}
+
+impl AzureProvider {
+    // Submits the username/password, MFA and "stay signed in" forms until
+    // Azure AD posts the SAML response
+    fn complete_login(&self, response: Response, username: &str, password: &str) -> Result<String> {
+        form::complete_login(&self.client, response, "Azure AD", |page_url, page| {
+            let config = PageConfig::from_page(page)?;
+            debug!("Azure AD page: {:?}", config.page_id);
+
+            let form_content = match evaluate_response_state(&config) {
+                ResponseState::Credentials => credentials_form(&config, username, password),
+                ResponseState::Mfa => self.verify_mfa(page_url, &config, username)?,
+                ResponseState::Kmsi => kmsi_form(&config),
+                ResponseState::Error(e) => return Err(anyhow!("Unable to login: {}", e)),
+            };
+
+            let submit_url = page_url.join(
+                config
+                    .url_post
+                    .as_deref()
+                    .with_context(|| "Missing submission URL on Azure AD page")?,
+            )?;
+
+            Ok((submit_url, form_content))
+        })
+    }
+
+    fn verify_mfa(
+        &self,
+        page_url: &Url,
+        config: &PageConfig,
+        username: &str,
+    ) -> Result<HashMap<String, String>> {
+        let proof = select_proof(&config.user_proofs)?;
+        let begin_url = page_url.join(
+            config
+                .url_begin_auth
+                .as_deref()
+                .with_context(|| "Missing MFA URL on Azure AD page")?,
+        )?;
+        let end_url = page_url.join(
+            config
+                .url_end_auth
+                .as_deref()
+                .with_context(|| "Missing MFA URL on Azure AD page")?,
+        )?;
+
+        let mut auth: AuthResponse = self.client.post_json(
+            begin_url,
+            &AuthRequest {
+                method: "BeginAuth".to_string(),
+                auth_method_id: proof.auth_method_id.clone(),
+                ctx: config.ctx.clone().unwrap_or_default(),
+                flow_token: config.flow_token.clone().unwrap_or_default(),
+                session_id: None,
+                additional_auth_data: None,
+                poll_count: None,
+            },
+        )?;
+
+        if !auth.success {
+            return Err(anyhow!(
+                "Unable to start MFA verification: {}",
+                auth.message.unwrap_or_default()
+            ));
+        }
+
+        let code = if PUSH_METHODS.contains(&proof.auth_method_id.as_str()) {
+            match auth.entropy {
+                Some(answer) => eprintln!("The correct answer is: {}", answer),
+                None => eprintln!("Waiting for confirmation"),
+            }
+            None
+        } else {
+            Some(utils::prompt_mfa()?)
+        };
+
+        let time_at_execution = Utc::now();
+        let mut poll_count = 0;
+
+        loop {
+            poll_count += 1;
+            auth = self.client.post_json(
+                end_url.clone(),
+                &AuthRequest {
+                    method: "EndAuth".to_string(),
+                    auth_method_id: proof.auth_method_id.clone(),
+                    ctx: auth.ctx.clone(),
+                    flow_token: auth.flow_token.clone(),
+                    session_id: auth.session_id.clone(),
+                    additional_auth_data: code.clone(),
+                    poll_count: Some(poll_count),
+                },
+            )?;
+
+            trace!("MFA response: {:?}", auth);
+
+            if auth.success {
+                break;
+            }
+
+            if !auth.is_pending() {
+                return Err(anyhow!(
+                    "MFA verification failed: {}",
+                    auth.result_value.clone().unwrap_or_default()
+                ));
+            }
+
+            if Utc::now()
+                .signed_duration_since(time_at_execution)
+                .num_seconds()
+                > PUSH_WAIT_TIMEOUT
+            {
+                return Err(anyhow!(
+                    "No verification after {} seconds",
+                    PUSH_WAIT_TIMEOUT
+                ));
+            }
+
+            thread::sleep(POLL_INTERVAL);
+        }
+
+        let mut form_content = form(&[
+            ("type", Some("22".to_string())),
+            ("request", Some(auth.ctx)),
+            ("mfaAuthMethod", Some(proof.auth_method_id)),
+            ("login", Some(username.to_string())),
+            ("flowToken", Some(auth.flow_token)),
+            ("canary", config.canary.clone()),
+            ("hpgrequestid", config.hpgrequestid.clone()),
+        ]);
+        if let Some(code) = code {
+            let _ = form_content.insert("otc".to_string(), code);
+        }
+
+        Ok(form_content)
+    }
+}
+
+fn evaluate_response_state(config: &PageConfig) -> ResponseState {
+    if let Some(error) = config.error() {
+        return ResponseState::Error(error);
+    }
+
+    if !config.user_proofs.is_empty() {
+        return ResponseState::Mfa;
+    }
+
+    match config.page_id.as_deref() {
+        Some(SIGN_IN_PAGE) => ResponseState::Credentials,
+        Some(KMSI_PAGE) => ResponseState::Kmsi,
+        page => ResponseState::Error(format!(
+            "Unsupported Azure AD page {}",
+            page.unwrap_or("without ID")
+        )),
+    }
+}
+
+fn select_proof(proofs: &[UserProof]) -> Result<UserProof> {
+    let proof = match proofs.len() {
+        0 => return Err(anyhow!("MFA required, and no available methods")),
+        1 => {
+            info!("Only one MFA method available, using it");
+            proofs[0].clone()
+        }
+        _ => {
+            let default = proofs.iter().position(|p| p.is_default).unwrap_or(0);
+            let selection = dialoguer::Select::new()
+                .with_prompt("Please select the MFA method to use")
+                .items(proofs)
+                .default(default)
+                .interact()?;
+            proofs[selection].clone()
+        }
+    };
+
+    debug!("MFA method: {:?}", proof);
+
+    Ok(proof)
+}
+
+fn credentials_form(
+    config: &PageConfig,
+    username: &str,
+    password: &str,
+) -> HashMap<String, String> {
+    form(&[
+        ("login", Some(username.to_string())),
+        ("loginfmt", Some(username.to_string())),
+        ("passwd", Some(password.to_string())),
+        ("type", Some("11".to_string())),
+        ("LoginOptions", Some("3".to_string())),
+        ("ctx", config.ctx.clone()),
+        ("flowToken", config.flow_token.clone()),
+        ("canary", config.canary.clone()),
+        ("hpgrequestid", config.hpgrequestid.clone()),
+    ])
+}
+
+fn kmsi_form(config: &PageConfig) -> HashMap<String, String> {
+    form(&[
+        ("type", Some("28".to_string())),
+        ("LoginOptions", Some("1".to_string())),
+        ("ctx", config.ctx.clone()),
+        ("flowToken", config.flow_token.clone()),
+        ("canary", config.canary.clone()),
+        ("hpgrequestid", config.hpgrequestid.clone()),
+    ])
+}
+
+fn form(fields: &[(&str, Option<String>)]) -> HashMap<String, String> {
+    fields
+        .iter()
+        .filter_map(|(name, value)| value.clone().map(|v| (name.to_string(), v)))
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::saml;
+    use mockito::Matcher;
+    use std::fs;
+
+    #[test]
+    fn parses_page_config() -> Result<()> {
+        let config =
+            PageConfig::from_page(&fs::read_to_string("tests/fixtures/azure/login_page.html")?)?;
+
+        assert_eq!(config.page_id.as_deref(), Some(SIGN_IN_PAGE));
+        assert_eq!(config.ctx.as_deref(), Some("ctx-login"));
+        assert_eq!(config.flow_token.as_deref(), Some("flow-token-login"));
+        assert_eq!(config.url_post.as_deref(), Some("/tenant-id/login"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn evaluates_state_of_fixture_pages() -> Result<()> {
+        let states = vec![
+            (
+                "tests/fixtures/azure/login_page.html",
+                ResponseState::Credentials,
+            ),
+            ("tests/fixtures/azure/mfa_page.html", ResponseState::Mfa),
+            ("tests/fixtures/azure/kmsi_page.html", ResponseState::Kmsi),
+            (
+                "tests/fixtures/azure/error_page.html",
+                ResponseState::Error("Your account or password is incorrect.".to_string()),
+            ),
+        ];
+
+        for (path, state) in states {
+            let config = PageConfig::from_page(&fs::read_to_string(path)?)?;
+            assert_eq!(evaluate_response_state(&config), state);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn runs_login_with_number_matching_push() -> Result<()> {
+        let mut server = mockito::Server::new();
+
+        let mocks = vec![
+            server
+                .mock("GET", "/tenant-id/saml2")
+                .with_body_from_file("tests/fixtures/azure/login_page.html")
+                .create(),
+            server
+                .mock("POST", "/tenant-id/login")
+                .match_body(Matcher::AllOf(vec![
+                    Matcher::UrlEncoded("login".to_string(), "jdoe@example.com".to_string()),
+                    Matcher::UrlEncoded("passwd".to_string(), "password".to_string()),
+                    Matcher::UrlEncoded("flowToken".to_string(), "flow-token-login".to_string()),
+                ]))
+                .with_body_from_file("tests/fixtures/azure/mfa_page.html")
+                .create(),
+            server
+                .mock("POST", "/common/SAS/BeginAuth")
+                .match_body(Matcher::PartialJsonString(
+                    r#"{"Method":"BeginAuth","AuthMethodId":"PhoneAppNotification"}"#.to_string(),
+                ))
+                .with_body_from_file("tests/fixtures/azure/begin_auth.json")
+                .create(),
+            server
+                .mock("POST", "/common/SAS/EndAuth")
+                .match_body(Matcher::PartialJsonString(
+                    r#"{"Method":"EndAuth","SessionId":"session-id","PollCount":1}"#.to_string(),
+                ))
+                .with_body_from_file("tests/fixtures/azure/end_auth_pending.json")
+                .create(),
+            server
+                .mock("POST", "/common/SAS/EndAuth")
+                .match_body(Matcher::PartialJsonString(
+                    r#"{"Method":"EndAuth","SessionId":"session-id","PollCount":2}"#.to_string(),
+                ))
+                .with_body_from_file("tests/fixtures/azure/end_auth_success.json")
+                .create(),
+            server
+                .mock("POST", "/common/SAS/ProcessAuth")
+                .match_body(Matcher::AllOf(vec![
+                    Matcher::UrlEncoded("request".to_string(), "ctx-end-auth".to_string()),
+                    Matcher::UrlEncoded("flowToken".to_string(), "flow-token-end-auth".to_string()),
+                ]))
+                .with_body_from_file("tests/fixtures/azure/kmsi_page.html")
+                .create(),
+            server
+                .mock("POST", "/kmsi")
+                .match_body(Matcher::UrlEncoded(
+                    "flowToken".to_string(),
+                    "flow-token-kmsi".to_string(),
+                ))
+                .with_body_from_file("tests/fixtures/azure/saml_response.html")
+                .create(),
+        ];
+
+        let profile: AppProfile = toml::from_str(&format!(
+            r#"
+            name = "profile"
+            provider = "azure"
+            url = "{}/tenant-id/saml2"
+            username = "jdoe@example.com"
+        "#,
+            server.url()
+        ))?;
+        let provider = AzureProvider::new(&profile)?;
+
+        let response = provider.client.get(profile.request_url()?)?;
+        let page = provider.complete_login(response, "jdoe@example.com", "password")?;
+
+        assert!(saml::extract_saml_assertion(&page).is_ok());
+        for mock in mocks {
+            mock.assert();
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn fails_on_login_error() -> Result<()> {
+        let mut server = mockito::Server::new();
+        let _m = server
+            .mock("GET", "/tenant-id/saml2")
+            .with_body_from_file("tests/fixtures/azure/error_page.html")
+            .create();
+
+        let profile: AppProfile = toml::from_str(&format!(
+            r#"
+            name = "profile"
+            provider = "azure"
+            url = "{}/tenant-id/saml2"
+            username = "jdoe@example.com"
+        "#,
+            server.url()
+        ))?;
+        let provider = AzureProvider::new(&profile)?;
+
+        let response = provider.client.get(profile.request_url()?)?;
+        let error = provider
+            .complete_login(response, "jdoe@example.com", "wrong")
+            .unwrap_err();
+
+        assert_eq!(
+            error.to_string(),
+            "Unable to login: Your account or password is incorrect."
+        );
+
+        Ok(())
+    }
+}
@@ -0,0 +1,113 @@
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+const CONFIG_MARKER: &str = "$Config=";
+
+// The state Azure AD embeds as `$Config` into each of its login pages
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct PageConfig {
+    #[serde(rename = "pgid")]
+    pub page_id: Option<String>,
+    #[serde(rename = "urlPost")]
+    pub url_post: Option<String>,
+    #[serde(rename = "urlBeginAuth")]
+    pub url_begin_auth: Option<String>,
+    #[serde(rename = "urlEndAuth")]
+    pub url_end_auth: Option<String>,
+    #[serde(rename = "sCtx")]
+    pub ctx: Option<String>,
+    #[serde(rename = "sFT")]
+    pub flow_token: Option<String>,
+    pub canary: Option<String>,
+    pub hpgrequestid: Option<String>,
+    #[serde(rename = "arrUserProofs", default)]
+    pub user_proofs: Vec<UserProof>,
+    #[serde(rename = "sErrorCode")]
+    pub error_code: Option<String>,
+    #[serde(rename = "strServiceExceptionMessage")]
+    pub error_message: Option<String>,
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct UserProof {
+    pub auth_method_id: String,
+    pub display: Option<String>,
+    #[serde(default)]
+    pub is_default: bool,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "PascalCase")]
+pub struct AuthRequest {
+    pub method: String,
+    pub auth_method_id: String,
+    pub ctx: String,
+    pub flow_token: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub session_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub additional_auth_data: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub poll_count: Option<u32>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(rename_all = "PascalCase")]
+pub struct AuthResponse {
+    pub success: bool,
+    pub result_value: Option<String>,
+    pub message: Option<String>,
+    pub ctx: String,
+    pub flow_token: String,
+    pub session_id: Option<String>,
+    // The number shown on screen for Authenticator number matching
+    pub entropy: Option<u64>,
+}
+
+impl PageConfig {
+    pub fn from_page(page: &str) -> Result<PageConfig> {
+        let start = page
+            .find(CONFIG_MARKER)
+            .ok_or_else(|| anyhow!("Missing login configuration on Azure AD page"))?
+            + CONFIG_MARKER.len();
+        let line = page[start..].lines().next().unwrap_or_default();
+        let json = line.trim().trim_end_matches(';');
+
+        serde_json::from_str(json).map_err(|e| anyhow!("Unable to parse Azure AD page: {}", e))
+    }
+
+    pub fn error(&self) -> Option<String> {
+        match (&self.error_message, &self.error_code) {
+            (Some(message), _) if !message.is_empty() => Some(message.clone()),
+            (_, Some(code)) if !code.is_empty() && code != "0" => {
+                Some(format!("Azure AD error code {}", code))
+            }
+            _ => None,
+        }
+    }
+}
+
+impl AuthResponse {
+    pub fn is_pending(&self) -> bool {
+        self.result_value.as_deref() == Some("AuthenticationPending")
+    }
+}
+
+impl fmt::Display for UserProof {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let method = match self.auth_method_id.as_str() {
+            "PhoneAppNotification" => "Authenticator Push",
+            "PhoneAppOTP" => "Authenticator TOTP",
+            "OneWaySMS" => "SMS",
+            "TwoWayVoiceMobile" => "Voice call",
+            m => m,
+        };
+
+        match &self.display {
+            Some(display) => write!(f, "{} to {}", method, display),
+            None => write!(f, "{}", method),
+        }
+    }
+}
@@ -0,0 +1,148 @@
+pub mod client;
+
+use crate::providers::form::client::Client;
+use crate::saml;
+
+use anyhow::{anyhow, Context, Result};
+use log::debug;
+use regex::Regex;
+use reqwest::blocking::Response;
+use reqwest::Url;
+use select::document::Document;
+use select::node::Node;
+use select::predicate::Name;
+use std::collections::HashMap;
+
+pub const USERNAME_FIELD_PATTERN: &str = r"(^email.*|^[Uu]ser.*)";
+pub const PASSWORD_FIELD_PATTERN: &str = r"(^[Pp]ass.*)";
+pub const MAX_FORM_STEPS: usize = 10;
+
+// Submits the forms of an IdP until it posts the SAML response. For every
+// other page, `fill` returns where to submit which values
+pub fn complete_login<F>(
+    client: &Client,
+    mut response: Response,
+    idp: &str,
+    mut fill: F,
+) -> Result<String>
+where
+    F: FnMut(&Url, &str) -> Result<(Url, HashMap<String, String>)>,
+{
+    for _ in 0..MAX_FORM_STEPS {
+        let page_url = response.url().clone();
+        let page = response.text()?;
+
+        if saml::extract_saml_assertion(&page).is_ok() {
+            return Ok(page);
+        }
+
+        let (submit_url, form_content) = fill(&page_url, &page)?;
+        debug!("Submitting {} form to {}", idp, submit_url);
+
+        response = client.post(submit_url, &form_content)?;
+    }
+
+    Err(anyhow!(
+        "{} login did not complete after {} steps",
+        idp,
+        MAX_FORM_STEPS
+    ))
+}
+
+// Collects the values a browser would submit for the given inputs of a form,
+// e.g. the hidden state IdPs thread through their sign-in pages. Unchecked
+// radio buttons and checkboxes are left out
+pub fn build_form_elements<'a>(inputs: impl Iterator<Item = Node<'a>>) -> HashMap<String, String> {
+    inputs
+        .filter(|input| match input.attr("type") {
+            Some("radio") | Some("checkbox") => input.attr("checked").is_some(),
+            _ => true,
+        })
+        .filter_map(|input| {
+            input.attr("name").map(|name| {
+                (
+                    name.to_owned(),
+                    input.attr("value").unwrap_or_default().to_owned(),
+                )
+            })
+        })
+        .collect()
+}
+
+// Fills the username and password into the inputs whose names match the
+// given patterns and keeps the values of all others
+pub fn build_matching_form_elements(
+    username: &str,
+    password: &str,
+    ur: &Regex,
+    pr: &Regex,
+    document: &Document,
+) -> HashMap<String, String> {
+    let mut form_content: HashMap<String, String> = HashMap::new();
+    let elements = document.find(Name("input"));
+
+    for element in elements {
+        match element.attr("name") {
+            Some(name) if ur.is_match(name) => {
+                let _ = form_content.insert(name.to_owned(), username.to_owned());
+            }
+            Some(name) if pr.is_match(name) => {
+                let _ = form_content.insert(name.to_owned(), password.to_owned());
+            }
+            _ => {
+                let name = element.attr("name");
+                let value = element.attr("value");
+                if let Some(n) = name {
+                    if let Some(v) = value {
+                        let _ = form_content.insert(n.to_owned(), v.to_owned());
+                    }
+                }
+            }
+        };
+    }
+
+    form_content
+}
+
+// The action of the last form on the page
+pub fn fetch_submit_url(document: &Document) -> Result<&str> {
+    document
+        .find(Name("form"))
+        .last()
+        .and_then(|form| form.attr("action"))
+        .with_context(|| "Missing submission URL for authentication form")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use select::document::Document;
+    use select::predicate::Name;
+
+    #[test]
+    fn builds_form_elements_a_browser_would_submit() {
+        let document = Document::from(
+            r#"
+            <form>
+              <input name="state" type="hidden" value="abc" />
+              <input name="username" type="text" />
+              <input name="remember" type="checkbox" value="on" />
+              <input name="method" type="radio" value="otp" checked />
+              <input name="method-other" type="radio" value="push" />
+              <input type="submit" value="Sign in" />
+            </form>
+        "#,
+        );
+
+        let form_content = build_form_elements(document.find(Name("input")));
+
+        assert_eq!(
+            form_content,
+            HashMap::from([
+                ("state".to_string(), "abc".to_string()),
+                ("username".to_string(), String::new()),
+                ("method".to_string(), "otp".to_string()),
+            ])
+        );
+    }
+}
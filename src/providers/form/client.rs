@@ -0,0 +1,70 @@
+use anyhow::Result;
+use reqwest::blocking::Client as HttpClient;
+use reqwest::blocking::Response;
+use reqwest::header::{HeaderValue, ACCEPT};
+use reqwest::IntoUrl;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::time::Duration;
+
+// Keeps the cookies IdPs set along their HTML sign-in forms
+pub struct Client {
+    client: HttpClient,
+}
+
+impl Client {
+    pub fn new() -> Result<Self> {
+        Ok(Client {
+            client: HttpClient::builder().cookie_store(true).build()?,
+        })
+    }
+
+    pub fn get<U: IntoUrl>(&self, url: U) -> Result<Response> {
+        self.client
+            .get(url)
+            .send()?
+            .error_for_status()
+            .map_err(|e| e.into())
+    }
+
+    pub fn post<U: IntoUrl, I>(&self, url: U, form_content: &I) -> Result<Response>
+    where
+        I: Serialize,
+    {
+        self.client
+            .post(url)
+            .form(form_content)
+            .send()?
+            .error_for_status()
+            .map_err(|e| e.into())
+    }
+
+    pub fn post_json<U: IntoUrl, I, O>(&self, url: U, body: &I) -> Result<O>
+    where
+        I: Serialize,
+        O: DeserializeOwned,
+    {
+        self.client
+            .post(url)
+            .json(body)
+            .header(ACCEPT, HeaderValue::from_static("application/json"))
+            .send()?
+            .error_for_status()?
+            .json()
+            .map_err(|e| e.into())
+    }
+
+    // Long-polling requests only return once the user acted on their device
+    pub fn await_json<U: IntoUrl, I>(&self, url: U, body: &I, timeout: Duration) -> Result<Response>
+    where
+        I: Serialize,
+    {
+        self.client
+            .post(url)
+            .json(body)
+            .timeout(timeout)
+            .send()?
+            .error_for_status()
+            .map_err(|e| e.into())
+    }
+}
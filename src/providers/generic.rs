@@ -0,0 +1,471 @@
+use crate::config::app::AppProfile;
+use crate::credentials::config::ConfigCredentials;
+use crate::credentials::Credential;
+use crate::providers::form::client::Client;
+use crate::providers::form::{
+    self, build_form_elements, build_matching_form_elements, fetch_submit_url,
+    PASSWORD_FIELD_PATTERN, USERNAME_FIELD_PATTERN,
+};
+use crate::providers::IdentityProvider;
+use crate::utils;
+
+use anyhow::{anyhow, Context, Result};
+use log::debug;
+use regex::Regex;
+use reqwest::blocking::Response;
+use select::document::Document;
+use select::node::Node;
+use select::predicate::Name;
+use serde::{Deserialize, Serialize};
+use std::str::FromStr;
+
+// Describes how to log into an IdP that uses plain HTML forms, configured
+// as `[profiles.form]` on a profile
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct FormRecipe {
+    // Pattern for the name of the username input
+    #[serde(default = "default_username_field")]
+    pub username_field: String,
+    // Pattern for the name of the password input
+    #[serde(default = "default_password_field")]
+    pub password_field: String,
+    // Selects the form to submit, e.g. `form#kc-form-login`; the last form on
+    // the page is used otherwise
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub submit_selector: Option<String>,
+    // Name of the input that takes the MFA code, if the IdP asks for one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mfa_field: Option<String>,
+}
+
+impl Default for FormRecipe {
+    fn default() -> Self {
+        FormRecipe {
+            username_field: default_username_field(),
+            password_field: default_password_field(),
+            submit_selector: None,
+            mfa_field: None,
+        }
+    }
+}
+
+fn default_username_field() -> String {
+    USERNAME_FIELD_PATTERN.to_string()
+}
+
+fn default_password_field() -> String {
+    PASSWORD_FIELD_PATTERN.to_string()
+}
+
+#[derive(PartialEq, Debug)]
+enum ResponseState {
+    Login,
+    Mfa,
+    Error,
+}
+
+// A compound CSS selector such as `form#login.primary[name=login]`
+#[derive(Debug, Default, PartialEq)]
+struct Selector {
+    name: Option<String>,
+    id: Option<String>,
+    classes: Vec<String>,
+    attributes: Vec<(String, Option<String>)>,
+}
+
+impl FromStr for Selector {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let format = Regex::new(
+            r#"^([A-Za-z][\w-]*)?((?:#[\w-]+|\.[\w-]+|\[[\w-]+(?:=["']?[^\]"']*["']?)?\])*)$"#,
+        )?;
+        let part = Regex::new(r#"#([\w-]+)|\.([\w-]+)|\[([\w-]+)(?:=["']?([^\]"']*)["']?)?\]"#)?;
+
+        let captures = format
+            .captures(s.trim())
+            .ok_or_else(|| anyhow!("Unsupported submit selector: {}", s))?;
+
+        let mut selector = Selector {
+            name: captures.get(1).map(|m| m.as_str().to_lowercase()),
+            ..Default::default()
+        };
+
+        for part in part.captures_iter(captures.get(2).map_or("", |m| m.as_str())) {
+            if let Some(id) = part.get(1) {
+                selector.id = Some(id.as_str().to_string());
+            } else if let Some(class) = part.get(2) {
+                selector.classes.push(class.as_str().to_string());
+            } else if let Some(attribute) = part.get(3) {
+                selector.attributes.push((
+                    attribute.as_str().to_string(),
+                    part.get(4).map(|v| v.as_str().to_string()),
+                ));
+            }
+        }
+
+        Ok(selector)
+    }
+}
+
+impl Selector {
+    fn matches(&self, node: &Node) -> bool {
+        let classes: Vec<&str> = node
+            .attr("class")
+            .map(|c| c.split_whitespace().collect())
+            .unwrap_or_default();
+
+        self.name.as_deref().is_none_or(|n| node.name() == Some(n))
+            && self
+                .id
+                .as_deref()
+                .is_none_or(|id| node.attr("id") == Some(id))
+            && self.classes.iter().all(|c| classes.contains(&c.as_str()))
+            && self
+                .attributes
+                .iter()
+                .all(|(name, value)| match (node.attr(name), value) {
+                    (Some(actual), Some(expected)) => actual == expected,
+                    (Some(_), None) => true,
+                    (None, _) => false,
+                })
+    }
+}
+
+pub struct GenericProvider {
+    client: Client,
+    profile: AppProfile,
+    recipe: FormRecipe,
+    username_field: Regex,
+    password_field: Regex,
+    submit_selector: Option<Selector>,
+    saml_response: Option<String>,
+}
+
+impl GenericProvider {
+    pub fn new(profile: &AppProfile) -> Result<Self> {
+        let recipe = profile.form.clone().unwrap_or_default();
+
+        Ok(GenericProvider {
+            client: Client::new()?,
+            profile: profile.clone(),
+            username_field: Regex::new(&recipe.username_field)
+                .with_context(|| "Invalid pattern for username field")?,
+            password_field: Regex::new(&recipe.password_field)
+                .with_context(|| "Invalid pattern for password field")?,
+            submit_selector: recipe
+                .submit_selector
+                .as_deref()
+                .map(Selector::from_str)
+                .transpose()?,
+            recipe,
+            saml_response: None,
+        })
+    }
+}
+
+impl IdentityProvider for GenericProvider {
+    fn profile(&self) -> &AppProfile {
+        &self.profile
+    }
+
+    fn new_session(&mut self) -> Result<()> {
+        let profile = &self.profile;
+
+        let config_credentials =
+            ConfigCredentials::load(profile).or_else(|_| ConfigCredentials::create(profile))?;
+
+        let response = self
+            .client
+            .get(profile.request_url()?)
+            .with_context(|| "Unable to reach login form")?;
+
+        let saml_response =
+            self.complete_login(response, &profile.username, &config_credentials.password)?;
+
+        config_credentials.write(profile)?;
+
+        self.saml_response = Some(saml_response);
+        Ok(())
+    }
+
+    fn fetch_saml_assertion(&self) -> Result<String> {
+        self.saml_response
+            .clone()
+            .with_context(|| "Missing SAML response, no session established")
+    }
+}
+
+impl GenericProvider {
+    // Submits the login and MFA forms described by the recipe until the IdP
+    // hands out the SAML response
+    fn complete_login(&self, response: Response, username: &str, password: &str) -> Result<String> {
+        let mut login_submitted = false;
+
+        form::complete_login(&self.client, response, "IdP", |page_url, page| {
+            let form = self.select_form(page)?;
+
+            let form_content = match self.evaluate_response_state(&form) {
+                ResponseState::Login if login_submitted => {
+                    return Err(anyhow!("Unable to login: credentials were not accepted"))
+                }
+                ResponseState::Login => {
+                    login_submitted = true;
+                    build_matching_form_elements(
+                        username,
+                        password,
+                        &self.username_field,
+                        &self.password_field,
+                        &form,
+                    )
+                }
+                ResponseState::Mfa => {
+                    let mut form_content = build_form_elements(form.find(Name("input")));
+                    if let Some(field) = &self.recipe.mfa_field {
+                        let _ = form_content.insert(field.to_owned(), utils::prompt_mfa()?);
+                    }
+                    form_content
+                }
+                ResponseState::Error => {
+                    return Err(anyhow!(
+                        "Unable to login: no login or MFA form found at {}",
+                        page_url
+                    ))
+                }
+            };
+
+            Ok((page_url.join(fetch_submit_url(&form)?)?, form_content))
+        })
+    }
+
+    // Narrows the page down to the form matched by the submit selector
+    fn select_form(&self, page: &str) -> Result<Document> {
+        let document = Document::from(page);
+
+        let selected = self.submit_selector.as_ref().and_then(|selector| {
+            document
+                .find(|node: &Node| selector.matches(node))
+                .next()
+                .and_then(enclosing_form)
+        });
+
+        match selected {
+            Some(form) => Ok(Document::from(form.html().as_str())),
+            None => {
+                debug!("No form matches the submit selector, using the last form");
+                Ok(document)
+            }
+        }
+    }
+
+    fn evaluate_response_state(&self, form: &Document) -> ResponseState {
+        let mut state = ResponseState::Error;
+
+        for input in form.find(Name("input")) {
+            match input.attr("name") {
+                Some(name) if self.password_field.is_match(name) => {
+                    return ResponseState::Login;
+                }
+                Some(name) if self.recipe.mfa_field.as_deref() == Some(name) => {
+                    state = ResponseState::Mfa;
+                }
+                _ => (),
+            }
+        }
+
+        state
+    }
+}
+
+fn enclosing_form(node: Node) -> Option<Node> {
+    let mut current = Some(node);
+
+    while let Some(node) = current {
+        if node.name() == Some("form") {
+            return Some(node);
+        }
+        current = node.parent();
+    }
+
+    None
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::saml;
+    use mockito::Matcher;
+    use std::fs;
+
+    fn profile(url: &str) -> Result<AppProfile> {
+        Ok(toml::from_str(&format!(
+            r#"
+            name = "profile"
+            provider = "generic"
+            url = "{}/realms/aws/protocol/saml/clients/amazon-aws"
+            username = "jdoe"
+
+            [form]
+            username_field = "^username$"
+            password_field = "^password$"
+            submit_selector = "form#kc-form-login"
+            mfa_field = "otp"
+        "#,
+            url
+        ))?)
+    }
+
+    #[test]
+    fn parses_selectors() -> Result<()> {
+        assert_eq!(
+            Selector::from_str("form#login.primary.wide[name='login'][data-flow]")?,
+            Selector {
+                name: Some("form".to_string()),
+                id: Some("login".to_string()),
+                classes: vec!["primary".to_string(), "wide".to_string()],
+                attributes: vec![
+                    ("name".to_string(), Some("login".to_string())),
+                    ("data-flow".to_string(), None),
+                ],
+            }
+        );
+        assert_eq!(
+            Selector::from_str("#submit")?,
+            Selector {
+                id: Some("submit".to_string()),
+                ..Default::default()
+            }
+        );
+        assert!(Selector::from_str("div > form").is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn selects_form_by_selector() -> Result<()> {
+        let provider = GenericProvider::new(&profile("https://idp.example.com")?)?;
+        let page = fs::read_to_string("tests/fixtures/generic/login_page.html")?;
+
+        let form = provider.select_form(&page)?;
+
+        assert_eq!(
+            fetch_submit_url(&form)?,
+            "/realms/aws/login-actions/authenticate?session_code=abc&execution=def"
+        );
+        assert_eq!(
+            provider.evaluate_response_state(&form),
+            ResponseState::Login
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn evaluates_state_of_fixture_pages() -> Result<()> {
+        let provider = GenericProvider::new(&profile("https://idp.example.com")?)?;
+        let states = vec![
+            (
+                "tests/fixtures/generic/login_page.html",
+                ResponseState::Login,
+            ),
+            ("tests/fixtures/generic/mfa_page.html", ResponseState::Mfa),
+            (
+                "tests/fixtures/generic/error_page.html",
+                ResponseState::Error,
+            ),
+        ];
+
+        for (path, state) in states {
+            let form = provider.select_form(&fs::read_to_string(path)?)?;
+            assert_eq!(provider.evaluate_response_state(&form), state, "{}", path);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn uses_default_recipe_without_configuration() -> Result<()> {
+        let mut profile = profile("https://idp.example.com")?;
+        profile.form = None;
+
+        let provider = GenericProvider::new(&profile)?;
+        let form = provider.select_form(&fs::read_to_string(
+            "tests/fixtures/adfs/initial_login_form.html",
+        )?)?;
+
+        assert_eq!(
+            provider.evaluate_response_state(&form),
+            ResponseState::Login
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn runs_login_against_form_idp() -> Result<()> {
+        let mut server = mockito::Server::new();
+
+        let mocks = vec![
+            server
+                .mock("GET", "/realms/aws/protocol/saml/clients/amazon-aws")
+                .with_body_from_file("tests/fixtures/generic/login_page.html")
+                .create(),
+            server
+                .mock("POST", "/realms/aws/login-actions/authenticate")
+                .match_query(Matcher::UrlEncoded(
+                    "session_code".to_string(),
+                    "abc".to_string(),
+                ))
+                .match_body(Matcher::AllOf(vec![
+                    Matcher::UrlEncoded("username".to_string(), "jdoe".to_string()),
+                    Matcher::UrlEncoded("password".to_string(), "password".to_string()),
+                    Matcher::UrlEncoded("credentialId".to_string(), "".to_string()),
+                ]))
+                .with_body_from_file("tests/fixtures/generic/saml_response.html")
+                .create(),
+        ];
+
+        let profile = profile(&server.url())?;
+        let provider = GenericProvider::new(&profile)?;
+
+        let response = provider.client.get(profile.request_url()?)?;
+        let page = provider.complete_login(response, "jdoe", "password")?;
+
+        assert!(saml::extract_saml_assertion(&page).is_ok());
+        for mock in mocks {
+            mock.assert();
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn fails_when_credentials_are_rejected() -> Result<()> {
+        let mut server = mockito::Server::new();
+
+        let _m = server
+            .mock("GET", "/realms/aws/protocol/saml/clients/amazon-aws")
+            .with_body_from_file("tests/fixtures/generic/login_page.html")
+            .create();
+        let _m = server
+            .mock("POST", "/realms/aws/login-actions/authenticate")
+            .match_query(Matcher::Any)
+            .with_body_from_file("tests/fixtures/generic/login_page.html")
+            .create();
+
+        let profile = profile(&server.url())?;
+        let provider = GenericProvider::new(&profile)?;
+
+        let response = provider.client.get(profile.request_url()?)?;
+        let error = provider
+            .complete_login(response, "jdoe", "wrong")
+            .unwrap_err();
+
+        assert_eq!(
+            error.to_string(),
+            "Unable to login: credentials were not accepted"
+        );
+
+        Ok(())
+    }
+}
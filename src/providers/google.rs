@@ -0,0 +1,464 @@
+use crate::config::app::AppProfile;
+use crate::credentials::config::ConfigCredentials;
+use crate::credentials::Credential;
+use crate::providers::form::build_form_elements;
+use crate::providers::form::client::Client;
+use crate::providers::IdentityProvider;
+use crate::saml;
+use crate::utils;
+
+use anyhow::{anyhow, Context, Result};
+use log::{debug, info};
+use reqwest::blocking::Response;
+use reqwest::Url;
+use select::document::Document;
+use select::node::Node;
+use select::predicate::{Attr, Name, Predicate};
+use serde::Serialize;
+use std::time::Duration;
+
+const AUTHZEN_URL: &str = "https://content.googleapis.com/cryptauth/v1/authzen/awaittx";
+const MAX_FORM_STEPS: usize = 10;
+const EMAIL_FIELD: &str = "Email";
+const PASSWORD_FIELD: &str = "Passwd";
+const PIN_FIELD: &str = "Pin";
+const CHALLENGE_PATH: &str = "/challenge/";
+pub const PROMPT_WAIT_TIMEOUT: u64 = 60;
+
+#[derive(PartialEq, Debug)]
+enum ResponseState {
+    Success,
+    Identifier,
+    Password,
+    Totp,
+    Sms,
+    Prompt,
+    SelectChallenge,
+    Error,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct AwaitTransactionRequest {
+    tx_id: String,
+}
+
+pub struct GoogleProvider {
+    client: Client,
+    profile: AppProfile,
+    authzen_url: Url,
+    saml_response: Option<String>,
+}
+
+impl GoogleProvider {
+    pub fn new(profile: &AppProfile) -> Result<Self> {
+        Ok(GoogleProvider {
+            client: Client::new()?,
+            profile: profile.clone(),
+            authzen_url: Url::parse(AUTHZEN_URL)?,
+            saml_response: None,
+        })
+    }
+}
+
+impl IdentityProvider for GoogleProvider {
+    fn profile(&self) -> &AppProfile {
+        &self.profile
+    }
+
+    fn new_session(&mut self) -> Result<()> {
+        let profile = &self.profile;
+
+        let config_credentials =
+            ConfigCredentials::load(profile).or_else(|_| ConfigCredentials::create(profile))?;
+
+        let response = self
+            .client
+            .get(profile.request_url()?)
+            .with_context(|| "Unable to reach Google sign-in page")?;
+
+        let saml_response =
+            self.complete_login(response, &profile.username, &config_credentials.password)?;
+
+        config_credentials.write(profile)?;
+
+        self.saml_response = Some(saml_response);
+        Ok(())
+    }
+
+    fn fetch_saml_assertion(&self) -> Result<String> {
+        self.saml_response
+            .clone()
+            .with_context(|| "Missing SAML response, no session established")
+    }
+}
+
+impl GoogleProvider {
+    // Walks through the sign-in and challenge pages until Google posts the
+    // SAML response
+    fn complete_login(
+        &self,
+        mut response: Response,
+        username: &str,
+        password: &str,
+    ) -> Result<String> {
+        for _ in 0..MAX_FORM_STEPS {
+            let page_url = response.url().clone();
+            let page = response.text()?;
+
+            let document = Document::from(page.as_str());
+            let state = evaluate_response_state(&page, &document);
+            debug!("Google sign-in page {} in state {:?}", page_url, state);
+
+            let (form, form_content) = match state {
+                ResponseState::Success => return Ok(page),
+                ResponseState::Identifier => {
+                    let form = find_form_with_field(&document, EMAIL_FIELD)?;
+                    let mut form_content = build_form_elements(form.find(Name("input")));
+                    let _ = form_content.insert(EMAIL_FIELD.to_owned(), username.to_owned());
+                    (form, form_content)
+                }
+                ResponseState::Password => {
+                    let form = find_form_with_field(&document, PASSWORD_FIELD)?;
+                    let mut form_content = build_form_elements(form.find(Name("input")));
+                    let _ = form_content.insert(EMAIL_FIELD.to_owned(), username.to_owned());
+                    let _ = form_content.insert(PASSWORD_FIELD.to_owned(), password.to_owned());
+                    (form, form_content)
+                }
+                ResponseState::Totp | ResponseState::Sms => {
+                    let form = find_form_with_field(&document, PIN_FIELD)?;
+                    let mut form_content = build_form_elements(form.find(Name("input")));
+                    let _ = form_content.insert(PIN_FIELD.to_owned(), utils::prompt_mfa()?);
+                    (form, form_content)
+                }
+                ResponseState::Prompt => {
+                    self.await_prompt(&document)?;
+                    let form = challenge_forms(&document)
+                        .into_iter()
+                        .next()
+                        .with_context(|| "Missing Google prompt form")?;
+                    let form_content = build_form_elements(form.find(Name("input")));
+                    (form, form_content)
+                }
+                ResponseState::SelectChallenge => {
+                    let form = select_challenge(&document)?;
+                    let form_content = build_form_elements(form.find(Name("input")));
+                    (form, form_content)
+                }
+                ResponseState::Error => {
+                    return Err(match fetch_error_text(&document) {
+                        Some(error) => anyhow!("Unable to login: {}", error),
+                        None => anyhow!("Unable to login: unsupported Google sign-in page"),
+                    })
+                }
+            };
+
+            let submit_url = page_url.join(
+                form.attr("action")
+                    .with_context(|| "Missing submission URL for sign-in form")?,
+            )?;
+            debug!("Submitting Google form to {}", submit_url);
+
+            response = self.client.post(submit_url, &form_content)?;
+        }
+
+        Err(anyhow!(
+            "Google sign-in did not complete after {} steps",
+            MAX_FORM_STEPS
+        ))
+    }
+
+    // Blocks until the user confirmed the sign-in prompt on their phone
+    fn await_prompt(&self, document: &Document) -> Result<()> {
+        let tx_id = find_data_attribute(document, "data-tx-id")
+            .with_context(|| "Missing transaction for Google prompt")?;
+        let api_key = find_data_attribute(document, "data-api-key")
+            .with_context(|| "Missing API key for Google prompt")?;
+
+        let mut url = self.authzen_url.clone();
+        url.query_pairs_mut()
+            .append_pair("alt", "json")
+            .append_pair("key", &api_key);
+
+        eprintln!("Open the Google app on your phone and confirm the sign-in");
+
+        self.client
+            .await_json(
+                url,
+                &AwaitTransactionRequest { tx_id },
+                Duration::from_secs(PROMPT_WAIT_TIMEOUT),
+            )
+            .map_err(|e| anyhow!("No confirmation of Google prompt: {}", e))?;
+
+        Ok(())
+    }
+}
+
+fn evaluate_response_state(page: &str, document: &Document) -> ResponseState {
+    if saml::extract_saml_assertion(page).is_ok() {
+        return ResponseState::Success;
+    }
+
+    if fetch_error_text(document).is_some() {
+        return ResponseState::Error;
+    }
+
+    if has_field(document, PASSWORD_FIELD) {
+        return ResponseState::Password;
+    }
+
+    if has_field(document, EMAIL_FIELD) {
+        return ResponseState::Identifier;
+    }
+
+    let forms = challenge_forms(document);
+    if forms.len() > 1 {
+        return ResponseState::SelectChallenge;
+    }
+
+    match forms.first().and_then(|form| challenge_type(form)) {
+        Some("totp") => ResponseState::Totp,
+        Some("ipp") => ResponseState::Sms,
+        Some("az") => ResponseState::Prompt,
+        _ => ResponseState::Error,
+    }
+}
+
+fn select_challenge<'a>(document: &'a Document) -> Result<Node<'a>> {
+    let forms: Vec<Node> = challenge_forms(document)
+        .into_iter()
+        .filter(|form| challenge_label(form).is_some())
+        .collect();
+
+    let form = match forms.len() {
+        0 => {
+            return Err(anyhow!(
+                "MFA required, and no supported challenges available"
+            ))
+        }
+        1 => {
+            info!("Only one challenge available, using it");
+            forms[0]
+        }
+        _ => {
+            let labels: Vec<&str> = forms.iter().filter_map(challenge_label).collect();
+            let selection = dialoguer::Select::new()
+                .with_prompt("Please select the challenge to use")
+                .items(&labels)
+                .default(0)
+                .interact()?;
+            forms[selection]
+        }
+    };
+
+    Ok(form)
+}
+
+// Challenge forms post to e.g. /signin/challenge/totp/2
+fn challenge_forms(document: &Document) -> Vec<Node<'_>> {
+    document
+        .find(Name("form"))
+        .filter(|form| {
+            form.attr("action")
+                .map(|action| action.contains(CHALLENGE_PATH))
+                .unwrap_or(false)
+        })
+        .collect()
+}
+
+fn challenge_type<'a>(form: &Node<'a>) -> Option<&'a str> {
+    let action = form.attr("action")?;
+    let start = action.find(CHALLENGE_PATH)? + CHALLENGE_PATH.len();
+
+    action[start..].split('/').next()
+}
+
+fn challenge_label(form: &Node) -> Option<&'static str> {
+    match challenge_type(form)? {
+        "totp" => Some("Authenticator app"),
+        "ipp" => Some("Text message"),
+        "az" => Some("Google prompt"),
+        _ => None,
+    }
+}
+
+fn has_field(document: &Document, name: &str) -> bool {
+    document
+        .find(Name("input").and(Attr("name", name)))
+        .any(|input| input.attr("type") != Some("hidden"))
+}
+
+fn find_form_with_field<'a>(document: &'a Document, name: &str) -> Result<Node<'a>> {
+    document
+        .find(Name("form"))
+        .find(|form| form.find(Attr("name", name)).next().is_some())
+        .with_context(|| format!("Missing sign-in form with field {}", name))
+}
+
+fn find_data_attribute(document: &Document, name: &str) -> Option<String> {
+    document
+        .find(Attr(name, ()))
+        .next()
+        .and_then(|node| node.attr(name))
+        .map(|value| value.to_owned())
+}
+
+fn fetch_error_text(document: &Document) -> Option<String> {
+    document
+        .find(Attr("id", "errorMsg"))
+        .next()
+        .map(|node| node.text().trim().to_owned())
+        .filter(|text| !text.is_empty())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use mockito::Matcher;
+    use std::fs;
+
+    fn document(path: &str) -> Result<(String, Document)> {
+        let page = fs::read_to_string(path)?;
+        let document = Document::from(page.as_str());
+        Ok((page, document))
+    }
+
+    #[test]
+    fn evaluates_state_of_fixture_pages() -> Result<()> {
+        let states = vec![
+            (
+                "tests/fixtures/google/identifier.html",
+                ResponseState::Identifier,
+            ),
+            (
+                "tests/fixtures/google/password.html",
+                ResponseState::Password,
+            ),
+            (
+                "tests/fixtures/google/totp_challenge.html",
+                ResponseState::Totp,
+            ),
+            (
+                "tests/fixtures/google/sms_challenge.html",
+                ResponseState::Sms,
+            ),
+            (
+                "tests/fixtures/google/prompt_challenge.html",
+                ResponseState::Prompt,
+            ),
+            (
+                "tests/fixtures/google/select_challenge.html",
+                ResponseState::SelectChallenge,
+            ),
+            (
+                "tests/fixtures/google/login_error.html",
+                ResponseState::Error,
+            ),
+            (
+                "tests/fixtures/google/saml_response.html",
+                ResponseState::Success,
+            ),
+        ];
+
+        for (path, state) in states {
+            let (page, document) = document(path)?;
+            assert_eq!(evaluate_response_state(&page, &document), state, "{}", path);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn reads_challenge_types_from_form_actions() -> Result<()> {
+        let (_, document) = document("tests/fixtures/google/select_challenge.html")?;
+
+        let types: Vec<&str> = challenge_forms(&document)
+            .iter()
+            .filter_map(challenge_type)
+            .collect();
+
+        assert_eq!(types, vec!["az", "totp", "sk"]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn fetches_error_text() -> Result<()> {
+        let (_, document) = document("tests/fixtures/google/login_error.html")?;
+
+        assert_eq!(
+            fetch_error_text(&document),
+            Some("Wrong password. Try again or click Forgot password to reset it.".to_string())
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn runs_login_with_google_prompt() -> Result<()> {
+        let mut server = mockito::Server::new();
+
+        let mocks = vec![
+            server
+                .mock("GET", "/o/saml2/initsso")
+                .match_query(Matcher::Any)
+                .with_body_from_file("tests/fixtures/google/identifier.html")
+                .create(),
+            server
+                .mock("POST", "/signin/v1/lookup")
+                .match_body(Matcher::AllOf(vec![
+                    Matcher::UrlEncoded("Email".to_string(), "jdoe@example.com".to_string()),
+                    Matcher::UrlEncoded("gxf".to_string(), "gxf-identifier".to_string()),
+                ]))
+                .with_body_from_file("tests/fixtures/google/password.html")
+                .create(),
+            server
+                .mock("POST", "/signin/challenge/sl/password")
+                .match_body(Matcher::AllOf(vec![
+                    Matcher::UrlEncoded("Passwd".to_string(), "password".to_string()),
+                    Matcher::UrlEncoded("gxf".to_string(), "gxf-password".to_string()),
+                ]))
+                .with_body_from_file("tests/fixtures/google/prompt_challenge.html")
+                .create(),
+            server
+                .mock("POST", "/awaittx")
+                .match_query(Matcher::AllOf(vec![
+                    Matcher::UrlEncoded("alt".to_string(), "json".to_string()),
+                    Matcher::UrlEncoded("key".to_string(), "api-key".to_string()),
+                ]))
+                .match_body(Matcher::Json(serde_json::json!({"txId": "tx-id"})))
+                .with_body(r#"{"status":"COMPLETED_AUTHORIZED"}"#)
+                .create(),
+            server
+                .mock("POST", "/signin/challenge/az/2")
+                .match_body(Matcher::UrlEncoded(
+                    "TL".to_string(),
+                    "tl-prompt".to_string(),
+                ))
+                .with_body_from_file("tests/fixtures/google/saml_response.html")
+                .create(),
+        ];
+
+        let profile: AppProfile = toml::from_str(&format!(
+            r#"
+            name = "profile"
+            provider = "google"
+            url = "{}/o/saml2/initsso?idpid=C01abcdef&spid=123456789&forceauthn=false"
+            username = "jdoe@example.com"
+        "#,
+            server.url()
+        ))?;
+        let mut provider = GoogleProvider::new(&profile)?;
+        provider.authzen_url = Url::parse(&format!("{}/awaittx", server.url()))?;
+
+        let response = provider.client.get(profile.request_url()?)?;
+        let page = provider.complete_login(response, "jdoe@example.com", "password")?;
+
+        assert!(saml::extract_saml_assertion(&page).is_ok());
+        for mock in mocks {
+            mock.assert();
+        }
+
+        Ok(())
+    }
+}
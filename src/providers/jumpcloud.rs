@@ -1,22 +1,28 @@
 mod client;
 
 use crate::config::app::AppProfile;
-use crate::credentials::aws::AwsCredentials;
 use crate::credentials::config::ConfigCredentials;
+use crate::credentials::session::IdpSession;
 use crate::credentials::Credential;
+use crate::duo::{self, DuoChallenge, DuoFactor};
 use crate::providers::jumpcloud::client::Client;
+use crate::providers::IdentityProvider;
 use crate::saml;
 use crate::utils;
 
 use anyhow::{anyhow, Context, Result};
-use log::{debug, trace};
+use log::{debug, info};
 use reqwest::{StatusCode, Url};
 use serde::{Deserialize, Serialize};
+use std::fmt;
 
-const AUTH_SUBMIT_URL: &str = "https://console.jumpcloud.com/userconsole/auth";
-const XSRF_URL: &str = "https://console.jumpcloud.com/userconsole/xsrf";
+const CONSOLE_URL: &str = "https://console.jumpcloud.com";
+const AUTH_SUBMIT_PATH: &str = "userconsole/auth";
+const DUO_AUTH_PATH: &str = "userconsole/auth/duo";
+const DUO_PARENT_PATH: &str = "userconsole/";
+const XSRF_PATH: &str = "userconsole/xsrf";
 
-#[derive(Serialize, Debug)]
+#[derive(Serialize, Debug, Clone)]
 #[serde(rename_all = "camelCase")]
 pub struct LoginRequest {
     context: String,
@@ -50,9 +56,48 @@ pub struct XsrfResponse {
     xsrf: String,
 }
 
+// Body of the 401 JumpCloud answers with when the user has to pass MFA
+#[derive(Deserialize, Debug, Default)]
+struct MfaRequiredResponse {
+    #[serde(default)]
+    factors: Vec<MfaFactor>,
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+struct MfaFactor {
+    #[serde(rename = "type")]
+    kind: MfaFactorType,
+    status: Option<String>,
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum MfaFactorType {
+    Totp,
+    Duo,
+    #[serde(other)]
+    Unimplemented,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct DuoAuthResponse {
+    api_host: String,
+    sig_request: String,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct DuoAuthRequest {
+    sig_response: String,
+    redirect_to: String,
+}
+
 pub struct JumpcloudProvider {
     client: Client,
     profile: AppProfile,
+    console_url: Url,
+    duo_prompt: fn() -> Result<DuoFactor>,
     pub redirect_to: Option<String>,
 }
 
@@ -61,71 +106,194 @@ impl JumpcloudProvider {
         Ok(JumpcloudProvider {
             client: Client::new()?,
             profile: profile.clone(),
+            console_url: Url::parse(CONSOLE_URL)?,
+            duo_prompt: duo::prompt_factor,
             redirect_to: None,
         })
     }
 }
 
-impl JumpcloudProvider {
-    pub fn new_session(&mut self) -> Result<&Self> {
+impl IdentityProvider for JumpcloudProvider {
+    fn profile(&self) -> &AppProfile {
+        &self.profile
+    }
+
+    fn new_session(&mut self) -> Result<()> {
         let profile = &self.profile;
 
         let config_credentials =
             ConfigCredentials::load(profile).or_else(|_| ConfigCredentials::create(profile))?;
 
+        let content = self.login(&profile.username, &config_credentials.password)?;
+
+        config_credentials.write(profile)?;
+
+        self.redirect_to = Some(content.redirect_to);
+        Ok(())
+    }
+
+    fn resume_session(&mut self) -> Result<bool> {
+        match IdpSession::load(&self.profile) {
+            Ok(session) if !session.is_expired() => self.resume(session),
+            _ => Ok(false),
+        }
+    }
+
+    fn fetch_saml_assertion(&self) -> Result<String> {
+        let profile = &self.profile;
+        let url = self
+            .redirect_to
+            .clone()
+            .with_context(|| "Missing SAML redirect URL, no session established")?;
+
+        let input = self
+            .client
+            .get(Url::parse(&url)?)
+            .with_context(|| format!("Error getting SAML response for profile {}", profile.name))?
+            .text()?;
+
+        debug!("Text for SAML response: {:#?}", input);
+
+        // JumpCloud doesn't tell when its session ends, so it's kept until the
+        // SSO page stops handing out assertions
+        let session = IdpSession {
+            cookies: self.client.jar.session_cookies(),
+            ..Default::default()
+        };
+        if let Err(e) = session.write(profile) {
+            debug!("Unable to save JumpCloud session: {}", e);
+        }
+
+        Ok(input)
+    }
+}
+
+impl JumpcloudProvider {
+    // With a live session the SSO URL of the app answers with the SAML
+    // response right away instead of sending us to the login
+    fn resume(&mut self, session: IdpSession) -> Result<bool> {
+        self.client.jar.restore(&session);
+
+        let page = self
+            .client
+            .get(Url::parse(&self.profile.url)?)
+            .and_then(|r| r.text().map_err(|e| e.into()));
+
+        match page.and_then(|p| saml::extract_saml_assertion(&p)) {
+            Ok(_) => {
+                debug!("Resuming JumpCloud session");
+                self.redirect_to = Some(self.profile.url.clone());
+                Ok(true)
+            }
+            Err(e) => {
+                debug!("Unable to resume JumpCloud session: {}", e);
+                self.client.jar.clear();
+                Ok(false)
+            }
+        }
+    }
+
+    fn login(&self, username: &str, password: &str) -> Result<LoginResponse> {
         let response: XsrfResponse = self
             .client
-            .get(Url::parse(XSRF_URL)?)
+            .get(self.console_url.join(XSRF_PATH)?)
             .with_context(|| "Unable to obtain XSRF token")?
             .json()?;
 
         let token = response.xsrf;
-        let username = &profile.username;
-        let password = &config_credentials.password;
-        let redirect_to = create_redirect_to(&profile.url)?;
+        let redirect_to = create_redirect_to(&self.profile.url)?;
         let mut login_request =
-            LoginRequest::from_credentials(username.clone(), password.clone(), redirect_to);
+            LoginRequest::from_credentials(username.to_owned(), password.to_owned(), redirect_to);
 
         debug!("Login request: {:?}", login_request);
 
-        let login_response: Result<LoginResponse, _> =
-            self.client
-                .post(Url::parse(AUTH_SUBMIT_URL)?, &login_request, &token);
-
-        let content: LoginResponse = match login_response {
-            Ok(r) => r,
-            Err(e) => match e.status() {
-                Some(StatusCode::UNAUTHORIZED) if login_request.otp.is_empty() => {
-                    login_request.otp = utils::prompt_mfa()?;
-                    self.client
-                        .post(Url::parse(AUTH_SUBMIT_URL)?, &login_request, &token)?
-                }
-                _ => return Err(anyhow!("Unable to login: {}", e)),
-            },
-        };
+        let submit_url = self.console_url.join(AUTH_SUBMIT_PATH)?;
+        let response = self
+            .client
+            .submit(submit_url.clone(), &login_request, &token)?;
 
-        config_credentials.write(profile)?;
+        match response.status() {
+            StatusCode::UNAUTHORIZED => {
+                let mfa: MfaRequiredResponse = response.json().unwrap_or_default();
 
-        self.redirect_to = Some(content.redirect_to);
-        Ok(self)
+                match select_factor(mfa.factors)? {
+                    MfaFactorType::Duo => self.verify_duo(login_request.redirect_to, &token),
+                    _ => {
+                        login_request.otp = utils::prompt_mfa()?;
+                        self.client
+                            .post(submit_url, &login_request, &token)
+                            .map_err(|e| anyhow!("Unable to login: {}", e))
+                    }
+                }
+            }
+            status if status.is_success() => Ok(response.json()?),
+            status => Err(anyhow!("Unable to login: {}", status)),
+        }
     }
 
-    pub fn fetch_aws_credentials(&self) -> Result<AwsCredentials> {
-        let profile = &self.profile;
-        let url = self.redirect_to.clone().expect("Missing SAML redirect URL");
-
-        let input = self
+    // Runs the Duo prompt JumpCloud hands out and submits its signed response
+    fn verify_duo(&self, redirect_to: String, token: &str) -> Result<LoginResponse> {
+        let duo_url = self.console_url.join(DUO_AUTH_PATH)?;
+        let duo_auth: DuoAuthResponse = self
             .client
-            .get(Url::parse(&url)?)
-            .with_context(|| format!("Error getting SAML response for profile {}", profile.name))?
-            .text()?;
+            .get(duo_url.clone())
+            .with_context(|| "Unable to start Duo verification")?
+            .json()?;
 
-        debug!("Text for SAML response: {:#?}", input);
+        let challenge = DuoChallenge {
+            host: duo_auth.api_host,
+            signature: duo_auth.sig_request,
+            parent: self.console_url.join(DUO_PARENT_PATH)?.to_string(),
+        };
+        let sig_response = duo::verify(&challenge, &(self.duo_prompt)()?)?;
+
+        self.client
+            .post(
+                duo_url,
+                &DuoAuthRequest {
+                    sig_response,
+                    redirect_to,
+                },
+                token,
+            )
+            .map_err(|e| anyhow!("Unable to login: {}", e))
+    }
+}
 
-        let credentials = saml::get_credentials_from_saml(input, profile.role.clone())?;
+// Falls back to TOTP when JumpCloud doesn't tell which factors are available
+fn select_factor(factors: Vec<MfaFactor>) -> Result<MfaFactorType> {
+    let mut factors: Vec<MfaFactorType> = factors
+        .into_iter()
+        .filter(|f| f.kind != MfaFactorType::Unimplemented)
+        .filter(|f| f.status.as_deref().is_none_or(|s| s == "available"))
+        .map(|f| f.kind)
+        .collect();
+    factors.dedup();
 
-        trace!("Credentials: {:#?}", credentials);
-        Ok(credentials)
+    match factors.len() {
+        0 => Ok(MfaFactorType::Totp),
+        1 => {
+            info!("Only one MFA factor available, using it");
+            Ok(factors.remove(0))
+        }
+        _ => {
+            let selection = dialoguer::Select::new()
+                .with_prompt("Please select the MFA factor to use")
+                .items(&factors)
+                .default(0)
+                .interact()?;
+            Ok(factors.remove(selection))
+        }
+    }
+}
+
+impl fmt::Display for MfaFactorType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MfaFactorType::Totp => write!(f, "TOTP"),
+            MfaFactorType::Duo => write!(f, "Duo Security"),
+            MfaFactorType::Unimplemented => write!(f, "Unimplemented factor"),
+        }
     }
 }
 
@@ -136,3 +304,165 @@ fn create_redirect_to(s: &str) -> Result<String> {
     url.remove(0);
     Ok(url)
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::credentials::session::SessionJar;
+    use base64::Engine;
+    use mockito::Matcher;
+    use reqwest::cookie::CookieStore;
+    use reqwest::header::HeaderValue;
+
+    fn provider(server: &mockito::ServerGuard) -> Result<JumpcloudProvider> {
+        let profile: AppProfile = toml::from_str(
+            r#"
+            name = "profile"
+            provider = "jumpcloud"
+            url = "https://sso.jumpcloud.com/saml2/amazon-aws"
+            username = "jdoe@example.com"
+        "#,
+        )?;
+        let mut provider = JumpcloudProvider::new(&profile)?;
+        provider.console_url = Url::parse(&server.url())?;
+
+        Ok(provider)
+    }
+
+    #[test]
+    fn selects_available_factor() -> Result<()> {
+        let factors: MfaRequiredResponse = serde_json::from_str(
+            r#"{"factors":[{"type":"push","status":"available"},{"type":"totp","status":"unavailable"},{"type":"duo","status":"available"}]}"#,
+        )?;
+
+        assert_eq!(select_factor(factors.factors)?, MfaFactorType::Duo);
+        assert_eq!(select_factor(vec![])?, MfaFactorType::Totp);
+
+        Ok(())
+    }
+
+    #[test]
+    fn logs_in_with_duo() -> Result<()> {
+        let (duo_server, duo_mocks) = duo::mock::server();
+        let mut server = mockito::Server::new();
+
+        let mocks = [
+            server
+                .mock("GET", "/userconsole/xsrf")
+                .with_body(r#"{"xsrf":"xsrf-token"}"#)
+                .create(),
+            server
+                .mock("POST", "/userconsole/auth")
+                .match_header("X-Xsrftoken", "xsrf-token")
+                .match_body(Matcher::PartialJsonString(
+                    r#"{"email":"jdoe@example.com","password":"password","otp":""}"#.to_string(),
+                ))
+                .with_status(401)
+                .with_body(
+                    r#"{"error":"MFA required","factors":[{"type":"duo","status":"available"}]}"#,
+                )
+                .create(),
+            server
+                .mock("GET", "/userconsole/auth/duo")
+                .with_body(format!(
+                    r#"{{"apiHost":"{}","sigRequest":"{}"}}"#,
+                    duo_server.url(),
+                    duo::mock::SIGNATURE
+                ))
+                .create(),
+            server
+                .mock("POST", "/userconsole/auth/duo")
+                .match_header("X-Xsrftoken", "xsrf-token")
+                .match_body(Matcher::PartialJsonString(format!(
+                    r#"{{"sigResponse":"{}","redirectTo":"saml2/amazon-aws"}}"#,
+                    duo::mock::SIGNED_RESPONSE
+                )))
+                .with_body(r#"{"redirectTo":"https://sso.jumpcloud.com/saml2/amazon-aws"}"#)
+                .create(),
+        ];
+
+        let mut provider = provider(&server)?;
+        provider.duo_prompt = || Ok(DuoFactor::Push);
+
+        let response = provider.login("jdoe@example.com", "password")?;
+
+        assert_eq!(
+            response.redirect_to,
+            "https://sso.jumpcloud.com/saml2/amazon-aws"
+        );
+        for mock in mocks.iter().chain(duo_mocks.iter()) {
+            mock.assert();
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn resumes_session_from_saved_cookies() -> Result<()> {
+        let mut server = mockito::Server::new();
+        let assertion = base64::engine::general_purpose::STANDARD
+            .encode(std::fs::read("tests/fixtures/okta/saml_response.xml")?);
+        let sso = server
+            .mock("GET", "/saml2/amazon-aws")
+            .match_header("cookie", "jcsession=live")
+            .with_body(format!(
+                r#"<form><input name="SAMLResponse" value="{}"/></form>"#,
+                assertion
+            ))
+            .create();
+        let _login = server
+            .mock("GET", "/saml2/amazon-aws")
+            .with_body("<html>Login</html>")
+            .create();
+
+        let mut provider = provider(&server)?;
+        provider.profile.url = format!("{}/saml2/amazon-aws", server.url());
+        let session = |value: &str| -> Result<IdpSession> {
+            let jar = SessionJar::default();
+            jar.set_cookies(
+                &mut [HeaderValue::from_str(&format!(
+                    "jcsession={}; Path=/",
+                    value
+                ))?]
+                .iter(),
+                &Url::parse(&server.url())?,
+            );
+            Ok(IdpSession {
+                cookies: jar.session_cookies(),
+                ..Default::default()
+            })
+        };
+
+        assert!(provider.resume(session("live")?)?);
+        assert_eq!(provider.redirect_to, Some(provider.profile.url.clone()));
+        sso.assert();
+
+        provider.redirect_to = None;
+        assert!(!provider.resume(session("expired")?)?);
+        assert_eq!(provider.redirect_to, None);
+        assert!(provider.client.jar.session_cookies().is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn fails_on_rejected_credentials() -> Result<()> {
+        let mut server = mockito::Server::new();
+        let _xsrf = server
+            .mock("GET", "/userconsole/xsrf")
+            .with_body(r#"{"xsrf":"xsrf-token"}"#)
+            .create();
+        let _auth = server
+            .mock("POST", "/userconsole/auth")
+            .with_status(403)
+            .create();
+
+        let error = provider(&server)?
+            .login("jdoe@example.com", "wrong")
+            .unwrap_err();
+
+        assert_eq!(error.to_string(), "Unable to login: 403 Forbidden");
+
+        Ok(())
+    }
+}
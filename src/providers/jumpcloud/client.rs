@@ -1,3 +1,5 @@
+use crate::credentials::session::SessionJar;
+
 use anyhow::Result;
 use reqwest::blocking::Client as HttpClient;
 use reqwest::blocking::Response;
@@ -5,17 +7,24 @@ use reqwest::header::{HeaderValue, ACCEPT};
 use reqwest::Url;
 use serde::de::DeserializeOwned;
 use serde::Serialize;
+use std::sync::Arc;
 
 const TOKEN: &str = "X-Xsrftoken";
 
 pub struct Client {
     client: HttpClient,
+    pub jar: Arc<SessionJar>,
 }
 
 impl Client {
     pub fn new() -> Result<Self> {
+        let jar = Arc::new(SessionJar::default());
+
         Ok(Client {
-            client: HttpClient::builder().cookie_store(true).build()?,
+            client: HttpClient::builder()
+                .cookie_provider(Arc::clone(&jar))
+                .build()?,
+            jar,
         })
     }
 
@@ -27,6 +36,22 @@ impl Client {
             .map_err(|e| e.into())
     }
 
+    // Leaves the status to the caller, which reads the MFA factors from the
+    // body of a 401
+    pub fn submit<I>(&self, url: Url, body: &I, token: &str) -> Result<Response>
+    where
+        I: Serialize,
+    {
+        let json = HeaderValue::from_static("application/json");
+        Ok(self
+            .client
+            .post(url)
+            .json(body)
+            .header(ACCEPT, &json)
+            .header(TOKEN, token)
+            .send()?)
+    }
+
     pub fn post<I, O>(&self, url: Url, body: &I, token: &str) -> Result<O, reqwest::Error>
     where
         I: Serialize,
@@ -0,0 +1,377 @@
+use crate::config::app::AppProfile;
+use crate::credentials::config::ConfigCredentials;
+use crate::credentials::Credential;
+use crate::providers::form::client::Client;
+use crate::providers::form::{self, build_form_elements};
+use crate::providers::IdentityProvider;
+use crate::utils;
+
+use anyhow::{anyhow, Context, Result};
+use log::{debug, info};
+use reqwest::blocking::Response;
+use select::document::Document;
+use select::node::Node;
+use select::predicate::{Attr, Class, Name, Predicate};
+
+const LOGIN_FORM_ID: &str = "kc-form-login";
+const OTP_FORM_ID: &str = "kc-otp-login-form";
+const USERNAME_FIELD: &str = "username";
+const PASSWORD_FIELD: &str = "password";
+const OTP_FIELD: &str = "otp";
+const OTP_CREDENTIAL_FIELD: &str = "selectedCredentialId";
+const ERROR_IDS: [&str; 3] = [
+    "input-error",
+    "input-error-otp-code",
+    "input-error-username",
+];
+
+#[derive(PartialEq, Debug)]
+enum ResponseState {
+    Login,
+    Otp,
+    Error,
+}
+
+#[derive(PartialEq, Debug, Clone)]
+struct OtpCredential {
+    id: String,
+    label: String,
+}
+
+pub struct KeycloakProvider {
+    client: Client,
+    profile: AppProfile,
+    prompt_code: fn() -> Result<String>,
+    saml_response: Option<String>,
+}
+
+impl KeycloakProvider {
+    pub fn new(profile: &AppProfile) -> Result<Self> {
+        Ok(KeycloakProvider {
+            client: Client::new()?,
+            profile: profile.clone(),
+            prompt_code: utils::prompt_mfa,
+            saml_response: None,
+        })
+    }
+}
+
+impl IdentityProvider for KeycloakProvider {
+    fn profile(&self) -> &AppProfile {
+        &self.profile
+    }
+
+    fn new_session(&mut self) -> Result<()> {
+        let profile = &self.profile;
+
+        let config_credentials =
+            ConfigCredentials::load(profile).or_else(|_| ConfigCredentials::create(profile))?;
+
+        // The IdP-initiated SSO URL redirects to the realm's login page
+        let response = self
+            .client
+            .get(profile.request_url()?)
+            .with_context(|| "Unable to reach Keycloak login page")?;
+
+        let saml_response =
+            self.complete_login(response, &profile.username, &config_credentials.password)?;
+
+        config_credentials.write(profile)?;
+
+        self.saml_response = Some(saml_response);
+        Ok(())
+    }
+
+    fn fetch_saml_assertion(&self) -> Result<String> {
+        self.saml_response
+            .clone()
+            .with_context(|| "Missing SAML response, no session established")
+    }
+}
+
+impl KeycloakProvider {
+    // Submits the login-actions forms until Keycloak posts the SAML response
+    fn complete_login(&self, response: Response, username: &str, password: &str) -> Result<String> {
+        form::complete_login(&self.client, response, "Keycloak", |page_url, page| {
+            let document = Document::from(page);
+            let state = evaluate_response_state(&document);
+            debug!("Keycloak page {} in state {:?}", page_url, state);
+
+            let (form, form_content) = match state {
+                ResponseState::Login => {
+                    let form = find_form(&document, LOGIN_FORM_ID)?;
+                    let mut form_content = build_form_elements(form.find(Name("input")));
+                    let _ = form_content.insert(USERNAME_FIELD.to_owned(), username.to_owned());
+                    let _ = form_content.insert(PASSWORD_FIELD.to_owned(), password.to_owned());
+                    (form, form_content)
+                }
+                ResponseState::Otp => {
+                    let form = find_form(&document, OTP_FORM_ID)?;
+                    let mut form_content = build_form_elements(form.find(Name("input")));
+                    if let Some(credential) = select_otp_credential(&form)? {
+                        let _ = form_content.insert(OTP_CREDENTIAL_FIELD.to_owned(), credential.id);
+                    }
+                    let _ = form_content.insert(OTP_FIELD.to_owned(), (self.prompt_code)()?);
+                    (form, form_content)
+                }
+                ResponseState::Error => {
+                    return Err(match fetch_error_text(&document) {
+                        Some(error) => anyhow!("Unable to login: {}", error),
+                        None => anyhow!("Unable to login: unexpected Keycloak page"),
+                    })
+                }
+            };
+
+            let submit_url = page_url.join(
+                form.attr("action")
+                    .with_context(|| "Missing submission URL for Keycloak form")?,
+            )?;
+
+            Ok((submit_url, form_content))
+        })
+    }
+}
+
+fn evaluate_response_state(document: &Document) -> ResponseState {
+    // Failed attempts render the same form again, along with the error
+    if fetch_error_text(document).is_some() {
+        return ResponseState::Error;
+    }
+
+    if find_form(document, OTP_FORM_ID).is_ok() {
+        ResponseState::Otp
+    } else if find_form(document, LOGIN_FORM_ID).is_ok() {
+        ResponseState::Login
+    } else {
+        ResponseState::Error
+    }
+}
+
+fn find_form<'a>(document: &'a Document, id: &str) -> Result<Node<'a>> {
+    document
+        .find(Name("form").and(Attr("id", id)))
+        .next()
+        .with_context(|| format!("Missing Keycloak form {}", id))
+}
+
+// Users with several OTP devices pick the one to use via radio buttons
+fn select_otp_credential(form: &Node) -> Result<Option<OtpCredential>> {
+    let credentials = otp_credentials(form);
+
+    let credential = match credentials.len() {
+        0 => None,
+        1 => {
+            info!("Only one OTP device available, using it");
+            Some(credentials[0].clone())
+        }
+        _ => {
+            let labels: Vec<&str> = credentials.iter().map(|c| c.label.as_str()).collect();
+            let selection = dialoguer::Select::new()
+                .with_prompt("Please select the OTP device to use")
+                .items(&labels)
+                .default(0)
+                .interact()?;
+            Some(credentials[selection].clone())
+        }
+    };
+
+    Ok(credential)
+}
+
+fn otp_credentials(form: &Node) -> Vec<OtpCredential> {
+    form.find(
+        Name("input")
+            .and(Attr("type", "radio"))
+            .and(Attr("name", OTP_CREDENTIAL_FIELD)),
+    )
+    .filter_map(|input| {
+        let id = input.attr("value")?.to_owned();
+        let label = input
+            .attr("id")
+            .and_then(|input_id| form.find(Name("label").and(Attr("for", input_id))).next())
+            .map(|label| label.text().trim().to_owned())
+            .filter(|label| !label.is_empty())
+            .unwrap_or_else(|| id.clone());
+
+        Some(OtpCredential { id, label })
+    })
+    .collect()
+}
+
+fn fetch_error_text(document: &Document) -> Option<String> {
+    let field_error = ERROR_IDS
+        .iter()
+        .find_map(|id| document.find(Attr("id", *id)).next());
+    let page_error = || {
+        document
+            .find(Class("alert-error").descendant(Class("kc-feedback-text")))
+            .next()
+    };
+
+    field_error
+        .or_else(page_error)
+        .map(|node| node.text().trim().to_owned())
+        .filter(|text| !text.is_empty())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::saml;
+    use mockito::Matcher;
+    use std::fs;
+
+    fn document(path: &str) -> Result<Document> {
+        Ok(Document::from(fs::read_to_string(path)?.as_str()))
+    }
+
+    #[test]
+    fn evaluates_state_of_fixture_pages() -> Result<()> {
+        let states = vec![
+            (
+                "tests/fixtures/keycloak/login_page.html",
+                ResponseState::Login,
+            ),
+            (
+                "tests/fixtures/keycloak/login_error.html",
+                ResponseState::Error,
+            ),
+            ("tests/fixtures/keycloak/otp_page.html", ResponseState::Otp),
+            (
+                "tests/fixtures/keycloak/otp_select_page.html",
+                ResponseState::Otp,
+            ),
+            (
+                "tests/fixtures/keycloak/otp_error.html",
+                ResponseState::Error,
+            ),
+            (
+                "tests/fixtures/keycloak/required_action.html",
+                ResponseState::Error,
+            ),
+        ];
+
+        for (path, state) in states {
+            assert_eq!(evaluate_response_state(&document(path)?), state, "{}", path);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn fetches_error_text() -> Result<()> {
+        let errors = vec![
+            (
+                "tests/fixtures/keycloak/login_error.html",
+                Some("Invalid username or password."),
+            ),
+            (
+                "tests/fixtures/keycloak/otp_error.html",
+                Some("Invalid authenticator code."),
+            ),
+            (
+                "tests/fixtures/keycloak/required_action.html",
+                Some("You need to change your password to activate your account."),
+            ),
+            ("tests/fixtures/keycloak/login_page.html", None),
+        ];
+
+        for (path, error) in errors {
+            assert_eq!(
+                fetch_error_text(&document(path)?).as_deref(),
+                error,
+                "{}",
+                path
+            );
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn lists_otp_credentials() -> Result<()> {
+        let document = document("tests/fixtures/keycloak/otp_select_page.html")?;
+        let form = find_form(&document, OTP_FORM_ID)?;
+
+        assert_eq!(
+            otp_credentials(&form),
+            vec![
+                OtpCredential {
+                    id: "credential-phone".to_string(),
+                    label: "Phone".to_string(),
+                },
+                OtpCredential {
+                    id: "credential-yubikey".to_string(),
+                    label: "YubiKey".to_string(),
+                },
+            ]
+        );
+        assert_eq!(
+            build_form_elements(form.find(Name("input"))).get(OTP_CREDENTIAL_FIELD),
+            Some(&"credential-phone".to_string())
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn runs_login_with_otp() -> Result<()> {
+        let mut server = mockito::Server::new();
+
+        let mocks = vec![
+            server
+                .mock("GET", "/realms/aws/protocol/saml/clients/amazon-aws")
+                .with_body_from_file("tests/fixtures/keycloak/login_page.html")
+                .create(),
+            server
+                .mock("POST", "/realms/aws/login-actions/authenticate")
+                .match_query(Matcher::UrlEncoded(
+                    "session_code".to_string(),
+                    "login-code".to_string(),
+                ))
+                .match_body(Matcher::AllOf(vec![
+                    Matcher::UrlEncoded("username".to_string(), "jdoe".to_string()),
+                    Matcher::UrlEncoded("password".to_string(), "password".to_string()),
+                ]))
+                .with_body_from_file("tests/fixtures/keycloak/otp_page.html")
+                .create(),
+            server
+                .mock("POST", "/realms/aws/login-actions/authenticate")
+                .match_query(Matcher::UrlEncoded(
+                    "session_code".to_string(),
+                    "otp-code".to_string(),
+                ))
+                .match_body(Matcher::AllOf(vec![
+                    Matcher::UrlEncoded("otp".to_string(), "123456".to_string()),
+                    Matcher::UrlEncoded(
+                        "selectedCredentialId".to_string(),
+                        "credential-phone".to_string(),
+                    ),
+                ]))
+                .with_body_from_file("tests/fixtures/keycloak/saml_response.html")
+                .create(),
+        ];
+
+        let profile: AppProfile = toml::from_str(&format!(
+            r#"
+            name = "profile"
+            provider = "keycloak"
+            url = "{}/realms/aws/protocol/saml/clients/amazon-aws"
+            username = "jdoe"
+        "#,
+            server.url()
+        ))?;
+        let mut provider = KeycloakProvider::new(&profile)?;
+        provider.prompt_code = || Ok("123456".to_string());
+
+        let response = provider.client.get(profile.request_url()?)?;
+        let page = provider.complete_login(response, "jdoe", "password")?;
+
+        assert!(saml::extract_saml_assertion(&page).is_ok());
+        for mock in mocks {
+            mock.assert();
+        }
+
+        Ok(())
+    }
+}
@@ -1,22 +1,35 @@
 pub mod auth;
 pub mod client;
 pub mod factors;
+pub mod idx;
 pub mod login;
 pub mod response;
+pub mod session;
 pub mod verification;
 
 use crate::config::app::AppProfile;
-use crate::credentials::aws::AwsCredentials;
 use crate::credentials::config::ConfigCredentials;
+use crate::credentials::session::IdpSession;
 use crate::credentials::Credential;
 use crate::providers::okta::client::Client;
 use crate::providers::okta::login::LoginRequest;
-use crate::saml;
+use crate::providers::IdentityProvider;
 
 use anyhow::{Context, Result};
 use log::{debug, trace};
+use serde::{Deserialize, Serialize};
 
 const API_AUTHN_PATH: &str = "api/v1/authn";
+const API_SESSION_PATH: &str = "api/v1/sessions/me";
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Pipeline {
+    // Okta calls the classic authentication API "v1"
+    #[serde(alias = "v1")]
+    Classic,
+    Idx,
+}
 
 pub struct OktaProvider {
     client: Client,
@@ -31,46 +44,163 @@ impl OktaProvider {
         })
     }
 
-    pub fn new_session(&mut self) -> Result<&Self> {
+    fn resume(&mut self, session: IdpSession) -> Result<bool> {
+        self.client.jar.restore(&session);
+
+        match self.client.current_session() {
+            Ok(current) => {
+                debug!("Resuming Okta session {}", current.id);
+                Ok(true)
+            }
+            Err(e) => {
+                debug!("Unable to resume Okta session: {}", e);
+                self.client.jar.clear();
+                Ok(false)
+            }
+        }
+    }
+
+    // Okta sets the session cookie while handing out the SAML assertion, so
+    // the session is saved afterwards
+    fn save_session(&self) -> Result<IdpSession> {
+        let current = self.client.current_session()?;
+
+        IdpSession {
+            id: Some(current.id),
+            expires_at: Some(current.expires_at),
+            cookies: self.client.jar.session_cookies(),
+        }
+        .write(&self.profile)
+    }
+}
+
+impl IdentityProvider for OktaProvider {
+    fn profile(&self) -> &AppProfile {
+        &self.profile
+    }
+
+    fn new_session(&mut self) -> Result<()> {
+        // A detected pipeline is kept on the profile, so it is only looked up
+        // when neither the configuration nor an earlier login knows it
+        if self.profile.okta_pipeline.is_none() {
+            match self.client.detect_pipeline() {
+                Ok(pipeline) => self.profile.okta_pipeline = Some(pipeline),
+                Err(e) => debug!("Unable to detect Okta pipeline, using classic: {}", e),
+            }
+        }
+
         let profile = &self.profile;
         let config_credentials =
             ConfigCredentials::load(profile).or_else(|_| ConfigCredentials::create(profile))?;
 
         let username = &profile.username;
         let password = &config_credentials.password;
-        let login_response = self
-            .client
-            .login(&LoginRequest::from_credentials(
-                username.clone(),
-                password.clone(),
-            ))
-            .with_context(|| "Unable to login")?;
+        match profile.okta_pipeline.as_ref().unwrap_or(&Pipeline::Classic) {
+            Pipeline::Classic => {
+                let login_response = self
+                    .client
+                    .login(&LoginRequest::from_credentials(
+                        username.clone(),
+                        password.clone(),
+                    ))
+                    .with_context(|| "Unable to login")?;
 
-        trace!("Login response: {:?}", login_response);
+                trace!("Login response: {:?}", login_response);
 
-        let session_token = self.client.get_session_token(login_response)?;
+                let session_token = self.client.get_session_token(login_response)?;
+                self.client.session_token = Some(session_token);
+            }
+            Pipeline::Idx => self
+                .client
+                .idx_login(profile.request_url()?, username, password)
+                .with_context(|| "Unable to login")?,
+        }
 
         config_credentials.write(profile)?;
 
-        self.client.session_token = Some(session_token);
-        Ok(self)
+        Ok(())
+    }
+
+    fn resume_session(&mut self) -> Result<bool> {
+        match IdpSession::load(&self.profile) {
+            Ok(session) if !session.is_expired() => self.resume(session),
+            _ => Ok(false),
+        }
     }
 
-    pub fn fetch_aws_credentials(&self) -> Result<AwsCredentials> {
+    fn fetch_saml_assertion(&self) -> Result<String> {
         let profile = &self.profile;
-        debug!("Requesting temporary STS credentials for {}", &profile.name);
+        debug!("Requesting SAML assertion for {}", &profile.name);
 
-        let url = profile.clone().request_url().unwrap();
         let input = self
             .client
-            .get(url)
+            .get(profile.request_url()?)
             .with_context(|| format!("Error getting SAML response for profile {}", profile.name))?
             .text()?;
 
         debug!("Text for SAML response: {:#?}", input);
 
-        let credentials = saml::get_credentials_from_saml(input, profile.role.clone())?;
-        trace!("Credentials: {:?}", credentials);
-        Ok(credentials)
+        if let Err(e) = self.save_session() {
+            debug!("Unable to save Okta session: {}", e);
+        }
+
+        Ok(input)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::credentials::session::SessionJar;
+    use reqwest::cookie::CookieStore;
+    use reqwest::header::HeaderValue;
+    use reqwest::Url;
+
+    #[test]
+    fn resumes_active_session() -> Result<()> {
+        let mut server = mockito::Server::new();
+        let active = server
+            .mock("GET", "/api/v1/sessions/me")
+            .match_header("cookie", "sid=live")
+            .with_body(
+                r#"{"id":"102abc","status":"ACTIVE","expiresAt":"2038-01-19T03:14:07.000Z"}"#,
+            )
+            .create();
+        let _expired = server
+            .mock("GET", "/api/v1/sessions/me")
+            .with_status(404)
+            .with_body(r#"{"errorCode":"E0000007","errorSummary":"Not found: Resource not found: me (Session)"}"#)
+            .create();
+
+        let profile: AppProfile = toml::from_str(&format!(
+            r#"
+            name = "profile"
+            provider = "okta"
+            url = "{}/home/amazon_aws/0oa1b2c3d4/272"
+            username = "jdoe"
+        "#,
+            server.url()
+        ))?;
+        let mut provider = OktaProvider::new(&profile)?;
+        let session = |sid: &str| -> Result<IdpSession> {
+            let jar = SessionJar::default();
+            jar.set_cookies(
+                &mut [HeaderValue::from_str(&format!("sid={}; Path=/", sid))?].iter(),
+                &Url::parse(&server.url())?,
+            );
+            Ok(IdpSession {
+                id: Some(sid.to_string()),
+                expires_at: None,
+                cookies: jar.session_cookies(),
+            })
+        };
+
+        assert!(provider.resume(session("live")?)?);
+        active.assert();
+
+        assert!(!provider.resume(session("expired")?)?);
+        assert!(provider.client.jar.session_cookies().is_empty());
+
+        Ok(())
     }
 }
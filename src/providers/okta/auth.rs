@@ -1,8 +1,10 @@
+use crate::duo::{self, DuoChallenge};
 use crate::providers::okta::client::Client;
 use crate::providers::okta::factors::Factor;
 use crate::providers::okta::response::{FactorResult, Links, Response, Status};
 use crate::providers::okta::verification::VerificationRequest;
 use crate::utils;
+use crate::webauthn;
 
 use anyhow::{anyhow, Context, Result};
 use chrono::{DateTime, Utc};
@@ -56,6 +58,21 @@ impl Client {
                             pass_code: mfa_code,
                         }
                     }
+                    Factor::HardwareToken { .. } | Factor::Token { .. } => {
+                        VerificationRequest::Token {
+                            state_token,
+                            pass_code: utils::prompt_mfa()?,
+                        }
+                    }
+                    Factor::Question { ref profile, .. } => VerificationRequest::Question {
+                        state_token,
+                        answer: utils::prompt_answer(&profile.question_text)?,
+                    },
+                    // Okta places the call first and challenges for the code it reads out
+                    Factor::Call { .. } => VerificationRequest::Call {
+                        state_token,
+                        pass_code: None,
+                    },
                     Factor::Push { .. } => VerificationRequest::Push { state_token },
                     Factor::WebAuthn { .. } => VerificationRequest::WebAuthn {
                         state_token,
@@ -63,6 +80,7 @@ impl Client {
                         signature_data: None,
                         client_data: None,
                     },
+                    Factor::Web { .. } => VerificationRequest::Web { state_token },
                     _ => return Err(anyhow!("The selected factor isn't implemented")),
                 };
 
@@ -105,13 +123,18 @@ impl Client {
                             pass_code: Some(mfa_code),
                         }
                     }
+                    Factor::Call { .. } => VerificationRequest::Call {
+                        state_token,
+                        pass_code: Some(utils::prompt_mfa()?),
+                    },
                     Factor::Push { .. } => VerificationRequest::Push { state_token },
-                    // Factor::WebAuthn { .. } => {
-                    //     unimplemented!()
-                    //     let challenge =
-                    //     embedded.expect("Missing embedded challenge for WebAuthn factor");
-                    //     get_webauthn_verification_request(&challenge)?
-                    // }
+                    Factor::WebAuthn { .. } => {
+                        self.get_webauthn_verification_request(&factor, state_token)?
+                    }
+                    Factor::Web { .. } => {
+                        self.verify_duo(&factor, &state_token)?;
+                        VerificationRequest::Web { state_token }
+                    }
                     _ => return Err(anyhow!("Unknown challenge received for MFA type")),
                 };
 
@@ -124,6 +147,9 @@ impl Client {
                     Factor::Push { .. } => {
                         self.poll_for_push_result(&links, &factor_verification_request)?
                     }
+                    Factor::WebAuthn { .. } | Factor::Web { .. } => {
+                        self.poll(&links, &factor_verification_request)?
+                    }
                     _ => self.verify(&factor, &factor_verification_request)?,
                 };
 
@@ -171,9 +197,86 @@ impl Client {
 
         Ok(verification_response)
     }
+
+    fn get_webauthn_verification_request(
+        &self,
+        factor: &Factor,
+        state_token: String,
+    ) -> Result<VerificationRequest> {
+        let (profile, embedded) = match factor {
+            Factor::WebAuthn {
+                ref profile,
+                ref embedded,
+                ..
+            } => (profile, embedded),
+            _ => return Err(anyhow!("Factor is not a WebAuthn factor")),
+        };
+
+        let challenge = embedded
+            .clone()
+            .and_then(|e| e.challenge)
+            .and_then(|c| c.challenge)
+            .with_context(|| "Missing embedded challenge for WebAuthn factor")?;
+
+        let signed = webauthn::sign_challenge(
+            self.authenticator.as_ref(),
+            &challenge,
+            &self.base_url,
+            vec![profile.credential_id.clone()],
+        )?;
+
+        Ok(VerificationRequest::WebAuthn {
+            state_token,
+            authenticator_data: Some(signed.authenticator_data),
+            signature_data: Some(signed.signature_data),
+            client_data: Some(signed.client_data),
+        })
+    }
+
+    // Runs the Duo prompt and hands its signed response to Okta's callback
+    fn verify_duo(&self, factor: &Factor, state_token: &str) -> Result<()> {
+        let (id, embedded) = match factor {
+            Factor::Web {
+                ref id,
+                ref embedded,
+                ..
+            } => (id, embedded),
+            _ => return Err(anyhow!("Factor is not a Duo factor")),
+        };
+
+        let verification = embedded
+            .clone()
+            .and_then(|e| e.verification)
+            .with_context(|| "Missing Duo verification for factor")?;
+        let callback_url = match verification.links.as_ref().and_then(|l| l.get("complete")) {
+            Some(Links::Single(link)) => link.href.clone(),
+            Some(Links::Multi(links)) => links
+                .first()
+                .with_context(|| "Missing Duo callback link")?
+                .href
+                .clone(),
+            None => return Err(anyhow!("Missing Duo callback link")),
+        };
+
+        let challenge = DuoChallenge {
+            host: verification.host,
+            signature: verification.signature,
+            parent: self.base_url.join("signin/verify/duo/web")?.to_string(),
+        };
+        let signed_response = duo::verify(&challenge, &(self.duo_prompt)()?)?;
+
+        self.post_form(
+            callback_url,
+            &[
+                ("id", id.as_str()),
+                ("stateToken", state_token),
+                ("sig_response", signed_response.as_str()),
+            ],
+        )
+    }
 }
 
-fn select_factor(factors: Vec<Factor>) -> Result<Factor> {
+pub fn select_factor(factors: Vec<Factor>) -> Result<Factor> {
     let factor = match factors.len() {
         0 => return Err(anyhow!("MFA required, and no available factors")),
         1 => {
@@ -184,7 +287,7 @@ fn select_factor(factors: Vec<Factor>) -> Result<Factor> {
             eprintln!("Please select the factor to use:");
             let mut menu = dialoguer::Select::new();
             for factor in &factors {
-                menu.item(&factor.to_string());
+                menu.item(factor.to_string());
             }
             factors[menu.interact()?].clone()
         }
@@ -195,13 +298,11 @@ fn select_factor(factors: Vec<Factor>) -> Result<Factor> {
     Ok(factor)
 }
 
-// fn get_webauthn_verification_request(challenge: &FactorChallenge) -> Result<VerificationRequest> {}
-
-fn timeout_not_reached(time: DateTime<Utc>) -> bool {
-    time.signed_duration_since(Utc::now()).num_seconds() < PUSH_WAIT_TIMEOUT
+pub fn timeout_not_reached(started: DateTime<Utc>) -> bool {
+    Utc::now().signed_duration_since(started).num_seconds() < PUSH_WAIT_TIMEOUT
 }
 
-fn filter_factors(factors: Vec<Factor>) -> Vec<Factor> {
+pub fn filter_factors(factors: Vec<Factor>) -> Vec<Factor> {
     factors
         .iter()
         .filter(|f| **f != Factor::Unimplemented)
@@ -229,17 +330,17 @@ fn fetch_correct_push_answer(response: &Response) -> Option<u64> {
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::config::app::AppProfile;
+    use crate::duo::DuoFactor;
     use crate::providers::okta::factors::FactorProvider;
     use crate::providers::okta::factors::{Factor, SmsFactorProfile};
-    use chrono::NaiveDateTime;
+    use crate::webauthn::software::SoftwareAuthenticator;
+    use mockito::Matcher;
     use std::fs;
 
     #[test]
     fn should_reach_timeout() -> Result<()> {
-        let dt = DateTime::<Utc>::from_utc(
-            NaiveDateTime::parse_from_str("2038-01-01T10:10:10", "%Y-%m-%dT%H:%M:%S")?,
-            Utc,
-        );
+        let dt = Utc::now() - chrono::Duration::seconds(PUSH_WAIT_TIMEOUT + 1);
         assert!(!timeout_not_reached(dt));
         Ok(())
     }
@@ -289,4 +390,162 @@ mod test {
 
         Ok(())
     }
+
+    #[test]
+    fn verifies_webauthn_challenge_with_authenticator() -> Result<()> {
+        let mut server = mockito::Server::new();
+        let fixture = fs::read_to_string("tests/fixtures/okta/challenge_response_webauthn.json")?
+            .replace("https://example.okta.com", &server.url());
+        let response = serde_json::de::from_str::<Response>(&fixture)?;
+
+        let mock = server
+            .mock("POST", "/api/v1/authn/factors/factor-id-webauthn/verify")
+            .match_body(Matcher::AllOf(vec![
+                Matcher::PartialJsonString(r#"{"stateToken":"state-token"}"#.to_string()),
+                Matcher::Regex("authenticatorData".to_string()),
+                Matcher::Regex("signatureData".to_string()),
+                Matcher::Regex("clientData".to_string()),
+            ]))
+            .with_body(fs::read_to_string(
+                "tests/fixtures/okta/success_response.json",
+            )?)
+            .create();
+
+        let profile: AppProfile = toml::from_str(&format!(
+            r#"
+            name = "profile"
+            provider = "okta"
+            url = "{}/home/amazon_aws/0oa/272"
+            username = "jdoe@example.com"
+        "#,
+            server.url()
+        ))?;
+        let mut client = Client::new(profile)?;
+        client.authenticator = Box::new(SoftwareAuthenticator::from_secret(
+            "credential-id",
+            &[7u8; 32],
+        )?);
+
+        assert_eq!("session-token", client.get_session_token(response)?);
+        mock.assert();
+
+        Ok(())
+    }
+
+    #[test]
+    fn verifies_duo_challenge_through_callback() -> Result<()> {
+        let (duo_server, duo_mocks) = duo::mock::server();
+        let mut server = mockito::Server::new();
+        let fixture = fs::read_to_string("tests/fixtures/okta/challenge_response_duo.json")?
+            .replace("https://example.okta.com", &server.url())
+            .replace("https://api-duo.example.com", &duo_server.url());
+        let response = serde_json::de::from_str::<Response>(&fixture)?;
+
+        let callback = server
+            .mock(
+                "POST",
+                "/api/v1/authn/factors/factor-id-duo/lifecycle/duoCallback",
+            )
+            .match_body(Matcher::AllOf(vec![
+                Matcher::UrlEncoded("id".to_string(), "factor-id-duo".to_string()),
+                Matcher::UrlEncoded("stateToken".to_string(), "state-token".to_string()),
+                Matcher::UrlEncoded(
+                    "sig_response".to_string(),
+                    duo::mock::SIGNED_RESPONSE.to_string(),
+                ),
+            ]))
+            .create();
+        let poll = server
+            .mock("POST", "/api/v1/authn/factors/factor-id-duo/verify")
+            .match_body(Matcher::JsonString(
+                r#"{"stateToken":"state-token"}"#.to_string(),
+            ))
+            .with_body(fs::read_to_string(
+                "tests/fixtures/okta/success_response.json",
+            )?)
+            .create();
+
+        let profile: AppProfile = toml::from_str(&format!(
+            r#"
+            name = "profile"
+            provider = "okta"
+            url = "{}/home/amazon_aws/0oa/272"
+            username = "jdoe@example.com"
+        "#,
+            server.url()
+        ))?;
+        let mut client = Client::new(profile)?;
+        client.duo_prompt = || Ok(DuoFactor::Push);
+
+        assert_eq!("session-token", client.get_session_token(response)?);
+        callback.assert();
+        poll.assert();
+        for mock in duo_mocks {
+            mock.assert();
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn verifies_question_and_call_factors() -> Result<()> {
+        let mut server = mockito::Server::new();
+        let fixture = fs::read_to_string("tests/fixtures/okta/login_response_token_factors.json")?
+            .replace("https://example.okta.com", &server.url());
+        let factors = serde_json::de::from_str::<Response>(&fixture)?
+            .embedded
+            .unwrap()
+            .factors
+            .unwrap();
+
+        let question = server
+            .mock("POST", "/api/v1/authn/factors/factor-id-question/verify")
+            .match_body(Matcher::JsonString(
+                r#"{"stateToken":"state-token","answer":"Mona Lisa"}"#.to_string(),
+            ))
+            .with_body(fs::read_to_string(
+                "tests/fixtures/okta/success_response.json",
+            )?)
+            .create();
+        let call = server
+            .mock("POST", "/api/v1/authn/factors/factor-id-call/verify")
+            .match_body(Matcher::JsonString(
+                r#"{"stateToken":"state-token"}"#.to_string(),
+            ))
+            .with_body(fs::read_to_string(
+                "tests/fixtures/okta/success_response.json",
+            )?)
+            .create();
+
+        let profile: AppProfile = toml::from_str(&format!(
+            r#"
+            name = "profile"
+            provider = "okta"
+            url = "{}/home/amazon_aws/0oa/272"
+            username = "jdoe@example.com"
+        "#,
+            server.url()
+        ))?;
+        let client = Client::new(profile)?;
+
+        client.verify(
+            &factors[3],
+            &VerificationRequest::Question {
+                state_token: "state-token".to_string(),
+                answer: "Mona Lisa".to_string(),
+            },
+        )?;
+        client.verify(
+            &factors[4],
+            &VerificationRequest::Call {
+                state_token: "state-token".to_string(),
+                pass_code: None,
+            },
+        )?;
+
+        question.assert();
+        call.assert();
+
+        Ok(())
+    }
 }
@@ -1,25 +1,42 @@
 use crate::config::app::AppProfile;
+use crate::credentials::session::SessionJar;
+use crate::duo::{self, DuoFactor};
+use crate::webauthn::hid::HidAuthenticator;
+use crate::webauthn::Authenticator;
 
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 use reqwest::blocking::Client as HttpClient;
 use reqwest::blocking::Response;
 use reqwest::header::{HeaderValue, ACCEPT};
 use reqwest::Url;
 use serde::de::DeserializeOwned;
 use serde::Serialize;
+use std::sync::Arc;
+
+const IDX_ACCEPT: &str = "application/json; okta-version=1.0.0";
 
 pub struct Client {
     client: HttpClient,
+    pub jar: Arc<SessionJar>,
     pub base_url: Url,
     pub session_token: Option<String>,
+    pub authenticator: Box<dyn Authenticator>,
+    pub duo_prompt: fn() -> Result<DuoFactor>,
 }
 
 impl Client {
     pub fn new(profile: AppProfile) -> Result<Client> {
+        let jar = Arc::new(SessionJar::default());
+
         Ok(Client {
-            client: HttpClient::builder().cookie_store(true).build()?,
+            client: HttpClient::builder()
+                .cookie_provider(Arc::clone(&jar))
+                .build()?,
+            jar,
             base_url: profile.base_url()?,
             session_token: None,
+            authenticator: Box::<HidAuthenticator>::default(),
+            duo_prompt: duo::prompt_factor,
         })
     }
 
@@ -34,6 +51,20 @@ impl Client {
             .map_err(|e| e.into())
     }
 
+    // Unlike get, this leaves out the session token and relies on cookies
+    pub fn get_json<O>(&self, url: Url) -> Result<O>
+    where
+        O: DeserializeOwned,
+    {
+        self.client
+            .get(url)
+            .header(ACCEPT, HeaderValue::from_static("application/json"))
+            .send()?
+            .error_for_status()?
+            .json()
+            .map_err(|e| e.into())
+    }
+
     pub fn post<I, O>(&self, url: Url, body: &I) -> Result<O>
     where
         I: Serialize,
@@ -48,4 +79,37 @@ impl Client {
             .json()
             .map_err(|e| e.into())
     }
+
+    pub fn post_form<I>(&self, url: Url, form_content: &I) -> Result<()>
+    where
+        I: Serialize,
+    {
+        self.client
+            .post(url)
+            .form(form_content)
+            .send()?
+            .error_for_status()?;
+
+        Ok(())
+    }
+
+    // Identity Engine answers with a JSON body even for failed remediations,
+    // so we don't bail on the status code here
+    pub fn post_idx<I, O>(&self, url: Url, body: &I) -> Result<O>
+    where
+        I: Serialize,
+        O: DeserializeOwned,
+    {
+        let response = self
+            .client
+            .post(url)
+            .json(body)
+            .header(ACCEPT, HeaderValue::from_static(IDX_ACCEPT))
+            .send()?;
+        let status = response.status();
+
+        response
+            .json()
+            .map_err(|e| anyhow!("Unexpected response from Okta ({}): {}", status, e))
+    }
 }
@@ -37,6 +37,44 @@ pub enum Factor {
         #[serde(rename = "_links")]
         links: Option<HashMap<String, Links>>,
     },
+    // One-time passwords from a YubiKey
+    #[serde(rename = "token:hardware", rename_all = "camelCase")]
+    HardwareToken {
+        id: String,
+        provider: FactorProvider,
+        status: Option<FactorStatus>,
+        profile: TokenFactorProfile,
+        #[serde(rename = "_links")]
+        links: Option<HashMap<String, Links>>,
+    },
+    // RSA SecurID and Symantec VIP tokens
+    #[serde(rename_all = "camelCase")]
+    Token {
+        id: String,
+        provider: FactorProvider,
+        status: Option<FactorStatus>,
+        profile: TokenFactorProfile,
+        #[serde(rename = "_links")]
+        links: Option<HashMap<String, Links>>,
+    },
+    #[serde(rename_all = "camelCase")]
+    Question {
+        id: String,
+        provider: FactorProvider,
+        status: Option<FactorStatus>,
+        profile: QuestionFactorProfile,
+        #[serde(rename = "_links")]
+        links: Option<HashMap<String, Links>>,
+    },
+    #[serde(rename_all = "camelCase")]
+    Call {
+        id: String,
+        provider: FactorProvider,
+        status: Option<FactorStatus>,
+        profile: CallFactorProfile,
+        #[serde(rename = "_links")]
+        links: Option<HashMap<String, Links>>,
+    },
     WebAuthn {
         id: String,
         provider: FactorProvider,
@@ -47,6 +85,17 @@ pub enum Factor {
         #[serde(rename = "_embedded")]
         embedded: Option<FactorEmbedded>,
     },
+    // Duo Security, verified through Duo's frame protocol
+    #[serde(rename_all = "camelCase")]
+    Web {
+        id: String,
+        provider: FactorProvider,
+        status: Option<FactorStatus>,
+        #[serde(rename = "_links")]
+        links: Option<HashMap<String, Links>>,
+        #[serde(rename = "_embedded")]
+        embedded: Option<FactorEmbedded>,
+    },
     #[serde(other)]
     Unimplemented,
 }
@@ -55,6 +104,7 @@ pub enum Factor {
 #[serde(rename_all = "camelCase")]
 pub struct FactorEmbedded {
     pub challenge: Option<FactorChallenge>,
+    pub verification: Option<FactorVerification>,
 }
 
 #[derive(Deserialize, Debug, Clone, PartialEq)]
@@ -64,12 +114,25 @@ pub struct FactorChallenge {
     pub correct_answer: Option<u64>,
 }
 
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct FactorVerification {
+    pub host: String,
+    pub signature: String,
+    #[serde(rename = "_links")]
+    pub links: Option<HashMap<String, Links>>,
+}
+
 #[derive(Deserialize, Debug, Clone, PartialEq)]
 #[serde(rename_all = "SCREAMING_SNAKE_CASE")]
 pub enum FactorProvider {
     Okta,
     Google,
     Fido,
+    Duo,
+    Yubico,
+    Rsa,
+    Symantec,
 }
 
 #[derive(Deserialize, Debug, Clone, PartialEq)]
@@ -91,18 +154,31 @@ pub struct SmsFactorProfile {
 
 #[derive(Deserialize, Debug, Clone, PartialEq)]
 #[serde(rename_all = "camelCase")]
+pub struct CallFactorProfile {
+    pub phone_number: String,
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct QuestionFactorProfile {
+    pub question: String,
+    pub question_text: String,
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq, Default)]
+#[serde(rename_all = "camelCase")]
 pub struct PushFactorProfile {
-    credential_id: String,
-    device_type: String,
-    name: String,
-    platform: String,
-    version: String,
+    pub credential_id: String,
+    pub device_type: String,
+    pub name: String,
+    pub platform: String,
+    pub version: String,
 }
 
 #[derive(Deserialize, Debug, Clone, PartialEq)]
 #[serde(rename_all = "camelCase")]
 pub struct TokenFactorProfile {
-    credential_id: String,
+    pub credential_id: String,
 }
 
 #[derive(Deserialize, Debug, Clone, PartialEq)]
@@ -123,9 +199,30 @@ impl fmt::Display for Factor {
                 ..
             } => write!(f, "Software TOTP"),
             Factor::Totp { .. } => write!(f, "Okta Verify TOTP"),
+            Factor::HardwareToken {
+                provider: FactorProvider::Yubico,
+                ..
+            } => write!(f, "YubiKey OTP"),
+            Factor::HardwareToken { .. } => write!(f, "Hardware token"),
+            Factor::Token {
+                provider: FactorProvider::Rsa,
+                ..
+            } => write!(f, "RSA SecurID"),
+            Factor::Token {
+                provider: FactorProvider::Symantec,
+                ..
+            } => write!(f, "Symantec VIP"),
+            Factor::Token { .. } => write!(f, "Token"),
+            Factor::Question { ref profile, .. } => {
+                write!(f, "Security question: {}", profile.question_text)
+            }
+            Factor::Call { ref profile, .. } => {
+                write!(f, "Okta voice call to {}", profile.phone_number)
+            }
             Factor::WebAuthn { ref profile, .. } => {
                 write!(f, "WebAuthn with {}", profile.authenticator_name)
             }
+            Factor::Web { .. } => write!(f, "Duo Security"),
             _ => write!(f, "Unimplemented factor"),
         }
     }
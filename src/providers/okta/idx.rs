@@ -0,0 +1,570 @@
+pub mod response;
+
+use crate::providers::okta::auth::{self, PUSH_WAIT_TIMEOUT};
+use crate::providers::okta::client::Client;
+use crate::providers::okta::factors::{
+    CallFactorProfile, Factor, FactorProvider, PushFactorProfile, SmsFactorProfile,
+    TokenFactorProfile, WebAuthnFactorProfile,
+};
+use crate::providers::okta::idx::response::{IdxResponse, OptionValue, Organization, Remediation};
+use crate::providers::okta::response::FactorResult;
+use crate::providers::okta::Pipeline;
+use crate::saml;
+use crate::utils;
+use crate::webauthn;
+
+use anyhow::{anyhow, Context, Result};
+use chrono::Utc;
+use log::{debug, trace};
+use regex::Regex;
+use serde_json::{json, Value};
+use std::{thread, time::Duration};
+use url::Url;
+
+const IDX_INTROSPECT_PATH: &str = "idp/idx/introspect";
+const WELL_KNOWN_ORGANIZATION_PATH: &str = ".well-known/okta-organization";
+const MAX_REMEDIATION_STEPS: usize = 20;
+const DEFAULT_POLL_REFRESH: u64 = 4000;
+
+const IDENTIFY: &str = "identify";
+const CHALLENGE_POLL: &str = "challenge-poll";
+const CHALLENGE_AUTHENTICATOR: &str = "challenge-authenticator";
+const SELECT_AUTHENTICATOR: &str = "select-authenticator-authenticate";
+
+const PASSWORD_KEY: &str = "okta_password";
+const WEBAUTHN_KEY: &str = "webauthn";
+
+// An authenticator and method offered by a select-authenticator remediation
+#[derive(Debug, Clone, PartialEq)]
+struct Candidate {
+    id: String,
+    key: String,
+    method: Option<String>,
+    label: String,
+}
+
+impl Candidate {
+    fn factor(&self) -> Factor {
+        let id = self.id.clone();
+        let label = self.label.clone();
+
+        match (self.key.as_str(), self.method.as_deref()) {
+            ("okta_verify", Some("push")) => Factor::Push {
+                id,
+                provider: FactorProvider::Okta,
+                status: None,
+                profile: PushFactorProfile {
+                    name: label,
+                    ..Default::default()
+                },
+                links: None,
+                embedded: None,
+            },
+            ("okta_verify", Some("totp")) => Factor::Totp {
+                id,
+                provider: FactorProvider::Okta,
+                status: None,
+                profile: TokenFactorProfile {
+                    credential_id: label,
+                },
+                links: None,
+            },
+            ("google_otp", _) => Factor::Totp {
+                id,
+                provider: FactorProvider::Google,
+                status: None,
+                profile: TokenFactorProfile {
+                    credential_id: label,
+                },
+                links: None,
+            },
+            ("phone_number", Some("sms")) => Factor::Sms {
+                id,
+                provider: FactorProvider::Okta,
+                status: None,
+                profile: SmsFactorProfile {
+                    phone_number: label,
+                },
+                links: None,
+            },
+            ("phone_number", Some("voice")) => Factor::Call {
+                id,
+                provider: FactorProvider::Okta,
+                status: None,
+                profile: CallFactorProfile {
+                    phone_number: label,
+                },
+                links: None,
+            },
+            ("yubikey_token", _) => Factor::HardwareToken {
+                id,
+                provider: FactorProvider::Yubico,
+                status: None,
+                profile: TokenFactorProfile {
+                    credential_id: label,
+                },
+                links: None,
+            },
+            ("rsa_token", _) => Factor::Token {
+                id,
+                provider: FactorProvider::Rsa,
+                status: None,
+                profile: TokenFactorProfile {
+                    credential_id: label,
+                },
+                links: None,
+            },
+            ("symantec_vip", _) => Factor::Token {
+                id,
+                provider: FactorProvider::Symantec,
+                status: None,
+                profile: TokenFactorProfile {
+                    credential_id: label,
+                },
+                links: None,
+            },
+            (WEBAUTHN_KEY, _) => Factor::WebAuthn {
+                id,
+                provider: FactorProvider::Fido,
+                status: None,
+                profile: WebAuthnFactorProfile {
+                    credential_id: String::new(),
+                    authenticator_name: label,
+                },
+                links: None,
+                embedded: None,
+            },
+            _ => Factor::Unimplemented,
+        }
+    }
+}
+
+impl Client {
+    pub fn detect_pipeline(&self) -> Result<Pipeline> {
+        let url = self.base_url.join(WELL_KNOWN_ORGANIZATION_PATH)?;
+        let organization: Organization = self.get(url)?.json()?;
+
+        debug!("Detected Okta pipeline: {:?}", organization.pipeline);
+
+        Ok(organization.pipeline)
+    }
+
+    // Runs the Identity Engine remediation flow for the app at `app_url` until
+    // Okta hands out a session. The session cookie is kept in the client.
+    pub fn idx_login(&self, app_url: Url, username: &str, password: &str) -> Result<()> {
+        let page = self
+            .get(app_url)
+            .with_context(|| "Unable to reach the Okta application")?
+            .text()?;
+
+        if saml::extract_saml_assertion(&page).is_ok() {
+            debug!("Existing Okta session found, skipping login");
+            return Ok(());
+        }
+
+        let state_token = extract_state_token(&page)?;
+        let mut response: IdxResponse = self.post_idx(
+            self.base_url.join(IDX_INTROSPECT_PATH)?,
+            &json!({ "stateToken": state_token }),
+        )?;
+
+        for _ in 0..MAX_REMEDIATION_STEPS {
+            trace!("IDX response: {:?}", response);
+
+            let errors = response.errors();
+            if !errors.is_empty() {
+                return Err(anyhow!("{}", errors.join(", ")));
+            }
+
+            if let Some(success) = &response.success {
+                self.get(success.href.clone())
+                    .with_context(|| "Unable to establish Okta session")?;
+                eprintln!("Authentication successful!");
+                return Ok(());
+            }
+
+            response = self.idx_remediate(&response, username, password)?;
+        }
+
+        Err(anyhow!(
+            "Okta Identity Engine login did not complete after {} steps",
+            MAX_REMEDIATION_STEPS
+        ))
+    }
+
+    fn idx_remediate(
+        &self,
+        response: &IdxResponse,
+        username: &str,
+        password: &str,
+    ) -> Result<IdxResponse> {
+        let state_handle = response
+            .state_handle
+            .clone()
+            .with_context(|| "Missing state handle in response")?;
+
+        let remediation = [
+            IDENTIFY,
+            CHALLENGE_POLL,
+            CHALLENGE_AUTHENTICATOR,
+            SELECT_AUTHENTICATOR,
+        ]
+        .iter()
+        .find_map(|name| response.remediation(name))
+        .with_context(|| "No supported remediation offered by Okta Identity Engine")?;
+
+        debug!("Remediation: {}", remediation.name);
+
+        let mut body = match remediation.name.as_str() {
+            IDENTIFY => {
+                let mut body = json!({ "identifier": username });
+                if remediation.has_field("credentials") {
+                    body["credentials"] = json!({ "passcode": password });
+                }
+                body
+            }
+            CHALLENGE_AUTHENTICATOR => self.idx_answer_challenge(response, password)?,
+            SELECT_AUTHENTICATOR => select_authenticator(response, remediation)?,
+            _ => return self.idx_poll_for_push_result(response, remediation, &state_handle),
+        };
+
+        body["stateHandle"] = Value::String(state_handle);
+        self.post_idx(remediation.href.clone(), &body)
+    }
+
+    fn idx_answer_challenge(&self, response: &IdxResponse, password: &str) -> Result<Value> {
+        let credentials = match response.current_authenticator_key() {
+            Some(PASSWORD_KEY) => json!({ "passcode": password }),
+            Some(WEBAUTHN_KEY) => {
+                let challenge = response
+                    .current_authenticator
+                    .as_ref()
+                    .and_then(|c| c.value.contextual_data.as_ref())
+                    .and_then(|d| d.challenge_data.as_ref())
+                    .map(|d| d.challenge.clone())
+                    .with_context(|| "Missing challenge for WebAuthn authenticator")?;
+
+                let signed = webauthn::sign_challenge(
+                    self.authenticator.as_ref(),
+                    &challenge,
+                    &self.base_url,
+                    response.webauthn_credential_ids(),
+                )?;
+
+                json!({
+                    "clientData": signed.client_data,
+                    "authenticatorData": signed.authenticator_data,
+                    "signatureData": signed.signature_data,
+                })
+            }
+            _ => json!({ "passcode": utils::prompt_mfa()? }),
+        };
+
+        Ok(json!({ "credentials": credentials }))
+    }
+
+    fn idx_poll_for_push_result(
+        &self,
+        response: &IdxResponse,
+        remediation: &Remediation,
+        state_handle: &str,
+    ) -> Result<IdxResponse> {
+        let time_at_execution = Utc::now();
+        let mut remediation = remediation.clone();
+        let mut answer = response.correct_answer();
+
+        if let Some(a) = answer {
+            eprintln!("The correct answer is: {}", a);
+        }
+        eprintln!("{}", FactorResult::Waiting);
+
+        while auth::timeout_not_reached(time_at_execution) {
+            let refresh = remediation.refresh.unwrap_or(DEFAULT_POLL_REFRESH);
+            thread::sleep(Duration::from_millis(refresh));
+
+            let next: IdxResponse = self.post_idx(
+                remediation.href.clone(),
+                &json!({ "stateHandle": state_handle }),
+            )?;
+
+            if answer.is_none() {
+                answer = next.correct_answer();
+                if let Some(a) = answer {
+                    eprintln!("The correct answer is: {}", a);
+                }
+            }
+
+            match next.remediation(CHALLENGE_POLL) {
+                Some(r) if next.errors().is_empty() && next.success.is_none() => {
+                    remediation = r.clone()
+                }
+                _ => return Ok(next),
+            }
+        }
+
+        Err(anyhow!(
+            "No verification after {} seconds",
+            PUSH_WAIT_TIMEOUT
+        ))
+    }
+}
+
+fn select_authenticator(response: &IdxResponse, remediation: &Remediation) -> Result<Value> {
+    let candidates = authenticator_candidates(response, remediation);
+
+    let selection = match candidates.iter().find(|c| c.key == PASSWORD_KEY) {
+        Some(candidate) => candidate.clone(),
+        None => {
+            let factors =
+                auth::filter_factors(candidates.iter().map(|c| c.factor()).collect::<Vec<_>>());
+            let factor = auth::select_factor(factors)?;
+
+            candidates
+                .into_iter()
+                .find(|c| c.factor() == factor)
+                .with_context(|| "Selected factor is not offered by Okta")?
+        }
+    };
+
+    let mut authenticator = json!({ "id": selection.id });
+    if let Some(method) = selection.method {
+        authenticator["methodType"] = Value::String(method);
+    }
+
+    Ok(json!({ "authenticator": authenticator }))
+}
+
+fn authenticator_candidates(response: &IdxResponse, remediation: &Remediation) -> Vec<Candidate> {
+    let options = remediation
+        .field("authenticator")
+        .and_then(|f| f.options.clone())
+        .unwrap_or_default();
+
+    let mut candidates = vec![];
+
+    for option in options {
+        let id = match option
+            .form_value("id")
+            .and_then(|v| v.value.as_ref())
+            .and_then(|v| v.as_str())
+        {
+            Some(id) => id.to_string(),
+            None => continue,
+        };
+        let key = response
+            .authenticator_key(&id)
+            .unwrap_or_default()
+            .to_string();
+        let method_type = option.form_value("methodType");
+
+        let methods = match method_type {
+            Some(m) => match (&m.value, &m.options) {
+                (Some(Value::String(v)), _) => vec![Some(v.clone())],
+                (_, Some(options)) => options
+                    .iter()
+                    .filter_map(|o| match &o.value {
+                        OptionValue::Plain(v) => Some(Some(v.clone())),
+                        _ => None,
+                    })
+                    .collect(),
+                _ => vec![None],
+            },
+            None => vec![None],
+        };
+
+        for method in methods {
+            candidates.push(Candidate {
+                id: id.clone(),
+                key: key.clone(),
+                method,
+                label: option.label.clone(),
+            });
+        }
+    }
+
+    candidates
+}
+
+fn extract_state_token(page: &str) -> Result<String> {
+    let token = Regex::new(r#"var stateToken = ['"]([^'"]+)['"]"#)?
+        .captures(page)
+        .and_then(|c| c.get(1))
+        .map(|m| m.as_str().to_string())
+        .with_context(|| "Unable to find state token on Okta login page")?;
+
+    // The sign-in page escapes the token as a JavaScript string literal
+    let escaped = Regex::new(r"\\x([0-9A-Fa-f]{2})")?;
+    Ok(escaped
+        .replace_all(&token, |c: &regex::Captures| {
+            u8::from_str_radix(&c[1], 16)
+                .map(|b| (b as char).to_string())
+                .unwrap_or_default()
+        })
+        .into_owned())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::config::app::AppProfile;
+    use mockito::{Matcher, Server};
+    use std::fs;
+
+    #[test]
+    fn extracts_escaped_state_token() -> Result<()> {
+        let page = r#"<script>var stateToken = '02\x2DabcDEF\x2D123';</script>"#;
+        assert_eq!("02-abcDEF-123", extract_state_token(page)?);
+        Ok(())
+    }
+
+    #[test]
+    fn maps_authenticator_options_to_factors() -> Result<()> {
+        let response = fixture(
+            "tests/fixtures/okta/idx/select_authenticator.json",
+            "https://example.okta.com",
+        )?;
+        let remediation = response.remediation(SELECT_AUTHENTICATOR).unwrap();
+        let factors = authenticator_candidates(&response, remediation)
+            .iter()
+            .map(|c| c.factor())
+            .collect::<Vec<Factor>>();
+
+        assert_eq!(4, factors.len());
+        assert!(matches!(factors[0], Factor::Push { .. }));
+        assert!(matches!(factors[1], Factor::Totp { .. }));
+        assert!(matches!(factors[2], Factor::Sms { .. }));
+        assert_eq!(Factor::Unimplemented, factors[3]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn runs_identity_engine_flow_with_push() -> Result<()> {
+        let mut server = Server::new();
+        let url = server.url();
+
+        let mocks = vec![
+            server
+                .mock("GET", "/home/amazon_aws/0oa/272")
+                .with_body_from_file("tests/fixtures/okta/idx/app_page.html")
+                .create(),
+            server
+                .mock("POST", "/idp/idx/introspect")
+                .match_body(Matcher::PartialJsonString(
+                    r#"{"stateToken":"02-state-token"}"#.to_string(),
+                ))
+                .with_body(fixture_text("tests/fixtures/okta/idx/identify.json", &url)?)
+                .create(),
+            server
+                .mock("POST", "/idp/idx/identify")
+                .match_body(Matcher::PartialJsonString(
+                    r#"{"identifier":"jdoe@example.com","stateHandle":"state-handle"}"#.to_string(),
+                ))
+                .with_body(fixture_text(
+                    "tests/fixtures/okta/idx/challenge_password.json",
+                    &url,
+                )?)
+                .create(),
+            server
+                .mock("POST", "/idp/idx/challenge/answer")
+                .match_body(Matcher::PartialJsonString(
+                    r#"{"credentials":{"passcode":"password"}}"#.to_string(),
+                ))
+                .with_body(fixture_text(
+                    "tests/fixtures/okta/idx/select_authenticator_push.json",
+                    &url,
+                )?)
+                .create(),
+            server
+                .mock("POST", "/idp/idx/challenge")
+                .match_body(Matcher::PartialJsonString(
+                    r#"{"authenticator":{"id":"aut-okta-verify","methodType":"push"}}"#.to_string(),
+                ))
+                .with_body(fixture_text(
+                    "tests/fixtures/okta/idx/challenge_poll.json",
+                    &url,
+                )?)
+                .create(),
+            server
+                .mock("POST", "/idp/idx/authenticators/poll")
+                .with_body(fixture_text("tests/fixtures/okta/idx/success.json", &url)?)
+                .create(),
+            server
+                .mock("GET", "/login/token/redirect")
+                .match_query(Matcher::UrlEncoded(
+                    "stateToken".to_string(),
+                    "02-state-token".to_string(),
+                ))
+                .with_body("<html></html>")
+                .create(),
+        ];
+
+        let client = Client::new(profile(&url)?)?;
+        client.idx_login(
+            Url::parse(&format!("{}/home/amazon_aws/0oa/272", url))?,
+            "jdoe@example.com",
+            "password",
+        )?;
+
+        for mock in mocks {
+            mock.assert();
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn fails_on_error_messages() -> Result<()> {
+        let mut server = Server::new();
+        let url = server.url();
+
+        let _app = server
+            .mock("GET", "/home/amazon_aws/0oa/272")
+            .with_body_from_file("tests/fixtures/okta/idx/app_page.html")
+            .create();
+        let _introspect = server
+            .mock("POST", "/idp/idx/introspect")
+            .with_body(fixture_text("tests/fixtures/okta/idx/identify.json", &url)?)
+            .create();
+        let _identify = server
+            .mock("POST", "/idp/idx/identify")
+            .with_status(401)
+            .with_body(fixture_text("tests/fixtures/okta/idx/error.json", &url)?)
+            .create();
+
+        let client = Client::new(profile(&url)?)?;
+        let result = client.idx_login(
+            Url::parse(&format!("{}/home/amazon_aws/0oa/272", url))?,
+            "jdoe@example.com",
+            "password",
+        );
+
+        assert_eq!(
+            "You do not have permission to perform the requested action.",
+            result.unwrap_err().to_string()
+        );
+
+        Ok(())
+    }
+
+    fn profile(url: &str) -> Result<AppProfile> {
+        toml::from_str(&format!(
+            r#"
+            name = "profile"
+            provider = "okta"
+            url = "{}/home/amazon_aws/0oa/272"
+            username = "jdoe@example.com"
+        "#,
+            url
+        ))
+        .map_err(|e| e.into())
+    }
+
+    fn fixture_text(path: &str, url: &str) -> Result<String> {
+        Ok(fs::read_to_string(path)?.replace("https://example.okta.com", url))
+    }
+
+    fn fixture(path: &str, url: &str) -> Result<IdxResponse> {
+        serde_json::from_str(&fixture_text(path, url)?).map_err(|e| e.into())
+    }
+}
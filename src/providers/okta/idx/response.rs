@@ -0,0 +1,192 @@
+use crate::providers::okta::Pipeline;
+
+use serde::Deserialize;
+use serde_json::Value;
+use url::Url;
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct IdxResponse {
+    pub state_handle: Option<String>,
+    pub remediation: Option<Collection<Remediation>>,
+    pub current_authenticator: Option<Single<CurrentAuthenticator>>,
+    pub authenticators: Option<Collection<IdxAuthenticator>>,
+    pub authenticator_enrollments: Option<Collection<Enrollment>>,
+    pub messages: Option<Collection<Message>>,
+    pub success: Option<Success>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct Collection<T> {
+    #[serde(default = "Vec::new")]
+    pub value: Vec<T>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct Single<T> {
+    pub value: T,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct Remediation {
+    pub name: String,
+    #[serde(with = "serde_str")]
+    pub href: Url,
+    #[serde(default)]
+    pub value: Vec<FormValue>,
+    // Milliseconds to wait between polls
+    pub refresh: Option<u64>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct FormValue {
+    pub name: String,
+    pub value: Option<Value>,
+    pub options: Option<Vec<FormOption>>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct Form {
+    pub value: Vec<FormValue>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct FormOption {
+    pub label: String,
+    pub value: OptionValue,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(untagged)]
+pub enum OptionValue {
+    Form { form: Form },
+    Plain(String),
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct IdxAuthenticator {
+    pub id: String,
+    pub key: String,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct CurrentAuthenticator {
+    pub key: String,
+    pub contextual_data: Option<ContextualData>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct ContextualData {
+    pub correct_answer: Option<u64>,
+    pub challenge_data: Option<ChallengeData>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct ChallengeData {
+    pub challenge: String,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct Enrollment {
+    pub key: String,
+    pub credential_id: Option<String>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct Message {
+    pub message: String,
+    pub class: Option<String>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct Success {
+    #[serde(with = "serde_str")]
+    pub href: Url,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct Organization {
+    pub pipeline: Pipeline,
+}
+
+impl IdxResponse {
+    pub fn remediation(&self, name: &str) -> Option<&Remediation> {
+        self.remediation
+            .as_ref()
+            .and_then(|r| r.value.iter().find(|r| r.name == name))
+    }
+
+    pub fn errors(&self) -> Vec<String> {
+        match &self.messages {
+            Some(messages) => messages
+                .value
+                .iter()
+                .filter(|m| m.class.as_deref() == Some("ERROR"))
+                .map(|m| m.message.clone())
+                .collect(),
+            None => vec![],
+        }
+    }
+
+    pub fn current_authenticator_key(&self) -> Option<&str> {
+        self.current_authenticator
+            .as_ref()
+            .map(|c| c.value.key.as_str())
+    }
+
+    pub fn correct_answer(&self) -> Option<u64> {
+        self.current_authenticator
+            .as_ref()
+            .and_then(|c| c.value.contextual_data.as_ref())
+            .and_then(|d| d.correct_answer)
+    }
+
+    pub fn authenticator_key(&self, id: &str) -> Option<&str> {
+        self.authenticators
+            .as_ref()
+            .and_then(|a| a.value.iter().find(|a| a.id == id))
+            .map(|a| a.key.as_str())
+    }
+
+    pub fn webauthn_credential_ids(&self) -> Vec<String> {
+        match &self.authenticator_enrollments {
+            Some(enrollments) => enrollments
+                .value
+                .iter()
+                .filter(|e| e.key == "webauthn")
+                .filter_map(|e| e.credential_id.clone())
+                .collect(),
+            None => vec![],
+        }
+    }
+}
+
+impl Remediation {
+    pub fn has_field(&self, name: &str) -> bool {
+        self.value.iter().any(|v| v.name == name)
+    }
+
+    pub fn field(&self, name: &str) -> Option<&FormValue> {
+        self.value.iter().find(|v| v.name == name)
+    }
+}
+
+impl FormOption {
+    // Returns the value of a named field inside the option's form
+    pub fn form_value(&self, name: &str) -> Option<&FormValue> {
+        match &self.value {
+            OptionValue::Form { form } => form.value.iter().find(|v| v.name == name),
+            OptionValue::Plain(_) => None,
+        }
+    }
+}
@@ -52,14 +52,14 @@ pub enum FactorResult {
 impl fmt::Display for FactorResult {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match *self {
-            FactorResult::Waiting { .. } | FactorResult::Challenge => {
+            FactorResult::Waiting | FactorResult::Challenge => {
                 write!(f, "Waiting for confirmation")
             }
             FactorResult::Timeout => {
                 write!(f, "No verification after {} seconds", PUSH_WAIT_TIMEOUT)
             }
-            FactorResult::Rejected { .. } => write!(f, "Verification challenge was rejected"),
-            FactorResult::Success { .. } => write!(f, "Verification challenge was successful"),
+            FactorResult::Rejected => write!(f, "Verification challenge was rejected"),
+            FactorResult::Success => write!(f, "Verification challenge was successful"),
         }
     }
 }
@@ -183,4 +183,30 @@ mod test {
         assert!(response.is_ok());
         Ok(())
     }
+
+    #[test]
+    fn parses_login_response_with_token_factors() -> Result<()> {
+        let response = serde_json::de::from_str::<Response>(&fs::read_to_string(
+            "tests/fixtures/okta/login_response_token_factors.json",
+        )?)?;
+
+        let factors = response.embedded.unwrap().factors.unwrap();
+        let names = factors
+            .iter()
+            .map(|f| f.to_string())
+            .collect::<Vec<String>>();
+
+        assert_eq!(
+            names,
+            vec![
+                "YubiKey OTP",
+                "RSA SecurID",
+                "Symantec VIP",
+                "Security question: What is your favorite piece of art?",
+                "Okta voice call to +1 XXX-XXX-1337",
+            ]
+        );
+
+        Ok(())
+    }
 }
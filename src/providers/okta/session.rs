@@ -0,0 +1,37 @@
+use crate::providers::okta::client::Client;
+use crate::providers::okta::API_SESSION_PATH;
+
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, Utc};
+use serde::Deserialize;
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct Session {
+    pub id: String,
+    pub expires_at: DateTime<Utc>,
+    pub status: SessionStatus,
+}
+
+#[derive(Deserialize, Debug, PartialEq)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum SessionStatus {
+    Active,
+    MfaRequired,
+    #[serde(other)]
+    Unknown,
+}
+
+impl Client {
+    // Returns the session the cookies of the client belong to, failing when
+    // Okta doesn't consider it active anymore
+    pub fn current_session(&self) -> Result<Session> {
+        let url = self.base_url.join(API_SESSION_PATH)?;
+        let session: Session = self.get_json(url)?;
+
+        match session.status {
+            SessionStatus::Active => Ok(session),
+            _ => Err(anyhow!("Okta session {} is not active", session.id)),
+        }
+    }
+}
@@ -16,12 +16,26 @@ pub enum VerificationRequest {
         pass_code: Option<String>,
     },
     #[serde(rename_all = "camelCase")]
+    Call {
+        state_token: String,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pass_code: Option<String>,
+    },
+    #[serde(rename_all = "camelCase")]
     Push { state_token: String },
     #[serde(rename_all = "camelCase")]
     Totp {
         state_token: String,
         pass_code: String,
     },
+    // Hardware and RSA/Symantec tokens
+    #[serde(rename_all = "camelCase")]
+    Token {
+        state_token: String,
+        pass_code: String,
+    },
+    #[serde(rename_all = "camelCase")]
+    Question { state_token: String, answer: String },
     #[serde(rename_all = "camelCase")]
     WebAuthn {
         state_token: String,
@@ -32,6 +46,8 @@ pub enum VerificationRequest {
         #[serde(skip_serializing_if = "Option::is_none")]
         client_data: Option<String>,
     },
+    #[serde(rename_all = "camelCase")]
+    Web { state_token: String },
 }
 
 impl Client {
@@ -40,7 +56,12 @@ impl Client {
             Factor::Sms { ref links, .. }
             | Factor::Totp { ref links, .. }
             | Factor::Push { ref links, .. }
-            | Factor::WebAuthn { ref links, .. } => {
+            | Factor::HardwareToken { ref links, .. }
+            | Factor::Token { ref links, .. }
+            | Factor::Question { ref links, .. }
+            | Factor::Call { ref links, .. }
+            | Factor::WebAuthn { ref links, .. }
+            | Factor::Web { ref links, .. } => {
                 if let Some(l) = links {
                     let url = match l.get("verify").unwrap() {
                         Links::Single(ref link) => link.href.clone(),
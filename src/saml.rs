@@ -1,76 +1,506 @@
-use crate::aws::role as RoleManager;
 use crate::aws::role::Role;
+use crate::aws::sts::{self, StsClient};
+use crate::config::app::AppProfile;
 use crate::credentials::aws::AwsCredentials;
+use crate::credentials::store;
+use crate::credentials::{Credential, CredentialType};
 use crate::utils;
 
 use anyhow::{anyhow, Context as AnyhowContext, Result};
 use base64::{engine::general_purpose::STANDARD as b64, Engine as _};
-use log::{debug, trace};
+use chrono::{DateTime, Duration, Utc};
+use log::{debug, trace, warn};
 use select::document::Document;
 use select::predicate::Attr;
-use std::collections::HashSet;
+use sha2::Digest;
+use std::collections::{BTreeMap, HashSet};
+use std::fmt;
 use std::str::FromStr;
 use sxd_document::parser;
+use sxd_xpath::nodeset::Node;
 use sxd_xpath::{Context, Factory, Value};
 
+const AWS_ATTRIBUTE_PREFIX: &str = "https://aws.amazon.com/SAML/Attributes/";
+const ROLE_ATTRIBUTE: &str = "Role";
+const ROLE_SESSION_NAME_ATTRIBUTE: &str = "RoleSessionName";
+const SESSION_DURATION_ATTRIBUTE: &str = "SessionDuration";
+const SOURCE_IDENTITY_ATTRIBUTE: &str = "SourceIdentity";
+const PRINCIPAL_TAG_ATTRIBUTE: &str = "PrincipalTag:";
+const TRANSITIVE_TAG_KEYS_ATTRIBUTE: &str = "TransitiveTagKeys";
+const ASSERTION_NS: &str = "urn:oasis:names:tc:SAML:2.0:assertion";
+const ASSERTION_PREFIX: &str = "saml";
+const PROTOCOL_NS: &str = "urn:oasis:names:tc:SAML:2.0:protocol";
+const PROTOCOL_PREFIX: &str = "samlp";
+const AWS_AUDIENCE: &str = "urn:amazon:webservices";
+// Tolerated difference between the clocks of the IdP and this machine
+const CLOCK_SKEW_SECONDS: i64 = 120;
+
+mod signature;
+
 #[derive(PartialEq, Debug)]
+pub enum SamlError {
+    InvalidEncoding(String),
+    InvalidXml(String),
+    MissingResponse,
+    MissingAssertion,
+    MultipleAssertions,
+    EncryptedAssertion,
+    InvalidRole(String),
+    InvalidTimestamp(String),
+    NotYetValid(DateTime<Utc>),
+    Expired(DateTime<Utc>),
+    InvalidAudience(Vec<String>),
+    MissingSignature,
+    InvalidSignature(String),
+    InvalidCertificate(String),
+    UnsupportedAlgorithm(String),
+}
+
+impl fmt::Display for SamlError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SamlError::InvalidEncoding(e) => write!(f, "SAML response is not valid base64: {}", e),
+            SamlError::InvalidXml(e) => write!(f, "SAML response is not valid XML: {}", e),
+            SamlError::MissingResponse => write!(f, "Could not find SAML element in HTML response"),
+            SamlError::MissingAssertion => write!(f, "SAML response carries no assertion"),
+            SamlError::MultipleAssertions => {
+                write!(f, "SAML response carries more than one assertion")
+            }
+            SamlError::EncryptedAssertion => write!(
+                f,
+                "SAML assertion is encrypted and can't be read. Please ask your IdP administrator to disable assertion encryption for the AWS app"
+            ),
+            SamlError::InvalidRole(e) => write!(f, "Invalid role in SAML assertion: {}", e),
+            SamlError::InvalidTimestamp(t) => write!(f, "Invalid timestamp in SAML assertion: {}", t),
+            SamlError::NotYetValid(t) => write!(
+                f,
+                "SAML assertion is not valid before {}, please check the clock of this machine",
+                t.to_rfc3339()
+            ),
+            SamlError::Expired(t) => write!(
+                f,
+                "SAML assertion expired at {}, please log in again",
+                t.to_rfc3339()
+            ),
+            SamlError::InvalidAudience(a) if a.is_empty() => write!(
+                f,
+                "SAML assertion names no audience, AWS expects {}",
+                AWS_AUDIENCE
+            ),
+            SamlError::InvalidAudience(a) => write!(
+                f,
+                "SAML assertion is meant for {} instead of AWS ({})",
+                a.join(", "),
+                AWS_AUDIENCE
+            ),
+            SamlError::MissingSignature => write!(
+                f,
+                "SAML assertion is not signed although an IdP certificate is pinned in the profile"
+            ),
+            SamlError::InvalidSignature(e) => {
+                write!(f, "Invalid signature on SAML assertion: {}", e)
+            }
+            SamlError::InvalidCertificate(e) => {
+                write!(f, "Pinned IdP certificate can't be read: {}", e)
+            }
+            SamlError::UnsupportedAlgorithm(a) => {
+                write!(f, "SAML assertion is signed with unsupported algorithm {}", a)
+            }
+        }
+    }
+}
+
+impl std::error::Error for SamlError {}
+
+#[derive(PartialEq, Debug, Clone)]
 pub struct Response {
     pub raw: String,
     pub roles: HashSet<Role>,
+    pub issuer: Option<String>,
+    pub audiences: Vec<String>,
+    // The assertion can't be used before this point in time
+    pub not_before: Option<DateTime<Utc>>,
+    // The assertion has to be used before this point in time
+    pub not_on_or_after: Option<DateTime<Utc>>,
+    pub role_session_name: Option<String>,
+    // The longest session in seconds the IdP allows for the roles
+    pub session_duration: Option<i32>,
+    pub source_identity: Option<String>,
+    pub principal_tags: BTreeMap<String, String>,
+    pub transitive_tag_keys: Vec<String>,
 }
 
 impl FromStr for Response {
     type Err = anyhow::Error;
 
     fn from_str(s: &str) -> Result<Self> {
-        let decoded_saml = String::from_utf8(b64.decode(s)?)?;
+        let decoded = b64
+            .decode(s)
+            .map_err(|e| SamlError::InvalidEncoding(e.to_string()))?;
+        let decoded_saml =
+            String::from_utf8(decoded).map_err(|e| SamlError::InvalidEncoding(e.to_string()))?;
 
         trace!("SAML: {}", s);
 
-        let package = parser::parse(&decoded_saml).expect("Failed parsing xml");
+        let package =
+            parser::parse(&decoded_saml).map_err(|e| SamlError::InvalidXml(e.to_string()))?;
         let document = package.as_document();
-
-        let xpath = Factory::new()
-            .build("//saml2:Attribute[@Name='https://aws.amazon.com/SAML/Attributes/Role']/saml2:AttributeValue")?
-            .with_context(|| "No XPath was compiled")?;
-
         let mut context = Context::new();
-        context.set_namespace("saml2", "urn:oasis:names:tc:SAML:2.0:assertion");
+        context.set_namespace(ASSERTION_PREFIX, ASSERTION_NS);
+        context.set_namespace(PROTOCOL_PREFIX, PROTOCOL_NS);
+
+        // Everything is read from within the one assertion, which is what a
+        // signature has to cover, and elements are matched by namespace, so
+        // the prefixes IdPs bind it to don't matter
+        let root = document.root().into();
+        let assertion = match select(root, &context, "//saml:Assertion")?.as_slice() {
+            [assertion] => *assertion,
+            [] => {
+                return Err(
+                    if select(root, &context, "//saml:EncryptedAssertion")?.is_empty() {
+                        SamlError::MissingAssertion
+                    } else {
+                        SamlError::EncryptedAssertion
+                    }
+                    .into(),
+                )
+            }
+            _ => return Err(SamlError::MultipleAssertions.into()),
+        };
 
-        let roles = match xpath.evaluate(&context, document.root())? {
-            Value::Nodeset(ns) => ns
+        // Names are compared here rather than in an XPath, so they don't have
+        // to be quoted
+        let mut attributes = vec![];
+        for node in select(
+            assertion,
+            &context,
+            "saml:AttributeStatement/saml:Attribute",
+        )? {
+            if let Some(name) = evaluate(node, &context, "@Name")?.into_iter().next() {
+                attributes.push((name, evaluate(node, &context, "saml:AttributeValue")?));
+            }
+        }
+        let attribute = |name: &str| -> Result<Vec<String>> {
+            Ok(attributes
                 .iter()
-                .map(|a| a.string_value().parse())
-                .collect::<Result<HashSet<Role>, anyhow::Error>>()?,
-            _ => HashSet::new(),
+                .filter(|(n, _)| {
+                    n.strip_prefix(AWS_ATTRIBUTE_PREFIX)
+                        .is_some_and(|n| n == name)
+                })
+                .flat_map(|(_, values)| values.clone())
+                .collect())
+        };
+        let first = |xpaths: &[&str]| -> Result<Option<String>> {
+            for xpath in xpaths {
+                if let Some(value) = evaluate(assertion, &context, xpath)?.into_iter().next() {
+                    return Ok(Some(value.trim().to_owned()));
+                }
+            }
+            Ok(None)
+        };
+
+        let roles = attribute(ROLE_ATTRIBUTE)?
+            .iter()
+            .map(|v| {
+                v.parse()
+                    .map_err(|e: anyhow::Error| SamlError::InvalidRole(e.to_string()))
+            })
+            .collect::<Result<HashSet<Role>, SamlError>>()?;
+
+        let mut principal_tags = BTreeMap::new();
+        for (name, values) in &attributes {
+            if let Some(key) = name
+                .strip_prefix(AWS_ATTRIBUTE_PREFIX)
+                .and_then(|n| n.strip_prefix(PRINCIPAL_TAG_ATTRIBUTE))
+            {
+                if let Some(value) = values.first() {
+                    let _ = principal_tags
+                        .entry(key.to_owned())
+                        .or_insert_with(|| value.trim().to_owned());
+                }
+            }
+        }
+
+        let timestamp = |t: Option<String>| {
+            t.map(|t| {
+                DateTime::parse_from_rfc3339(&t)
+                    .map(|t| t.with_timezone(&Utc))
+                    .map_err(|_| SamlError::InvalidTimestamp(t))
+            })
+            .transpose()
         };
 
+        let not_before = timestamp(first(&["saml:Conditions/@NotBefore"])?)?;
+        let not_on_or_after = timestamp(first(&[
+            "saml:Conditions/@NotOnOrAfter",
+            "saml:Subject/saml:SubjectConfirmation/saml:SubjectConfirmationData/@NotOnOrAfter",
+        ])?)?;
+
         Ok(Response {
             raw: s.to_owned(),
             roles,
+            issuer: first(&["saml:Issuer", "/samlp:Response/saml:Issuer"])?,
+            audiences: evaluate(
+                assertion,
+                &context,
+                "saml:Conditions/saml:AudienceRestriction/saml:Audience",
+            )?
+            .iter()
+            .map(|a| a.trim().to_owned())
+            .collect(),
+            not_before,
+            not_on_or_after,
+            role_session_name: attribute(ROLE_SESSION_NAME_ATTRIBUTE)?
+                .first()
+                .map(|v| v.trim().to_owned()),
+            session_duration: attribute(SESSION_DURATION_ATTRIBUTE)?
+                .first()
+                .and_then(|v| v.trim().parse().ok()),
+            source_identity: attribute(SOURCE_IDENTITY_ATTRIBUTE)?
+                .first()
+                .map(|v| v.trim().to_owned()),
+            principal_tags,
+            transitive_tag_keys: attribute(TRANSITIVE_TAG_KEYS_ATTRIBUTE)?
+                .iter()
+                .map(|v| v.trim().to_owned())
+                .collect(),
         })
     }
 }
 
-pub fn get_credentials_from_saml(input: String, role: Option<String>) -> Result<AwsCredentials> {
+impl Response {
+    // Checks the assertion is meant for AWS and currently valid, and if a
+    // certificate is given, that the IdP holding it signed the assertion
+    pub fn validate(&self, now: DateTime<Utc>, certificate: Option<&str>) -> Result<(), SamlError> {
+        let skew = Duration::seconds(CLOCK_SKEW_SECONDS);
+
+        if let Some(not_before) = self.not_before.filter(|t| now + skew < *t) {
+            return Err(SamlError::NotYetValid(not_before));
+        }
+        if let Some(not_on_or_after) = self.not_on_or_after.filter(|t| now - skew >= *t) {
+            return Err(SamlError::Expired(not_on_or_after));
+        }
+        if !self.audiences.iter().any(|a| is_aws_audience(a)) {
+            return Err(SamlError::InvalidAudience(self.audiences.clone()));
+        }
+
+        if let Some(certificate) = certificate {
+            let decoded = b64
+                .decode(&self.raw)
+                .map_err(|e| SamlError::InvalidEncoding(e.to_string()))?;
+            let xml = String::from_utf8(decoded)
+                .map_err(|e| SamlError::InvalidEncoding(e.to_string()))?;
+
+            signature::verify(&xml, &signature::decode_certificate(certificate)?)?;
+        }
+
+        Ok(())
+    }
+}
+
+// AWS is addressed as urn:amazon:webservices, or with a partition or region
+// appended, e.g. urn:amazon:webservices:cn-north-1
+fn is_aws_audience(audience: &str) -> bool {
+    match audience.strip_prefix(AWS_AUDIENCE) {
+        Some("") => true,
+        Some(suffix) => suffix.strip_prefix(':').is_some_and(|region| {
+            !region.is_empty()
+                && region
+                    .chars()
+                    .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-')
+        }),
+        None => false,
+    }
+}
+
+impl fmt::Display for Response {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let optional = |value: &Option<String>| value.clone().unwrap_or_else(|| "-".to_string());
+
+        writeln!(f, "Issuer:              {}", optional(&self.issuer))?;
+        writeln!(
+            f,
+            "Not on or after:     {}",
+            optional(&self.not_on_or_after.map(|t| t.to_rfc3339()))
+        )?;
+        writeln!(
+            f,
+            "Role session name:   {}",
+            optional(&self.role_session_name)
+        )?;
+        writeln!(
+            f,
+            "Session duration:    {}",
+            optional(&self.session_duration.map(|d| format!("{} seconds", d)))
+        )?;
+        writeln!(
+            f,
+            "Source identity:     {}",
+            optional(&self.source_identity)
+        )?;
+
+        let mut roles = self.roles.iter().collect::<Vec<&Role>>();
+        roles.sort_by(|a, b| a.role_arn.cmp(&b.role_arn));
+        writeln!(f, "Roles:")?;
+        for role in roles {
+            writeln!(f, "  {} via {}", role.role_arn, role.provider_arn)?;
+        }
+
+        writeln!(f, "Principal tags:")?;
+        for (key, value) in &self.principal_tags {
+            writeln!(f, "  {} = {}", key, value)?;
+        }
+
+        write!(
+            f,
+            "Transitive tag keys: {}",
+            match self.transitive_tag_keys.is_empty() {
+                true => "-".to_string(),
+                false => self.transitive_tag_keys.join(", "),
+            }
+        )
+    }
+}
+
+// Returns the string values of the nodes the XPath selects
+fn select<'d>(node: Node<'d>, context: &Context<'d>, xpath: &str) -> Result<Vec<Node<'d>>> {
+    let xpath = Factory::new()
+        .build(xpath)?
+        .with_context(|| "No XPath was compiled")?;
+
+    Ok(match xpath.evaluate(context, node)? {
+        Value::Nodeset(ns) => ns.document_order(),
+        _ => vec![],
+    })
+}
+
+fn evaluate(node: Node, context: &Context, xpath: &str) -> Result<Vec<String>> {
+    Ok(select(node, context, xpath)?
+        .iter()
+        .map(|n| n.string_value())
+        .collect())
+}
+
+// Decodes a captured assertion, either the base64 encoded SAMLResponse, the
+// page carrying it, or the plain XML
+pub fn decode_assertion(input: &str) -> Result<Response> {
+    let input = input.trim();
+
+    if input.contains("SAMLResponse") {
+        extract_saml_assertion(input)
+    } else if input.starts_with('<') {
+        b64.encode(input).parse()
+    } else {
+        // Captured values are often wrapped over several lines
+        input.split_whitespace().collect::<String>().parse()
+    }
+}
+
+pub fn get_credentials_from_saml(
+    input: String,
+    profile: &AppProfile,
+    sts: &dyn StsClient,
+) -> Result<AwsCredentials> {
     let saml = extract_saml_assertion(&input)?;
 
     debug!("SAML response: {:?}", &saml);
+    debug!("SAML attributes:\n{}", &saml);
 
-    let roles = saml.roles;
+    // STS would reject most of these as well, but with far less helpful errors
+    saml.validate(Utc::now(), profile.idp_certificate.as_deref())?;
 
-    debug!("SAML Roles: {:?}", &roles);
+    if saml.not_on_or_after.is_some() {
+        if let Err(e) = saml.clone().write(profile) {
+            debug!("Unable to cache SAML assertion: {}", e);
+        }
+    }
+
+    assume_role_from_saml(&saml, profile, sts)
+}
 
-    let role = utils::select_role(roles, role)?;
+// Assumes the role of the profile, or the one picked from the assertion,
+// with an assertion that has been validated before
+pub fn assume_role_from_saml(
+    saml: &Response,
+    profile: &AppProfile,
+    sts: &dyn StsClient,
+) -> Result<AwsCredentials> {
+    let duration = profile.duration;
+    let role = profile.role.clone();
 
-    let assumption_response =
-        RoleManager::assume_role(&role, saml.raw).with_context(|| "Error assuming role")?;
+    debug!("SAML Roles: {:?}", &saml.roles);
 
-    Ok(AwsCredentials::from(
-        assumption_response.credentials.with_context(|| {
-            "Error fetching credentials for selected AWS role from assumption response"
-        })?,
-    ))
+    let role = utils::select_role(saml.roles.clone(), role)?;
+
+    match sts.assume_role_with_saml(&role, &saml.raw, duration) {
+        Err(e) if duration.is_some() && sts::is_duration_rejected(&e) => {
+            warn!(
+                "Session duration of {} seconds was rejected, falling back to {}",
+                duration.unwrap_or_default(),
+                saml.session_duration
+                    .map(|d| format!("{} seconds", d))
+                    .unwrap_or_else(|| "the default".to_string())
+            );
+            sts.assume_role_with_saml(&role, &saml.raw, saml.session_duration)
+        }
+        credentials => credentials,
+    }
+    .with_context(|| "Error assuming role")
+}
+
+// Assertions are cached per IdP app and user until they expire, so any role
+// they list can be assumed without logging in again
+impl Credential<AppProfile, Response> for Response {
+    fn create(_profile: &AppProfile) -> Result<Response> {
+        Err(SamlError::MissingResponse.into())
+    }
+
+    fn load(profile: &AppProfile) -> Result<Response> {
+        let service = assertion_as_service(profile);
+
+        debug!("Trying to load cached SAML assertion from ID {}", &service);
+
+        let saml: Response = store::current().get(&service, &profile.username)?.parse()?;
+
+        // STS doesn't allow for clock skew, so neither does the cache
+        match saml.not_on_or_after {
+            Some(t) if t > Utc::now() => {
+                saml.validate(Utc::now(), profile.idp_certificate.as_deref())?;
+                Ok(saml)
+            }
+            Some(t) => Err(SamlError::Expired(t).into()),
+            None => Err(anyhow!("Cached SAML assertion has no expiry")),
+        }
+    }
+
+    fn write(self, profile: &AppProfile) -> Result<Response> {
+        let service = assertion_as_service(profile);
+
+        debug!("Caching SAML assertion at {}", &service);
+
+        store::current().set(&service, &profile.username, &self.raw)?;
+
+        Ok(self)
+    }
+
+    fn delete(self, profile: &AppProfile) -> Result<Response> {
+        let service = assertion_as_service(profile);
+
+        debug!("Deleting cached SAML assertion at {}", &service);
+        store::current().delete(&service, &profile.username)?;
+
+        Ok(self)
+    }
+}
+
+pub fn assertion_as_service(profile: &AppProfile) -> String {
+    let identifier = format!("{}\n{}", profile.url, profile.username);
+
+    format!(
+        "crowbar::{}::{:x}",
+        CredentialType::Saml,
+        sha2::Sha256::digest(identifier.as_bytes())
+    )
 }
 
 pub fn extract_saml_assertion(text: &str) -> Result<Response> {
@@ -84,13 +514,14 @@ pub fn extract_saml_assertion(text: &str) -> Result<Response> {
             Err(anyhow!("Missing SAML response in assertion element"))
         }
     } else {
-        Err(anyhow!("Could not find SAML element in HTML response"))
+        Err(SamlError::MissingResponse.into())
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::aws::sts::FakeStsClient;
     use std::fs;
 
     #[test]
@@ -110,6 +541,7 @@ mod tests {
         .collect::<HashSet<Role>>();
 
         assert_eq!(response.roles, expected_roles);
+        assert_eq!(response.session_duration, Some(43200));
 
         Ok(())
     }
@@ -153,6 +585,377 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn parses_aws_attributes() -> Result<()> {
+        let response = get_response("tests/fixtures/saml/saml_response_attributes.xml")?;
+
+        assert_eq!(
+            response.issuer.as_deref(),
+            Some("http://www.okta.com/exk1fcia6d6EMsf331d8")
+        );
+        assert_eq!(
+            response.not_on_or_after,
+            Some(DateTime::parse_from_rfc3339("2024-01-18T06:21:48Z")?.with_timezone(&Utc))
+        );
+        assert_eq!(
+            response.role_session_name.as_deref(),
+            Some("jdoe@example.com")
+        );
+        assert_eq!(response.session_duration, Some(28800));
+        assert_eq!(response.source_identity.as_deref(), Some("jdoe"));
+        assert_eq!(
+            response.principal_tags,
+            BTreeMap::from([
+                ("CostCenter".to_string(), "12345".to_string()),
+                ("Department".to_string(), "Engineering".to_string()),
+            ])
+        );
+        assert_eq!(
+            response.transitive_tag_keys,
+            vec!["Department".to_string(), "CostCenter".to_string()]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn displays_aws_attributes() -> Result<()> {
+        let response = get_response("tests/fixtures/saml/saml_response_attributes.xml")?;
+
+        assert_eq!(
+            response.to_string(),
+            r#"Issuer:              http://www.okta.com/exk1fcia6d6EMsf331d8
+Not on or after:     2024-01-18T06:21:48+00:00
+Role session name:   jdoe@example.com
+Session duration:    28800 seconds
+Source identity:     jdoe
+Roles:
+  arn:aws:iam::123456789012:role/role1 via arn:aws:iam::123456789012:saml-provider/okta-idp
+Principal tags:
+  CostCenter = 12345
+  Department = Engineering
+Transitive tag keys: Department, CostCenter"#
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn decodes_captured_assertions() -> Result<()> {
+        let xml = fs::read_to_string("tests/fixtures/saml/saml_response_attributes.xml")?;
+        let html = fs::read_to_string("tests/fixtures/jumpcloud/html_saml_response.html")?;
+        let wrapped = b64
+            .encode(&xml)
+            .as_bytes()
+            .chunks(76)
+            .map(|c| String::from_utf8_lossy(c).into_owned())
+            .collect::<Vec<String>>()
+            .join("\n");
+
+        assert_eq!(decode_assertion(&xml)?.session_duration, Some(28800));
+        assert_eq!(decode_assertion(&wrapped)?.session_duration, Some(28800));
+        assert!(!decode_assertion(&html)?.roles.is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn parses_assertions_regardless_of_namespace_prefix() -> Result<()> {
+        let unprefixed = get_response("tests/fixtures/saml/saml_response_unprefixed.xml")?;
+        let saml_prefix = get_response("tests/fixtures/saml/saml_response_saml_prefix.xml")?;
+
+        assert_eq!(unprefixed.roles.len(), 2);
+        assert_eq!(
+            unprefixed.issuer.as_deref(),
+            Some("http://adfs.example.com/adfs/services/trust")
+        );
+        assert_eq!(
+            unprefixed.not_on_or_after,
+            Some(DateTime::parse_from_rfc3339("2024-01-18T07:16:48Z")?.with_timezone(&Utc))
+        );
+        assert_eq!(
+            unprefixed.role_session_name.as_deref(),
+            Some("jdoe@example.com")
+        );
+
+        assert_eq!(
+            saml_prefix.roles,
+            HashSet::from([Role {
+                provider_arn: "arn:aws:iam::123456789012:saml-provider/shibboleth".to_string(),
+                role_arn: "arn:aws:iam::123456789012:role/shibboleth-user".to_string(),
+            }])
+        );
+        assert_eq!(
+            saml_prefix.issuer.as_deref(),
+            Some("https://idp.example.edu/idp/shibboleth")
+        );
+        assert_eq!(saml_prefix.role_session_name.as_deref(), Some("jdoe"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn returns_typed_errors_for_unusable_responses() {
+        let error = |path: &str| -> SamlError {
+            get_response(path)
+                .unwrap_err()
+                .downcast::<SamlError>()
+                .unwrap()
+        };
+
+        assert_eq!(
+            error("tests/fixtures/saml/saml_response_encrypted.xml"),
+            SamlError::EncryptedAssertion
+        );
+        assert_eq!(
+            error("tests/fixtures/saml/saml_response_no_assertion.xml"),
+            SamlError::MissingAssertion
+        );
+        assert!(matches!(
+            error("tests/fixtures/saml/saml_response_malformed.xml"),
+            SamlError::InvalidXml(_)
+        ));
+        assert!(matches!(
+            "not base64"
+                .parse::<Response>()
+                .unwrap_err()
+                .downcast::<SamlError>()
+                .unwrap(),
+            SamlError::InvalidEncoding(_)
+        ));
+        assert_eq!(
+            extract_saml_assertion("<html></html>")
+                .unwrap_err()
+                .downcast::<SamlError>()
+                .unwrap(),
+            SamlError::MissingResponse
+        );
+    }
+
+    #[test]
+    fn reads_principal_tags_with_quotes_in_their_names() -> Result<()> {
+        let xml = fs::read_to_string("tests/fixtures/saml/saml_response_attributes.xml")?
+            .replace("PrincipalTag:CostCenter", "PrincipalTag:Cost'Center");
+
+        let response = decode_assertion(&xml)?;
+
+        assert_eq!(
+            response
+                .principal_tags
+                .get("Cost'Center")
+                .map(|v| v.as_str()),
+            Some("12345")
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn validates_window_and_audience() -> Result<()> {
+        let response = get_response("tests/fixtures/saml/saml_response_attributes.xml")?;
+        let at = |t: &str| DateTime::parse_from_rfc3339(t).unwrap().with_timezone(&Utc);
+
+        assert_eq!(
+            response.audiences,
+            vec!["urn:amazon:webservices".to_string()]
+        );
+        assert_eq!(response.validate(at("2024-01-18T06:16:48Z"), None), Ok(()));
+        // Clocks a little off are tolerated
+        assert_eq!(response.validate(at("2024-01-18T06:10:00Z"), None), Ok(()));
+        assert_eq!(
+            response.validate(at("2024-01-18T06:05:00Z"), None),
+            Err(SamlError::NotYetValid(at("2024-01-18T06:11:48Z")))
+        );
+        assert_eq!(
+            response.validate(at("2024-01-18T06:30:00Z"), None),
+            Err(SamlError::Expired(at("2024-01-18T06:21:48Z")))
+        );
+
+        let audience = |audience: &str| {
+            Response {
+                audiences: vec![audience.to_string()],
+                ..response.clone()
+            }
+            .validate(at("2024-01-18T06:16:48Z"), None)
+        };
+        assert_eq!(audience("urn:amazon:webservices:cn-north-1"), Ok(()));
+        for invalid in [
+            "urn:amazon:webservices.evil",
+            "urn:amazon:webservicesevil",
+            "urn:amazon:webservices:",
+            "urn:amazon:webservices:evil.example.com",
+        ] {
+            assert_eq!(
+                audience(invalid),
+                Err(SamlError::InvalidAudience(vec![invalid.to_string()]))
+            );
+        }
+
+        let foreign = Response {
+            audiences: vec!["https://app.example.com".to_string()],
+            ..response
+        };
+        assert_eq!(
+            foreign.validate(at("2024-01-18T06:16:48Z"), None),
+            Err(SamlError::InvalidAudience(vec![
+                "https://app.example.com".to_string()
+            ]))
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn verifies_signature_with_pinned_certificate() -> Result<()> {
+        let certificate = fs::read_to_string("tests/fixtures/saml/idp_certificate.pem")?;
+        let other_certificate = fs::read_to_string("tests/fixtures/saml/other_certificate.pem")?;
+        let now = DateTime::parse_from_rfc3339("2024-01-18T06:16:48Z")?.with_timezone(&Utc);
+        let validate = |path: &str, certificate: &str| {
+            get_response(path).unwrap().validate(now, Some(certificate))
+        };
+
+        assert_eq!(
+            validate(
+                "tests/fixtures/saml/saml_response_signed_assertion.xml",
+                &certificate
+            ),
+            Ok(())
+        );
+        assert_eq!(
+            validate(
+                "tests/fixtures/saml/saml_response_signed_response.xml",
+                &certificate
+            ),
+            Ok(())
+        );
+        assert!(matches!(
+            validate(
+                "tests/fixtures/saml/saml_response_signed_assertion.xml",
+                &other_certificate
+            ),
+            Err(SamlError::InvalidSignature(_))
+        ));
+        assert!(matches!(
+            validate(
+                "tests/fixtures/saml/saml_response_tampered.xml",
+                &certificate
+            ),
+            Err(SamlError::InvalidSignature(_))
+        ));
+        assert_eq!(
+            validate(
+                "tests/fixtures/saml/saml_response_attributes.xml",
+                &certificate
+            ),
+            Err(SamlError::MissingSignature)
+        );
+
+        // An unsigned assertion smuggled in next to the signed one
+        let signed = fs::read_to_string("tests/fixtures/saml/saml_response_signed_assertion.xml")?;
+        let unsigned = fs::read_to_string("tests/fixtures/saml/saml_response_attributes.xml")?;
+        let start = unsigned.find("<saml2:Assertion").unwrap();
+        let end = unsigned.find("</saml2:Assertion>").unwrap() + "</saml2:Assertion>".len();
+        let wrapped = signed.replace(
+            "</samlp:Response>",
+            &format!("{}\n</samlp:Response>", &unsigned[start..end]),
+        );
+        assert_eq!(
+            decode_assertion(&wrapped)
+                .unwrap_err()
+                .downcast::<SamlError>()?,
+            SamlError::MultipleAssertions
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn ignores_claims_outside_of_signed_assertion() -> Result<()> {
+        let certificate = fs::read_to_string("tests/fixtures/saml/idp_certificate.pem")?;
+        let now = DateTime::parse_from_rfc3339("2024-01-18T06:16:48Z")?.with_timezone(&Utc);
+        let signed = fs::read_to_string("tests/fixtures/saml/saml_response_signed_assertion.xml")?;
+        let role = |name: &str| {
+            format!(
+                "arn:aws:iam::123456789012:saml-provider/example-idp,arn:aws:iam::123456789012:role/{}",
+                name
+            )
+        };
+
+        // Unsigned claims next to the signed assertion, in its namespace and
+        // in a foreign one
+        let injected = signed.replace(
+            "</samlp:Response>",
+            &format!(
+                r#"<saml2:AttributeStatement xmlns:saml2="urn:oasis:names:tc:SAML:2.0:assertion">
+    <saml2:Attribute Name="https://aws.amazon.com/SAML/Attributes/Role">
+      <saml2:AttributeValue>{}</saml2:AttributeValue>
+    </saml2:Attribute>
+  </saml2:AttributeStatement>
+  <evil:Attribute xmlns:evil="urn:example:evil" Name="https://aws.amazon.com/SAML/Attributes/Role">
+    <evil:AttributeValue>{}</evil:AttributeValue>
+  </evil:Attribute>
+  <saml2:Conditions xmlns:saml2="urn:oasis:names:tc:SAML:2.0:assertion" NotOnOrAfter="2038-01-19T03:14:07Z" />
+</samlp:Response>"#,
+                role("admin"),
+                role("root")
+            ),
+        );
+        let response = decode_assertion(&injected)?;
+
+        assert_eq!(response.validate(now, Some(&certificate)), Ok(()));
+        assert_eq!(
+            response.roles,
+            HashSet::from([Role::from_str(&role("role1"))?])
+        );
+        assert_eq!(
+            response.not_on_or_after,
+            Some(DateTime::parse_from_rfc3339("2024-01-18T06:21:48Z")?.with_timezone(&Utc))
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn assumes_requested_role_from_assertion() -> Result<()> {
+        let response = get_response("tests/fixtures/okta/saml_response.xml")?;
+        let profile = |url: &str, role: &str| -> AppProfile {
+            toml::from_str(&format!(
+                r#"
+                name = "profile"
+                provider = "okta"
+                url = "{}"
+                username = "jdoe"
+                role = "{}"
+            "#,
+                url, role
+            ))
+            .unwrap()
+        };
+        let app = "https://example.okta.com/home/amazon_aws/0oa1/272";
+        let sts = FakeStsClient::new();
+
+        assume_role_from_saml(
+            &response,
+            &profile(app, "arn:aws:iam::123456789012:role/role2"),
+            &sts,
+        )?;
+
+        assert_eq!(
+            sts.calls()[0].role_arn,
+            "arn:aws:iam::123456789012:role/role2"
+        );
+        assert_eq!(
+            assertion_as_service(&profile(app, "arn:aws:iam::123456789012:role/role1")),
+            assertion_as_service(&profile(app, "arn:aws:iam::123456789012:role/role2"))
+        );
+        assert_ne!(
+            assertion_as_service(&profile(app, "")),
+            assertion_as_service(&profile("https://example.okta.com/home/other/0oa2/272", ""))
+        );
+
+        Ok(())
+    }
+
     fn get_response(path: &str) -> Result<Response> {
         let saml_xml: String = fs::read_to_string(path)?;
         let saml_base64 = b64.encode(&saml_xml);
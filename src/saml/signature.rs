@@ -0,0 +1,430 @@
+use crate::saml::SamlError;
+
+use base64::{engine::general_purpose::STANDARD as b64, Engine as _};
+use ring::digest;
+use std::collections::BTreeMap;
+use sxd_document::dom::{ChildOfElement, ChildOfRoot, Element};
+use sxd_document::parser;
+
+const ASSERTION_NS: &str = "urn:oasis:names:tc:SAML:2.0:assertion";
+const PROTOCOL_NS: &str = "urn:oasis:names:tc:SAML:2.0:protocol";
+const DSIG_NS: &str = "http://www.w3.org/2000/09/xmldsig#";
+const EXC_C14N_NS: &str = "http://www.w3.org/2001/10/xml-exc-c14n#";
+
+const EXC_C14N: &str = "http://www.w3.org/2001/10/xml-exc-c14n#";
+const ENVELOPED_SIGNATURE: &str = "http://www.w3.org/2000/09/xmldsig#enveloped-signature";
+
+// Accepts a PEM certificate as well as its bare base64 encoded DER
+pub fn decode_certificate(certificate: &str) -> Result<Vec<u8>, SamlError> {
+    let encoded = certificate
+        .lines()
+        .filter(|l| !l.trim_start().starts_with("-----"))
+        .flat_map(|l| l.split_whitespace())
+        .collect::<String>();
+
+    b64.decode(encoded)
+        .map_err(|e| SamlError::InvalidCertificate(e.to_string()))
+}
+
+// Checks that the assertion of the response is covered by a signature made
+// with the pinned certificate, either its own or the one of the response
+pub fn verify(xml: &str, certificate: &[u8]) -> Result<(), SamlError> {
+    let package = parser::parse(xml).map_err(|e| SamlError::InvalidXml(e.to_string()))?;
+    let document = package.as_document();
+    let root = document
+        .root()
+        .children()
+        .into_iter()
+        .find_map(|c| match c {
+            ChildOfRoot::Element(e) => Some(e),
+            _ => None,
+        })
+        .ok_or(SamlError::MissingResponse)?;
+
+    let elements = descendants(root);
+
+    // A second, unsigned assertion next to a signed one is how signature
+    // wrapping attacks get their claims in
+    let assertion = match elements
+        .iter()
+        .filter(|e| is(e, ASSERTION_NS, "Assertion"))
+        .collect::<Vec<&Element>>()
+        .as_slice()
+    {
+        [assertion] => **assertion,
+        [] => return Err(SamlError::MissingAssertion),
+        _ => return Err(invalid("response carries more than one assertion")),
+    };
+
+    let mut candidates = vec![assertion];
+    if is(&root, PROTOCOL_NS, "Response") {
+        candidates.push(root);
+    }
+
+    match candidates
+        .into_iter()
+        .find_map(|e| child(e, DSIG_NS, "Signature").map(|s| (e, s)))
+    {
+        Some((signed, signature)) => verify_signature(&elements, signed, signature, certificate),
+        None => Err(SamlError::MissingSignature),
+    }
+}
+
+fn verify_signature(
+    elements: &[Element],
+    signed: Element,
+    signature: Element,
+    certificate: &[u8],
+) -> Result<(), SamlError> {
+    let signed_info = child(signature, DSIG_NS, "SignedInfo")
+        .ok_or_else(|| invalid("signature has no SignedInfo"))?;
+
+    let canonicalization = child(signed_info, DSIG_NS, "CanonicalizationMethod")
+        .ok_or_else(|| invalid("signature has no CanonicalizationMethod"))?;
+    if algorithm(canonicalization)? != EXC_C14N {
+        return Err(SamlError::UnsupportedAlgorithm(
+            algorithm(canonicalization)?.to_owned(),
+        ));
+    }
+    let signature_algorithm = signature_algorithm(algorithm(
+        child(signed_info, DSIG_NS, "SignatureMethod")
+            .ok_or_else(|| invalid("signature has no SignatureMethod"))?,
+    )?)?;
+
+    let reference = match children(signed_info, DSIG_NS, "Reference").as_slice() {
+        [reference] => *reference,
+        _ => return Err(invalid("signature has to hold exactly one reference")),
+    };
+
+    let id = signed
+        .attribute_value("ID")
+        .ok_or_else(|| invalid("signed element has no ID"))?;
+    if reference.attribute_value("URI") != Some(&format!("#{}", id)) {
+        return Err(invalid(
+            "signature doesn't reference the element it is part of",
+        ));
+    }
+    if elements
+        .iter()
+        .filter(|e| e.attribute_value("ID") == Some(id))
+        .count()
+        != 1
+    {
+        return Err(invalid(&format!("ID {} is not unique", id)));
+    }
+
+    let mut enveloped = false;
+    let mut inclusive_prefixes = None;
+    for transform in child(reference, DSIG_NS, "Transforms")
+        .map(|t| children(t, DSIG_NS, "Transform"))
+        .unwrap_or_default()
+    {
+        match algorithm(transform)? {
+            ENVELOPED_SIGNATURE => enveloped = true,
+            EXC_C14N => inclusive_prefixes = Some(prefix_list(transform)),
+            other => return Err(SamlError::UnsupportedAlgorithm(other.to_owned())),
+        }
+    }
+    if !enveloped {
+        return Err(invalid("signature is not an enveloped signature"));
+    }
+
+    let digest_algorithm = digest_algorithm(algorithm(
+        child(reference, DSIG_NS, "DigestMethod")
+            .ok_or_else(|| invalid("reference has no DigestMethod"))?,
+    )?)?;
+    let expected_digest = decode(
+        child(reference, DSIG_NS, "DigestValue")
+            .ok_or_else(|| invalid("reference has no DigestValue"))?,
+    )?;
+
+    let canonical = canonicalize(
+        signed,
+        Some(signature),
+        &inclusive_prefixes.unwrap_or_default(),
+    );
+    if digest::digest(digest_algorithm, canonical.as_bytes()).as_ref() != expected_digest {
+        return Err(invalid("digest doesn't match the signed content"));
+    }
+
+    let signature_value = decode(
+        child(signature, DSIG_NS, "SignatureValue")
+            .ok_or_else(|| invalid("signature has no SignatureValue"))?,
+    )?;
+    let certificate = webpki::EndEntityCert::try_from(certificate)
+        .map_err(|e| SamlError::InvalidCertificate(format!("{:?}", e)))?;
+
+    certificate
+        .verify_signature(
+            signature_algorithm,
+            canonicalize(signed_info, None, &prefix_list(canonicalization)).as_bytes(),
+            &signature_value,
+        )
+        .map_err(|_| invalid("signature wasn't made with the pinned IdP certificate"))
+}
+
+fn signature_algorithm(algorithm: &str) -> Result<&'static webpki::SignatureAlgorithm, SamlError> {
+    match algorithm {
+        "http://www.w3.org/2001/04/xmldsig-more#rsa-sha256" => {
+            Ok(&webpki::RSA_PKCS1_2048_8192_SHA256)
+        }
+        "http://www.w3.org/2001/04/xmldsig-more#rsa-sha384" => {
+            Ok(&webpki::RSA_PKCS1_2048_8192_SHA384)
+        }
+        "http://www.w3.org/2001/04/xmldsig-more#rsa-sha512" => {
+            Ok(&webpki::RSA_PKCS1_2048_8192_SHA512)
+        }
+        _ => Err(SamlError::UnsupportedAlgorithm(algorithm.to_owned())),
+    }
+}
+
+fn digest_algorithm(algorithm: &str) -> Result<&'static digest::Algorithm, SamlError> {
+    match algorithm {
+        "http://www.w3.org/2001/04/xmlenc#sha256" => Ok(&digest::SHA256),
+        "http://www.w3.org/2001/04/xmldsig-more#sha384" => Ok(&digest::SHA384),
+        "http://www.w3.org/2001/04/xmlenc#sha512" => Ok(&digest::SHA512),
+        _ => Err(SamlError::UnsupportedAlgorithm(algorithm.to_owned())),
+    }
+}
+
+fn invalid(reason: &str) -> SamlError {
+    SamlError::InvalidSignature(reason.to_owned())
+}
+
+fn algorithm<'d>(element: Element<'d>) -> Result<&'d str, SamlError> {
+    element
+        .attribute_value("Algorithm")
+        .ok_or_else(|| invalid(&format!("{} has no Algorithm", element.name().local_part())))
+}
+
+fn decode(element: Element) -> Result<Vec<u8>, SamlError> {
+    b64.decode(text(element).split_whitespace().collect::<String>())
+        .map_err(|e| SamlError::InvalidSignature(e.to_string()))
+}
+
+// The prefixes an exclusive canonicalization is told to treat inclusively
+fn prefix_list(method: Element) -> Vec<String> {
+    child(method, EXC_C14N_NS, "InclusiveNamespaces")
+        .and_then(|e| e.attribute_value("PrefixList"))
+        .map(|l| l.split_whitespace().map(|p| p.to_owned()).collect())
+        .unwrap_or_default()
+}
+
+fn is(element: &Element, namespace: &str, local_name: &str) -> bool {
+    element.name().namespace_uri() == Some(namespace) && element.name().local_part() == local_name
+}
+
+fn children<'d>(element: Element<'d>, namespace: &str, local_name: &str) -> Vec<Element<'d>> {
+    element
+        .children()
+        .into_iter()
+        .filter_map(|c| c.element())
+        .filter(|e| is(e, namespace, local_name))
+        .collect()
+}
+
+fn child<'d>(element: Element<'d>, namespace: &str, local_name: &str) -> Option<Element<'d>> {
+    children(element, namespace, local_name).into_iter().next()
+}
+
+fn descendants(element: Element) -> Vec<Element> {
+    let mut elements = vec![element];
+    for child in element.children().into_iter().filter_map(|c| c.element()) {
+        elements.extend(descendants(child));
+    }
+    elements
+}
+
+fn text(element: Element) -> String {
+    element
+        .children()
+        .into_iter()
+        .filter_map(|c| c.text())
+        .map(|t| t.text())
+        .collect()
+}
+
+// Exclusive XML canonicalization, without comments, of the element and its
+// descendants, leaving out the excluded element
+pub fn canonicalize(
+    element: Element,
+    excluded: Option<Element>,
+    inclusive_prefixes: &[String],
+) -> String {
+    let mut output = String::new();
+    write_element(
+        &mut output,
+        element,
+        excluded,
+        inclusive_prefixes,
+        &BTreeMap::new(),
+    );
+    output
+}
+
+fn write_element(
+    output: &mut String,
+    element: Element,
+    excluded: Option<Element>,
+    inclusive_prefixes: &[String],
+    rendered: &BTreeMap<String, String>,
+) {
+    // Namespaces are declared where they are visibly utilized, unless an
+    // output ancestor declared them already
+    let mut utilized = BTreeMap::new();
+    utilized.insert(
+        element.preferred_prefix().unwrap_or_default().to_owned(),
+        element
+            .name()
+            .namespace_uri()
+            .unwrap_or_default()
+            .to_owned(),
+    );
+    for attribute in element.attributes() {
+        if let (Some(prefix), Some(namespace)) = (
+            attribute.preferred_prefix(),
+            attribute.name().namespace_uri(),
+        ) {
+            if prefix != "xml" {
+                utilized.insert(prefix.to_owned(), namespace.to_owned());
+            }
+        }
+    }
+    for prefix in inclusive_prefixes {
+        if let Some(namespace) = element.namespace_uri_for_prefix(prefix) {
+            utilized.insert(prefix.to_owned(), namespace.to_owned());
+        }
+    }
+
+    let mut in_scope = rendered.clone();
+    let name = qualified_name(element.preferred_prefix(), element.name().local_part());
+    output.push('<');
+    output.push_str(&name);
+    for (prefix, namespace) in utilized {
+        if rendered
+            .get(&prefix)
+            .map(String::as_str)
+            .unwrap_or_default()
+            == namespace
+        {
+            continue;
+        }
+        match prefix.as_str() {
+            "" => output.push_str(" xmlns=\""),
+            _ => {
+                output.push_str(" xmlns:");
+                output.push_str(&prefix);
+                output.push_str("=\"");
+            }
+        }
+        output.push_str(&escape(&namespace, true));
+        output.push('"');
+        in_scope.insert(prefix, namespace);
+    }
+
+    let mut attributes = element.attributes();
+    attributes.sort_by_key(|a| {
+        (
+            a.name().namespace_uri().unwrap_or_default().to_owned(),
+            a.name().local_part().to_owned(),
+        )
+    });
+    for attribute in attributes {
+        output.push(' ');
+        output.push_str(&qualified_name(
+            attribute.preferred_prefix(),
+            attribute.name().local_part(),
+        ));
+        output.push_str("=\"");
+        output.push_str(&escape(attribute.value(), true));
+        output.push('"');
+    }
+    output.push('>');
+
+    for child in element.children() {
+        match child {
+            ChildOfElement::Element(e) if Some(e) != excluded => {
+                write_element(output, e, excluded, inclusive_prefixes, &in_scope)
+            }
+            ChildOfElement::Text(t) => output.push_str(&escape(t.text(), false)),
+            ChildOfElement::ProcessingInstruction(pi) => {
+                output.push_str("<?");
+                output.push_str(pi.target());
+                if let Some(value) = pi.value() {
+                    output.push(' ');
+                    output.push_str(value);
+                }
+                output.push_str("?>");
+            }
+            _ => {}
+        }
+    }
+
+    output.push_str("</");
+    output.push_str(&name);
+    output.push('>');
+}
+
+fn qualified_name(prefix: Option<&str>, local_name: &str) -> String {
+    match prefix {
+        Some(prefix) => format!("{}:{}", prefix, local_name),
+        None => local_name.to_owned(),
+    }
+}
+
+fn escape(value: &str, attribute: bool) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match (c, attribute) {
+            ('&', _) => escaped.push_str("&amp;"),
+            ('<', _) => escaped.push_str("&lt;"),
+            ('>', false) => escaped.push_str("&gt;"),
+            ('"', true) => escaped.push_str("&quot;"),
+            ('\t', true) => escaped.push_str("&#x9;"),
+            ('\n', true) => escaped.push_str("&#xA;"),
+            ('\r', _) => escaped.push_str("&#xD;"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn canonicalizes_exclusively() {
+        let package = parser::parse(
+            r#"<a:Root xmlns:a="urn:a" xmlns:b="urn:b" xmlns:unused="urn:unused">
+  <!-- dropped --><b:Child z="1" b:y="2" a="&quot;&#9;"/>
+  <Plain xmlns="urn:default">1 &lt; 2 &amp;&amp; 3 &gt; 2</Plain>
+</a:Root>"#,
+        )
+        .unwrap();
+        let document = package.as_document();
+        let root = document.root().children()[0].element().unwrap();
+
+        assert_eq!(
+            canonicalize(root, None, &[]),
+            r#"<a:Root xmlns:a="urn:a">
+  <b:Child xmlns:b="urn:b" a="&quot;&#x9;" z="1" b:y="2"></b:Child>
+  <Plain xmlns="urn:default">1 &lt; 2 &amp;&amp; 3 &gt; 2</Plain>
+</a:Root>"#
+        );
+        assert!(canonicalize(root, None, &["unused".to_string()])
+            .starts_with(r#"<a:Root xmlns:a="urn:a" xmlns:unused="urn:unused">"#));
+    }
+
+    #[test]
+    fn decodes_pem_and_bare_certificates() {
+        let pem = "-----BEGIN CERTIFICATE-----\nAAEC\nAwQ=\n-----END CERTIFICATE-----\n";
+
+        assert_eq!(decode_certificate(pem), Ok(vec![0, 1, 2, 3, 4]));
+        assert_eq!(decode_certificate("AAECAwQ="), Ok(vec![0, 1, 2, 3, 4]));
+        assert!(matches!(
+            decode_certificate("not a certificate"),
+            Err(SamlError::InvalidCertificate(_))
+        ));
+    }
+}
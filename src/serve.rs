@@ -0,0 +1,323 @@
+use crate::credentials::aws::AwsCredentials;
+use anyhow::{anyhow, Result};
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use log::{debug, error, info};
+use ring::constant_time;
+use ring::rand::{SecureRandom, SystemRandom};
+use serde::Serialize;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+pub const FULL_URI_VARIABLE: &str = "AWS_CONTAINER_CREDENTIALS_FULL_URI";
+pub const AUTHORIZATION_TOKEN_VARIABLE: &str = "AWS_CONTAINER_AUTHORIZATION_TOKEN";
+
+const CONNECTION_TIMEOUT: Duration = Duration::from_secs(5);
+
+pub type CredentialsFetcher = Box<dyn FnMut() -> Result<AwsCredentials> + Send>;
+
+// The layout of the ECS container credentials endpoint, which the AWS SDKs
+// read from AWS_CONTAINER_CREDENTIALS_FULL_URI
+#[derive(Serialize, Debug, PartialEq)]
+#[serde(rename_all = "PascalCase")]
+struct ContainerCredentials {
+    access_key_id: String,
+    secret_access_key: String,
+    token: String,
+    expiration: String,
+}
+
+impl TryFrom<AwsCredentials> for ContainerCredentials {
+    type Error = anyhow::Error;
+
+    fn try_from(credentials: AwsCredentials) -> Result<Self> {
+        match credentials {
+            AwsCredentials {
+                access_key_id: Some(access_key_id),
+                secret_access_key: Some(secret_access_key),
+                session_token: Some(token),
+                expiration: Some(expiration),
+                ..
+            } => Ok(ContainerCredentials {
+                access_key_id,
+                secret_access_key,
+                token,
+                expiration,
+            }),
+            _ => Err(anyhow!("Incomplete AWS credentials")),
+        }
+    }
+}
+
+// Hands out the credentials of a profile over HTTP on the loopback interface,
+// fetching new ones once they are about to expire
+pub struct Server {
+    listener: TcpListener,
+    handler: Handler,
+}
+
+impl Server {
+    // The refresh window is the one the credential cache of the profile uses,
+    // see seconds_to_expiration
+    pub fn bind(port: u16, refresh_window: i64, fetch: CredentialsFetcher) -> Result<Server> {
+        Ok(Server {
+            listener: TcpListener::bind(("127.0.0.1", port))?,
+            handler: Handler {
+                token: Arc::new(generate_token()?),
+                cache: Arc::new(Mutex::new(Cache {
+                    fetch,
+                    refresh_window,
+                    credentials: None,
+                })),
+            },
+        })
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        Ok(self.listener.local_addr()?)
+    }
+
+    pub fn token(&self) -> &str {
+        &self.handler.token
+    }
+
+    // Every connection gets its own thread, so clients which are slow to send
+    // their request don't hold up the others
+    pub fn run(self) -> Result<()> {
+        info!("Serving credentials at http://{}/", self.local_addr()?);
+
+        loop {
+            match self.listener.accept() {
+                Ok((stream, _)) => {
+                    let handler = self.handler.clone();
+                    thread::spawn(move || {
+                        handler
+                            .handle(stream)
+                            .unwrap_or_else(|e| debug!("Unable to answer request: {}", e))
+                    });
+                }
+                Err(e) => debug!("Unable to accept connection: {}", e),
+            }
+        }
+    }
+}
+
+#[derive(Clone)]
+struct Handler {
+    token: Arc<String>,
+    cache: Arc<Mutex<Cache>>,
+}
+
+impl Handler {
+    fn handle(&self, mut stream: TcpStream) -> Result<()> {
+        stream.set_read_timeout(Some(CONNECTION_TIMEOUT))?;
+        stream.set_write_timeout(Some(CONNECTION_TIMEOUT))?;
+
+        let mut reader = BufReader::new(&stream);
+        let mut request_line = String::new();
+        reader.read_line(&mut request_line)?;
+
+        let mut authorization = None;
+        loop {
+            let mut header = String::new();
+            if reader.read_line(&mut header)? == 0 || header.trim().is_empty() {
+                break;
+            }
+            if let Some((name, value)) = header.split_once(':') {
+                if name.trim().eq_ignore_ascii_case("authorization") {
+                    authorization = Some(value.trim().to_string());
+                }
+            }
+        }
+
+        debug!("Credentials requested with {}", request_line.trim());
+
+        let (status, body) = if !request_line.starts_with("GET ") {
+            (
+                "405 Method Not Allowed",
+                error_body("Only GET is supported"),
+            )
+        } else if !self.authorized(authorization.as_deref()) {
+            ("403 Forbidden", error_body("Invalid authorization token"))
+        } else {
+            match self.current_credentials() {
+                Ok(credentials) => ("200 OK", serde_json::to_string(&credentials)?),
+                Err(e) => {
+                    error!("Unable to fetch credentials: {}", e);
+                    ("500 Internal Server Error", error_body(&e.to_string()))
+                }
+            }
+        };
+
+        write!(
+            stream,
+            "HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            status,
+            body.len(),
+            body
+        )?;
+
+        Ok(stream.flush()?)
+    }
+
+    fn authorized(&self, authorization: Option<&str>) -> bool {
+        authorization.is_some_and(|a| {
+            constant_time::verify_slices_are_equal(a.as_bytes(), self.token.as_bytes()).is_ok()
+        })
+    }
+
+    // Requests coming in while credentials are fetched wait for them instead
+    // of logging in again
+    fn current_credentials(&self) -> Result<ContainerCredentials> {
+        let mut cache = self
+            .cache
+            .lock()
+            .map_err(|_| anyhow!("Credentials cache is unavailable"))?;
+
+        let credentials = match cache.credentials.take() {
+            Some(credentials)
+                if credentials.valid() && !credentials.expires_within(cache.refresh_window) =>
+            {
+                credentials
+            }
+            _ => {
+                debug!("Fetching new credentials to serve");
+                (cache.fetch)()?
+            }
+        };
+        cache.credentials = Some(credentials.clone());
+
+        credentials.try_into()
+    }
+}
+
+struct Cache {
+    fetch: CredentialsFetcher,
+    refresh_window: i64,
+    credentials: Option<AwsCredentials>,
+}
+
+fn error_body(message: &str) -> String {
+    serde_json::json!({ "message": message }).to_string()
+}
+
+fn generate_token() -> Result<String> {
+    let mut token = [0u8; 32];
+    SystemRandom::new()
+        .fill(&mut token)
+        .map_err(|_| anyhow!("Unable to generate authorization token"))?;
+
+    Ok(URL_SAFE_NO_PAD.encode(token))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::credentials::aws::seconds_to_expiration;
+    use chrono::{Duration, SecondsFormat, Utc};
+    use reqwest::blocking::Client;
+    use reqwest::StatusCode;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    type Get = Box<dyn Fn(Option<&str>) -> Result<(StatusCode, serde_json::Value)>>;
+
+    fn credentials(key: &str, expires_in: Duration) -> AwsCredentials {
+        AwsCredentials {
+            version: 1,
+            access_key_id: Some(key.to_string()),
+            secret_access_key: Some("some_secret".to_string()),
+            session_token: Some("some_token".to_string()),
+            expiration: Some((Utc::now() + expires_in).to_rfc3339_opts(SecondsFormat::Secs, true)),
+        }
+    }
+
+    // Starts a server whose first credentials expire in the given time and
+    // later ones in an hour
+    fn serve(
+        refresh_window: i64,
+        first_expires_in: Duration,
+    ) -> Result<(SocketAddr, String, Arc<AtomicUsize>, Get)> {
+        let fetched = Arc::new(AtomicUsize::new(0));
+        let counter = Arc::clone(&fetched);
+        let server = Server::bind(
+            0,
+            refresh_window,
+            Box::new(move || {
+                Ok(match counter.fetch_add(1, Ordering::SeqCst) {
+                    0 => credentials("first_key", first_expires_in),
+                    _ => credentials("fresh_key", Duration::hours(1)),
+                })
+            }),
+        )?;
+        let addr = server.local_addr()?;
+        let token = server.token().to_string();
+        thread::spawn(move || server.run());
+
+        let url = format!("http://{}/", addr);
+        let client = Client::new();
+        let get = Box::new(move |token: Option<&str>| {
+            let mut request = client.get(&url);
+            if let Some(token) = token {
+                request = request.header("Authorization", token);
+            }
+            let response = request.send()?;
+            Ok((response.status(), response.json()?))
+        });
+
+        Ok((addr, token, fetched, get))
+    }
+
+    #[test]
+    fn serves_and_refreshes_credentials() -> Result<()> {
+        // The first credentials are within the refresh window already
+        let (_, token, fetched, get) = serve(seconds_to_expiration(None), Duration::minutes(5))?;
+
+        assert_eq!(get(None)?.0, StatusCode::FORBIDDEN);
+        assert_eq!(get(Some("guessed"))?.0, StatusCode::FORBIDDEN);
+        assert_eq!(fetched.load(Ordering::SeqCst), 0);
+
+        let (status, body) = get(Some(&token))?;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["AccessKeyId"], "first_key");
+        assert_eq!(body["SecretAccessKey"], "some_secret");
+        assert_eq!(body["Token"], "some_token");
+        assert_eq!(fetched.load(Ordering::SeqCst), 1);
+
+        for _ in 0..2 {
+            let (_, body) = get(Some(&token))?;
+            assert_eq!(body["AccessKeyId"], "fresh_key");
+        }
+        assert_eq!(fetched.load(Ordering::SeqCst), 2);
+
+        Ok(())
+    }
+
+    #[test]
+    fn refreshes_within_window_of_session_duration() -> Result<()> {
+        // Fifteen minute sessions are refreshed in their last quarter only
+        let (_, token, fetched, get) =
+            serve(seconds_to_expiration(Some(900)), Duration::minutes(10))?;
+
+        for _ in 0..2 {
+            let (_, body) = get(Some(&token))?;
+            assert_eq!(body["AccessKeyId"], "first_key");
+        }
+        assert_eq!(fetched.load(Ordering::SeqCst), 1);
+
+        Ok(())
+    }
+
+    #[test]
+    fn answers_while_other_clients_stall() -> Result<()> {
+        let (addr, token, _, get) = serve(seconds_to_expiration(None), Duration::hours(1))?;
+
+        let _silent = TcpStream::connect(addr)?;
+        let (status, _) = get(Some(&token))?;
+        assert_eq!(status, StatusCode::OK);
+
+        Ok(())
+    }
+}
@@ -7,6 +7,7 @@ use dialoguer::{Input, Password};
 use serde::{Deserialize, Serialize};
 use std::collections::HashSet;
 use std::env::var;
+use std::path::Path;
 
 #[derive(Deserialize, Serialize, Debug, Clone, Eq, PartialEq, Hash)]
 pub enum LevelFilter {
@@ -46,7 +47,7 @@ pub fn non_empty_env_var(name: &str) -> Option<String> {
 
 pub fn prompt_password(profile: &AppProfile) -> Result<String> {
     Password::new()
-        .with_prompt(&format!(
+        .with_prompt(format!(
             "Password for {} at {}",
             &profile.username,
             profile.clone().base_url()?.host().unwrap()
@@ -55,6 +56,17 @@ pub fn prompt_password(profile: &AppProfile) -> Result<String> {
         .map_err(|e| e.into())
 }
 
+// New stores ask for the passphrase twice to rule out typos
+pub fn prompt_passphrase(store: &Path, new: bool) -> Result<String> {
+    let mut prompt = Password::new();
+    prompt.with_prompt(format!("Passphrase for {}", store.display()));
+    if new {
+        prompt.with_confirmation("Repeat passphrase", "Passphrases don't match");
+    }
+
+    prompt.interact().map_err(|e| e.into())
+}
+
 pub fn prompt_mfa() -> Result<String> {
     Input::new()
         .with_prompt("Enter MFA code")
@@ -62,6 +74,14 @@ pub fn prompt_mfa() -> Result<String> {
         .with_context(|| "Failed to get MFA input")
 }
 
+// Answers are hidden like passwords since they act as one
+pub fn prompt_answer(question: &str) -> Result<String> {
+    Password::new()
+        .with_prompt(question)
+        .interact()
+        .with_context(|| "Failed to get answer input")
+}
+
 pub fn select_role(roles: HashSet<AwsRole>, role: Option<String>) -> Result<AwsRole> {
     let selection = match role {
         None => match roles.clone() {
@@ -81,7 +101,7 @@ pub fn select_role(roles: HashSet<AwsRole>, role: Option<String>) -> Result<AwsR
             None => match roles.clone() {
                 r if r.len() < 2 => 0,
                 r => Select::with_theme(&SimpleTheme)
-                    .with_prompt(&format!(
+                    .with_prompt(format!(
                         "Role {} not found; select the role to assume:",
                         role
                     ))
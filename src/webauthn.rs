@@ -0,0 +1,110 @@
+pub mod hid;
+pub mod software;
+
+use anyhow::{Context, Result};
+use base64::{engine::general_purpose::STANDARD as b64, Engine as _};
+use log::debug;
+use serde::Serialize;
+use sha2::Digest;
+use url::Url;
+
+const CLIENT_DATA_TYPE_GET: &str = "webauthn.get";
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct AssertionRequest {
+    pub rp_id: String,
+    pub client_data_hash: Vec<u8>,
+    // Credential IDs as handed out by the IdP (base64url encoded)
+    pub credential_ids: Vec<String>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Assertion {
+    pub authenticator_data: Vec<u8>,
+    pub signature: Vec<u8>,
+}
+
+// The base64 encoded fields IdPs expect in a WebAuthn verification request
+#[derive(Debug, Clone, PartialEq)]
+pub struct SignedChallenge {
+    pub client_data: String,
+    pub authenticator_data: String,
+    pub signature_data: String,
+}
+
+pub trait Authenticator {
+    fn get_assertion(&self, request: &AssertionRequest) -> Result<Assertion>;
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct ClientData {
+    #[serde(rename = "type")]
+    kind: String,
+    challenge: String,
+    origin: String,
+    cross_origin: bool,
+}
+
+impl ClientData {
+    pub fn get(challenge: &str, origin: &str) -> Self {
+        ClientData {
+            kind: CLIENT_DATA_TYPE_GET.to_string(),
+            challenge: challenge.to_string(),
+            origin: origin.trim_end_matches('/').to_string(),
+            cross_origin: false,
+        }
+    }
+
+    pub fn to_json(&self) -> Result<String> {
+        serde_json::to_string(self).map_err(|e| e.into())
+    }
+}
+
+pub fn client_data_hash(client_data_json: &str) -> Vec<u8> {
+    sha2::Sha256::digest(client_data_json.as_bytes()).to_vec()
+}
+
+pub fn sign_challenge(
+    authenticator: &dyn Authenticator,
+    challenge: &str,
+    origin: &Url,
+    credential_ids: Vec<String>,
+) -> Result<SignedChallenge> {
+    let client_data = ClientData::get(challenge, origin.as_str()).to_json()?;
+    let request = AssertionRequest {
+        rp_id: origin
+            .host_str()
+            .with_context(|| "Missing host for WebAuthn relying party")?
+            .to_string(),
+        client_data_hash: client_data_hash(&client_data),
+        credential_ids,
+    };
+
+    debug!("WebAuthn assertion request: {:?}", request);
+
+    let assertion = authenticator.get_assertion(&request)?;
+
+    Ok(SignedChallenge {
+        client_data: b64.encode(client_data),
+        authenticator_data: b64.encode(assertion.authenticator_data),
+        signature_data: b64.encode(assertion.signature),
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn renders_client_data_json() -> Result<()> {
+        let client_data = ClientData::get("challenge", "https://example.okta.com/");
+
+        assert_eq!(
+            r#"{"type":"webauthn.get","challenge":"challenge","origin":"https://example.okta.com","crossOrigin":false}"#,
+            client_data.to_json()?
+        );
+
+        Ok(())
+    }
+}
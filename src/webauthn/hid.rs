@@ -0,0 +1,299 @@
+use crate::webauthn::{Assertion, AssertionRequest, Authenticator};
+
+use anyhow::{anyhow, Context, Result};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD as b64url, Engine as _};
+use ciborium::value::Value;
+use log::debug;
+
+const REPORT_SIZE: usize = 64;
+const INIT_HEADER_SIZE: usize = 7;
+const CONT_HEADER_SIZE: usize = 5;
+const BROADCAST_CID: [u8; 4] = [0xff, 0xff, 0xff, 0xff];
+
+const CTAPHID_INIT: u8 = 0x86;
+const CTAPHID_CBOR: u8 = 0x90;
+const CTAPHID_KEEPALIVE: u8 = 0xbb;
+const CTAPHID_ERROR: u8 = 0xbf;
+
+const CTAP2_GET_ASSERTION: u8 = 0x02;
+const CTAP2_OK: u8 = 0x00;
+
+// Talks CTAP2 to the first FIDO security key attached via USB HID
+#[derive(Default)]
+pub struct HidAuthenticator {}
+
+impl Authenticator for HidAuthenticator {
+    fn get_assertion(&self, request: &AssertionRequest) -> Result<Assertion> {
+        let payload = encode_get_assertion(request)?;
+
+        eprintln!("Please touch your security key");
+
+        let response = device::transact(CTAPHID_CBOR, &payload)?;
+        decode_get_assertion(&response)
+    }
+}
+
+fn encode_get_assertion(request: &AssertionRequest) -> Result<Vec<u8>> {
+    let allow_list = request
+        .credential_ids
+        .iter()
+        .map(|id| {
+            let id = b64url
+                .decode(id.trim_end_matches('='))
+                .with_context(|| format!("Unable to decode credential ID {}", id))?;
+            Ok(Value::Map(vec![
+                (Value::Text("id".to_string()), Value::Bytes(id)),
+                (
+                    Value::Text("type".to_string()),
+                    Value::Text("public-key".to_string()),
+                ),
+            ]))
+        })
+        .collect::<Result<Vec<Value>>>()?;
+
+    let mut parameters = vec![
+        (Value::Integer(1.into()), Value::Text(request.rp_id.clone())),
+        (
+            Value::Integer(2.into()),
+            Value::Bytes(request.client_data_hash.clone()),
+        ),
+    ];
+    if !allow_list.is_empty() {
+        parameters.push((Value::Integer(3.into()), Value::Array(allow_list)));
+    }
+    parameters.push((
+        Value::Integer(5.into()),
+        Value::Map(vec![(Value::Text("up".to_string()), Value::Bool(true))]),
+    ));
+
+    let mut payload = vec![CTAP2_GET_ASSERTION];
+    ciborium::ser::into_writer(&Value::Map(parameters), &mut payload)
+        .map_err(|e| anyhow!("Unable to encode CTAP2 request: {}", e))?;
+
+    Ok(payload)
+}
+
+fn decode_get_assertion(response: &[u8]) -> Result<Assertion> {
+    match response.split_first() {
+        Some((&CTAP2_OK, body)) => {
+            let value: Value = ciborium::de::from_reader(body)
+                .map_err(|e| anyhow!("Unable to decode CTAP2 response: {}", e))?;
+            let map = value
+                .into_map()
+                .map_err(|_| anyhow!("Unexpected CTAP2 response layout"))?;
+
+            let field = |key: u8| {
+                map.iter()
+                    .find(|(k, _)| *k == Value::Integer(key.into()))
+                    .and_then(|(_, v)| v.as_bytes().cloned())
+                    .with_context(|| format!("Missing field {} in CTAP2 response", key))
+            };
+
+            Ok(Assertion {
+                authenticator_data: field(2)?,
+                signature: field(3)?,
+            })
+        }
+        Some((status, _)) => Err(anyhow!(
+            "Security key returned CTAP2 error 0x{:02x}",
+            status
+        )),
+        None => Err(anyhow!("Empty response from security key")),
+    }
+}
+
+fn frame(cid: &[u8; 4], command: u8, payload: &[u8]) -> Vec<[u8; REPORT_SIZE]> {
+    let mut packets = vec![];
+    let mut packet = [0u8; REPORT_SIZE];
+    packet[..4].copy_from_slice(cid);
+    packet[4] = command;
+    packet[5..7].copy_from_slice(&(payload.len() as u16).to_be_bytes());
+
+    let (first, mut rest) = payload.split_at(payload.len().min(REPORT_SIZE - INIT_HEADER_SIZE));
+    packet[INIT_HEADER_SIZE..INIT_HEADER_SIZE + first.len()].copy_from_slice(first);
+    packets.push(packet);
+
+    let mut sequence = 0u8;
+    while !rest.is_empty() {
+        let (chunk, remaining) = rest.split_at(rest.len().min(REPORT_SIZE - CONT_HEADER_SIZE));
+        let mut packet = [0u8; REPORT_SIZE];
+        packet[..4].copy_from_slice(cid);
+        packet[4] = sequence;
+        packet[CONT_HEADER_SIZE..CONT_HEADER_SIZE + chunk.len()].copy_from_slice(chunk);
+        packets.push(packet);
+        sequence += 1;
+        rest = remaining;
+    }
+
+    packets
+}
+
+#[cfg(target_os = "linux")]
+mod device {
+    use super::*;
+    use ring::rand::{SecureRandom, SystemRandom};
+    use std::fs::{self, File, OpenOptions};
+    use std::io::{Read, Write};
+    use std::path::PathBuf;
+
+    // HID usage page 0xF1D0 (FIDO Alliance), as it appears in a report descriptor
+    const FIDO_USAGE_PAGE: [u8; 3] = [0x06, 0xd0, 0xf1];
+
+    pub fn transact(command: u8, payload: &[u8]) -> Result<Vec<u8>> {
+        let path = find_device()?;
+        debug!("Using security key at {:?}", path);
+
+        let mut device = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(&path)
+            .with_context(|| format!("Unable to open security key at {:?}", path))?;
+
+        let nonce: [u8; 8] = rand_nonce()?;
+        let init = exchange(&mut device, &BROADCAST_CID, CTAPHID_INIT, &nonce)?;
+        if init.len() < 12 || init[..8] != nonce {
+            return Err(anyhow!("Security key sent an invalid channel response"));
+        }
+
+        let mut cid = [0u8; 4];
+        cid.copy_from_slice(&init[8..12]);
+
+        exchange(&mut device, &cid, command, payload)
+    }
+
+    fn exchange(device: &mut File, cid: &[u8; 4], command: u8, payload: &[u8]) -> Result<Vec<u8>> {
+        for packet in frame(cid, command, payload) {
+            // The leading zero is the HID report ID
+            let mut report = vec![0u8];
+            report.extend_from_slice(&packet);
+            device.write_all(&report)?;
+        }
+
+        loop {
+            let packet = read_packet(device, cid)?;
+            match packet[4] {
+                CTAPHID_KEEPALIVE => continue,
+                CTAPHID_ERROR => {
+                    return Err(anyhow!(
+                        "Security key returned HID error 0x{:02x}",
+                        packet[7]
+                    ))
+                }
+                c if c == command => return read_message(device, cid, &packet),
+                c => return Err(anyhow!("Unexpected HID command 0x{:02x}", c)),
+            }
+        }
+    }
+
+    fn read_message(
+        device: &mut File,
+        cid: &[u8; 4],
+        first: &[u8; REPORT_SIZE],
+    ) -> Result<Vec<u8>> {
+        let length = u16::from_be_bytes([first[5], first[6]]) as usize;
+        let mut message = first[INIT_HEADER_SIZE..].to_vec();
+
+        while message.len() < length {
+            let packet = read_packet(device, cid)?;
+            message.extend_from_slice(&packet[CONT_HEADER_SIZE..]);
+        }
+
+        message.truncate(length);
+        Ok(message)
+    }
+
+    fn read_packet(device: &mut File, cid: &[u8; 4]) -> Result<[u8; REPORT_SIZE]> {
+        loop {
+            let mut packet = [0u8; REPORT_SIZE];
+            device.read_exact(&mut packet)?;
+            if packet[..4] == cid[..] {
+                return Ok(packet);
+            }
+        }
+    }
+
+    fn find_device() -> Result<PathBuf> {
+        for entry in fs::read_dir("/sys/class/hidraw")? {
+            let entry = entry?;
+            let descriptor = fs::read(entry.path().join("device/report_descriptor"))?;
+
+            if descriptor
+                .windows(FIDO_USAGE_PAGE.len())
+                .any(|w| w == FIDO_USAGE_PAGE)
+            {
+                return Ok(PathBuf::from("/dev").join(entry.file_name()));
+            }
+        }
+
+        Err(anyhow!("No FIDO security key found"))
+    }
+
+    fn rand_nonce() -> Result<[u8; 8]> {
+        let mut nonce = [0u8; 8];
+        SystemRandom::new()
+            .fill(&mut nonce)
+            .map_err(|_| anyhow!("Unable to generate channel nonce"))?;
+
+        Ok(nonce)
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+mod device {
+    use super::*;
+
+    pub fn transact(_command: u8, _payload: &[u8]) -> Result<Vec<u8>> {
+        debug!("No HID transport for security keys on this platform");
+        Err(anyhow!(
+            "Security keys are currently only supported on Linux"
+        ))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn frames_long_payload_into_continuation_packets() {
+        let payload = vec![1u8; 100];
+        let packets = frame(&[1, 2, 3, 4], CTAPHID_CBOR, &payload);
+
+        assert_eq!(2, packets.len());
+        assert_eq!([0x00, 0x64], packets[0][5..7]);
+        assert_eq!(0, packets[1][4]);
+        assert_eq!(1, packets[1][CONT_HEADER_SIZE + 100 - 58]);
+        assert_eq!(0, packets[1][CONT_HEADER_SIZE + 100 - 57]);
+    }
+
+    #[test]
+    fn encodes_and_decodes_get_assertion() -> Result<()> {
+        let request = AssertionRequest {
+            rp_id: "example.okta.com".to_string(),
+            client_data_hash: vec![0; 32],
+            credential_ids: vec!["AQID".to_string()],
+        };
+
+        let payload = encode_get_assertion(&request)?;
+        assert_eq!(CTAP2_GET_ASSERTION, payload[0]);
+
+        let value: Value = ciborium::de::from_reader(&payload[1..])?;
+        let map = value.into_map().unwrap();
+        assert_eq!(Value::Text("example.okta.com".to_string()), map[0].1);
+
+        let mut response = vec![CTAP2_OK];
+        ciborium::ser::into_writer(
+            &Value::Map(vec![
+                (Value::Integer(2.into()), Value::Bytes(vec![1, 2])),
+                (Value::Integer(3.into()), Value::Bytes(vec![3, 4])),
+            ]),
+            &mut response,
+        )?;
+
+        let assertion = decode_get_assertion(&response)?;
+        assert_eq!(vec![1, 2], assertion.authenticator_data);
+        assert_eq!(vec![3, 4], assertion.signature);
+
+        Ok(())
+    }
+}
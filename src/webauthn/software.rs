@@ -0,0 +1,111 @@
+use crate::webauthn::{Assertion, AssertionRequest, Authenticator};
+
+use anyhow::{anyhow, Result};
+use p256::ecdsa::{signature::Signer, Signature, SigningKey, VerifyingKey};
+use sha2::Digest;
+use std::cell::Cell;
+
+// User present and user verified
+const AUTHENTICATOR_FLAGS: u8 = 0x05;
+
+// A CTAP2 stand-in keeping a single P-256 credential in memory. It produces
+// the same authenticator data and signature layout as a hardware key would.
+pub struct SoftwareAuthenticator {
+    credential_id: String,
+    signing_key: SigningKey,
+    sign_count: Cell<u32>,
+}
+
+impl SoftwareAuthenticator {
+    pub fn from_secret(credential_id: &str, secret: &[u8]) -> Result<Self> {
+        let signing_key =
+            SigningKey::from_slice(secret).map_err(|e| anyhow!("Invalid credential key: {}", e))?;
+
+        Ok(SoftwareAuthenticator {
+            credential_id: credential_id.to_string(),
+            signing_key,
+            sign_count: Cell::new(0),
+        })
+    }
+
+    pub fn verifying_key(&self) -> VerifyingKey {
+        *self.signing_key.verifying_key()
+    }
+}
+
+impl Authenticator for SoftwareAuthenticator {
+    fn get_assertion(&self, request: &AssertionRequest) -> Result<Assertion> {
+        if !request.credential_ids.is_empty()
+            && !request.credential_ids.contains(&self.credential_id)
+        {
+            return Err(anyhow!(
+                "No matching credential found on the software authenticator"
+            ));
+        }
+
+        let sign_count = self.sign_count.get() + 1;
+        self.sign_count.set(sign_count);
+
+        let mut authenticator_data = sha2::Sha256::digest(request.rp_id.as_bytes()).to_vec();
+        authenticator_data.push(AUTHENTICATOR_FLAGS);
+        authenticator_data.extend_from_slice(&sign_count.to_be_bytes());
+
+        let mut message = authenticator_data.clone();
+        message.extend_from_slice(&request.client_data_hash);
+        let signature: Signature = self.signing_key.sign(&message);
+
+        Ok(Assertion {
+            authenticator_data,
+            signature: signature.to_der().as_bytes().to_vec(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::webauthn::{client_data_hash, ClientData};
+    use p256::ecdsa::signature::Verifier;
+
+    #[test]
+    fn signs_assertion_for_known_credential() -> Result<()> {
+        let authenticator = SoftwareAuthenticator::from_secret("credential-id", &[7u8; 32])?;
+        let client_data = ClientData::get("challenge", "https://example.okta.com").to_json()?;
+        let request = AssertionRequest {
+            rp_id: "example.okta.com".to_string(),
+            client_data_hash: client_data_hash(&client_data),
+            credential_ids: vec!["credential-id".to_string()],
+        };
+
+        let assertion = authenticator.get_assertion(&request)?;
+        let mut message = assertion.authenticator_data.clone();
+        message.extend_from_slice(&request.client_data_hash);
+        let signature = Signature::from_der(&assertion.signature)?;
+
+        assert_eq!(37, assertion.authenticator_data.len());
+        assert_eq!(
+            sha2::Sha256::digest(b"example.okta.com").to_vec(),
+            assertion.authenticator_data[..32]
+        );
+        assert!(authenticator
+            .verifying_key()
+            .verify(&message, &signature)
+            .is_ok());
+
+        Ok(())
+    }
+
+    #[test]
+    fn refuses_unknown_credential() -> Result<()> {
+        let authenticator = SoftwareAuthenticator::from_secret("credential-id", &[7u8; 32])?;
+        let request = AssertionRequest {
+            rp_id: "example.okta.com".to_string(),
+            client_data_hash: vec![0; 32],
+            credential_ids: vec!["other-credential".to_string()],
+        };
+
+        assert!(authenticator.get_assertion(&request).is_err());
+
+        Ok(())
+    }
+}
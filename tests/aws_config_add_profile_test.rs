@@ -26,6 +26,8 @@ fn adds_profile_to_file() -> Result<()> {
         Some(format!("sh -c 'crowbar creds {} -p 2> /dev/tty'", profile_name).as_str()),
         new_config.profiles.get_from(Some(section), PROFILE_KEY)
     );
+    assert!(new_config.runs_crowbar(profile_name));
+    assert!(!new_config.runs_crowbar("legacy-tools"));
 
     env::remove_var(AWS_CONFIG_FILE);
     Ok(())
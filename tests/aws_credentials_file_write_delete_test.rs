@@ -0,0 +1,70 @@
+extern crate crowbar;
+
+mod common;
+
+use anyhow::Result;
+use crowbar::config::aws::{
+    AwsCredentialsFile, ACCESS_KEY_ID_KEY, AWS_SHARED_CREDENTIALS_FILE, CROWBAR_PROFILE_KEY,
+    EXPIRATION_KEY, SECRET_ACCESS_KEY_KEY, SESSION_TOKEN_KEY,
+};
+use std::env;
+use std::io::Write;
+use tempfile::NamedTempFile;
+
+#[test]
+fn writes_and_deletes_credentials_in_file() -> Result<()> {
+    let mut file = NamedTempFile::new()?;
+    let location = file.path().to_path_buf();
+    let source = &common::short_app_profile_a().name;
+    let profile_name = "legacy-tools";
+
+    writeln!(
+        file,
+        "[default]\naws_access_key_id=AKIADEFAULT\naws_secret_access_key=default\n\n[{}]\nregion=eu-central-1",
+        profile_name
+    )?;
+
+    env::set_var(AWS_SHARED_CREDENTIALS_FILE, &location);
+
+    AwsCredentialsFile::new()?
+        .set_credentials(profile_name, source, &common::create_credentials())?
+        .write()?;
+
+    let credentials_file = AwsCredentialsFile::new()?;
+    let get = |key| credentials_file.profiles.get_from(Some(profile_name), key);
+
+    assert_eq!(get(ACCESS_KEY_ID_KEY), Some("some_key"));
+    assert_eq!(get(SECRET_ACCESS_KEY_KEY), Some("some_secret"));
+    assert_eq!(get(SESSION_TOKEN_KEY), Some("some_token"));
+    assert_eq!(get(EXPIRATION_KEY), Some("2038-01-01T10:10:10Z"));
+    assert_eq!(get(CROWBAR_PROFILE_KEY), Some(source.as_str()));
+    assert_eq!(get("region"), Some("eu-central-1"));
+    assert_eq!(credentials_file.written_for(source), vec![profile_name]);
+
+    let credentials_file = credentials_file.delete_profile(source)?.write()?;
+    assert_eq!(
+        credentials_file
+            .profiles
+            .get_from(Some(profile_name), ACCESS_KEY_ID_KEY),
+        None
+    );
+    assert!(credentials_file.has_profile(profile_name));
+
+    // Sections holding nothing but credentials go away with them
+    credentials_file
+        .set_credentials("ephemeral", source, &common::create_credentials())?
+        .write()?;
+    AwsCredentialsFile::new()?.delete_profile(source)?.write()?;
+
+    let credentials_file = AwsCredentialsFile::new()?;
+    assert!(!credentials_file.has_profile("ephemeral"));
+    assert_eq!(
+        credentials_file
+            .profiles
+            .get_from(Some("default"), ACCESS_KEY_ID_KEY),
+        Some("AKIADEFAULT")
+    );
+
+    env::remove_var(AWS_SHARED_CREDENTIALS_FILE);
+    Ok(())
+}
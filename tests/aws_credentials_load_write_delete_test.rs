@@ -1,15 +1,18 @@
 extern crate crowbar;
-extern crate keyring;
 
 mod common;
 
-use anyhow::{anyhow, Result};
+use anyhow::Result;
 use crowbar::credentials::aws;
 use crowbar::credentials::aws::AwsCredentials;
+use crowbar::credentials::store::{self, NullStore};
 use crowbar::credentials::Credential;
+use std::collections::HashMap;
+use std::sync::Arc;
 
 #[test]
 fn load_non_existing_credentials() -> Result<()> {
+    let _store = common::use_memory_store();
     let app_profile = common::short_app_profile_a();
     let creds = AwsCredentials::load(&app_profile)?;
 
@@ -20,15 +23,14 @@ fn load_non_existing_credentials() -> Result<()> {
 
 #[test]
 fn handles_credentials_with_keystore() -> Result<()> {
+    let _store = common::use_memory_store();
     let app_profile = common::short_app_profile_b();
     let creds = common::create_credentials();
 
     let creds = creds.write(&app_profile)?;
 
     let service = aws::credentials_as_service(&app_profile);
-    let value = keyring::Entry::new(&service, "access_key_id")
-        .get_password()
-        .map_err(|_e| anyhow!("Test failed!"))?;
+    let value = store::current().get(&service, "access_key_id")?;
 
     assert_eq!(creds.access_key_id.unwrap(), value);
 
@@ -44,3 +46,68 @@ fn handles_credentials_with_keystore() -> Result<()> {
 
     Ok(())
 }
+
+#[test]
+fn migrates_credentials_cached_by_profile_name() -> Result<()> {
+    let _store = common::use_memory_store();
+
+    let app_profile = common::short_app_profile_a();
+    let legacy_service = aws::legacy_credentials_as_service(&app_profile);
+    let creds = common::create_credentials();
+    let credential_map: HashMap<String, Option<String>> = creds.clone().into();
+    for (key, value) in credential_map {
+        store::current().set(&legacy_service, &key, &value.unwrap())?;
+    }
+
+    aws::migrate_legacy_credentials(&app_profile)?;
+    let migrated = AwsCredentials::load(&app_profile)?;
+    let legacy = store::current().get(&legacy_service, "access_key_id");
+    let _res = AwsCredentials::default().delete(&app_profile)?;
+
+    assert_eq!(migrated, creds);
+    assert!(legacy.is_err());
+
+    Ok(())
+}
+
+#[test]
+fn keeps_credentials_shared_with_other_profiles() -> Result<()> {
+    let _store = common::use_memory_store();
+    let app_profile = common::short_app_profile_a();
+    let mut sharing_profile = app_profile.clone();
+    sharing_profile.name = "sharing".to_string();
+    let profiles = [app_profile.clone(), sharing_profile.clone()];
+
+    common::create_credentials().write(&app_profile)?;
+    aws::delete_credentials(&app_profile, &profiles)?;
+
+    assert_eq!(
+        AwsCredentials::load(&sharing_profile)?,
+        common::create_credentials()
+    );
+
+    aws::delete_credentials(&sharing_profile, &profiles[1..])?;
+
+    assert_eq!(
+        AwsCredentials::load(&sharing_profile)?,
+        common::empty_credentials()
+    );
+
+    Ok(())
+}
+
+#[test]
+fn never_persists_credentials_without_cache() -> Result<()> {
+    let _store = common::use_store(Arc::new(NullStore));
+    let app_profile = common::short_app_profile_b();
+
+    let creds = common::create_credentials().write(&app_profile)?;
+
+    assert_eq!(creds, common::create_credentials());
+    assert_eq!(
+        AwsCredentials::load(&app_profile)?,
+        common::empty_credentials()
+    );
+
+    Ok(())
+}
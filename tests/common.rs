@@ -1,10 +1,61 @@
 extern crate crowbar;
-extern crate keyring;
 
+use base64::{engine::general_purpose::STANDARD as b64, Engine as _};
+use chrono::{DateTime, Duration, SecondsFormat, Utc};
 use crowbar::config::app::AppProfile;
 use crowbar::credentials::aws;
 use crowbar::credentials::aws::AwsCredentials;
+use crowbar::credentials::store::{self, EncryptedFileStore, MemoryStore, SecretStore, Unlock};
 use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, MutexGuard};
+use tempfile::TempDir;
+
+#[allow(dead_code)]
+pub const STORE_KEY: &str = "AAECAwQFBgcICQoLDA0ODxAREhMUFRYXGBkaGxwdHh8=";
+
+// The store is shared by the whole test binary, so tests using it take turns
+static STORE_LOCK: Mutex<()> = Mutex::new(());
+
+#[allow(dead_code)]
+pub struct TestStore {
+    pub path: PathBuf,
+    _dir: TempDir,
+    _lock: MutexGuard<'static, ()>,
+}
+
+// Makes credentials go to the given store until the returned guard is dropped
+#[allow(dead_code)]
+pub fn use_store(secret_store: Arc<dyn SecretStore>) -> MutexGuard<'static, ()> {
+    let lock = STORE_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+    store::use_store(secret_store);
+
+    lock
+}
+
+#[allow(dead_code)]
+pub fn use_memory_store() -> MutexGuard<'static, ()> {
+    use_store(Arc::new(MemoryStore::default()))
+}
+
+// Like use_memory_store, but with an encrypted file other processes can
+// read as well
+#[allow(dead_code)]
+pub fn use_file_store() -> TestStore {
+    let dir = TempDir::new().unwrap();
+    let path = dir.path().join("secrets.json");
+    let key = b64.decode(STORE_KEY).unwrap();
+    let lock = use_store(Arc::new(EncryptedFileStore::new(
+        path.clone(),
+        Unlock::Key(key),
+    )));
+
+    TestStore {
+        path,
+        _dir: dir,
+        _lock: lock,
+    }
+}
 
 #[allow(dead_code)]
 pub fn short_app_profile_a() -> AppProfile {
@@ -83,8 +134,67 @@ pub fn clean_keystore(profile: &AppProfile, creds: AwsCredentials) {
     let service = aws::credentials_as_service(profile);
 
     for key in credential_map.keys() {
-        keyring::Entry::new(&service, key)
-            .delete_password()
-            .unwrap()
+        store::current().delete(&service, key).unwrap()
     }
 }
+
+// Stores the IdP password the way crowbar does after the first login
+#[allow(dead_code)]
+pub fn store_password(profile: &AppProfile, password: &str) {
+    store::current()
+        .set(
+            &format!("crowbar::config::{}", profile),
+            &profile.username,
+            password,
+        )
+        .unwrap()
+}
+
+#[allow(dead_code)]
+pub fn clean_password(profile: &AppProfile) {
+    let _ = store::current().delete(&format!("crowbar::config::{}", profile), &profile.username);
+}
+
+// The page an IdP answers with for the AWS app, carrying an assertion that is
+// valid right now
+#[allow(dead_code)]
+pub fn saml_page(role_arns: &[&str]) -> String {
+    let now = Utc::now();
+    let timestamp = |t: DateTime<Utc>| t.to_rfc3339_opts(SecondsFormat::Secs, true);
+    let roles = role_arns
+        .iter()
+        .map(|r| {
+            format!(
+                "<saml2:AttributeValue>arn:aws:iam::123456789012:saml-provider/mock-idp,{}</saml2:AttributeValue>",
+                r
+            )
+        })
+        .collect::<String>();
+
+    let assertion = format!(
+        r#"<saml2p:Response xmlns:saml2p="urn:oasis:names:tc:SAML:2.0:protocol" ID="id-response" Version="2.0">
+  <saml2:Assertion xmlns:saml2="urn:oasis:names:tc:SAML:2.0:assertion" ID="id-assertion" Version="2.0">
+    <saml2:Issuer>http://idp.example.com</saml2:Issuer>
+    <saml2:Conditions NotBefore="{}" NotOnOrAfter="{}">
+      <saml2:AudienceRestriction>
+        <saml2:Audience>urn:amazon:webservices</saml2:Audience>
+      </saml2:AudienceRestriction>
+    </saml2:Conditions>
+    <saml2:AttributeStatement>
+      <saml2:Attribute Name="https://aws.amazon.com/SAML/Attributes/Role">{}</saml2:Attribute>
+      <saml2:Attribute Name="https://aws.amazon.com/SAML/Attributes/SessionDuration">
+        <saml2:AttributeValue>3600</saml2:AttributeValue>
+      </saml2:Attribute>
+    </saml2:AttributeStatement>
+  </saml2:Assertion>
+</saml2p:Response>"#,
+        timestamp(now - Duration::minutes(1)),
+        timestamp(now + Duration::minutes(5)),
+        roles
+    );
+
+    format!(
+        r#"<html><body><form method="post" action="https://signin.aws.amazon.com/saml"><input type="hidden" name="SAMLResponse" value="{}"/></form></body></html>"#,
+        b64.encode(assertion)
+    )
+}
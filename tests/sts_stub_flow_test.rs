@@ -0,0 +1,452 @@
+extern crate crowbar;
+
+mod common;
+
+use anyhow::Result;
+use crowbar::aws::sts::{FakeStsCall, FakeStsClient, FAKE_STS_VARIABLE};
+use crowbar::config::app::AppProfile;
+use crowbar::config::CrowbarConfig;
+use crowbar::credentials::aws::{self, AwsCredentials};
+use crowbar::credentials::store::{self, MemoryStore, StoreBackend, STORE_KEY_VARIABLE};
+use crowbar::credentials::Credential;
+use crowbar::providers::okta::Pipeline;
+use crowbar::providers::ProviderRegistry;
+use mockito::{Matcher, Server};
+use std::collections::HashMap;
+use std::process::Command;
+use std::sync::Arc;
+use tempfile::NamedTempFile;
+
+const APP_PATH: &str = "/home/amazon_aws/0oa1b2c3d4/272";
+const ROLE: &str = "arn:aws:iam::123456789012:role/developer";
+
+fn okta_profile(server: &Server, name: &str, extra: &str) -> AppProfile {
+    toml::from_str(&format!(
+        r#"
+        name = "{}"
+        provider = "okta"
+        url = "{}{}"
+        username = "jdoe"
+        role = "{}"
+        okta_pipeline = "classic"
+        {}
+    "#,
+        name,
+        server.url(),
+        APP_PATH,
+        ROLE,
+        extra
+    ))
+    .unwrap()
+}
+
+fn registry_with(sts: &FakeStsClient) -> ProviderRegistry {
+    let sts = sts.clone();
+    let mut registry = ProviderRegistry::default();
+    registry.register_sts(move |_| Box::new(sts.clone()));
+    registry
+}
+
+fn write_config(profile: &AppProfile) -> Result<(NamedTempFile, String)> {
+    write_config_with_store(profile, StoreBackend::default())
+}
+
+fn write_config_with_store(
+    profile: &AppProfile,
+    store: StoreBackend,
+) -> Result<(NamedTempFile, String)> {
+    let file = NamedTempFile::new()?;
+    let location = file.path().to_str().unwrap().to_owned();
+    let mut config = CrowbarConfig::with_location(Some(location.clone())).add_profile(profile)?;
+    config.store = store;
+    config.write()?;
+
+    Ok((file, location))
+}
+
+#[test]
+fn fetches_saml_and_assumes_role_chain() -> Result<()> {
+    let mut server = Server::new();
+    let saml = server
+        .mock("GET", APP_PATH)
+        .with_body(common::saml_page(&[ROLE]))
+        .create();
+    let profile = okta_profile(
+        &server,
+        "chain",
+        r#"
+        duration = 7200
+
+        [[role_chain]]
+        role_arn = "arn:aws:iam::210987654321:role/workload"
+        "#,
+    );
+    let sts = FakeStsClient::new();
+
+    let provider = registry_with(&sts).create(&profile)?;
+    let credentials = provider.fetch_aws_credentials(&sts)?;
+
+    saml.assert();
+    assert!(credentials.valid());
+    assert_eq!(
+        sts.calls(),
+        vec![
+            FakeStsCall {
+                role_arn: ROLE.to_string(),
+                duration: Some(7200),
+            },
+            FakeStsCall {
+                role_arn: "arn:aws:iam::210987654321:role/workload".to_string(),
+                duration: Some(3600),
+            },
+        ]
+    );
+
+    Ok(())
+}
+
+#[test]
+fn falls_back_to_session_duration_of_idp() -> Result<()> {
+    let mut server = Server::new();
+    let _saml = server
+        .mock("GET", APP_PATH)
+        .with_body(common::saml_page(&[ROLE]))
+        .create();
+    let profile = okta_profile(&server, "fallback", "duration = 43200");
+    let sts = FakeStsClient::new().with_max_duration(3600);
+
+    let provider = registry_with(&sts).create(&profile)?;
+    let credentials = provider.fetch_aws_credentials(&sts)?;
+
+    assert!(credentials.valid());
+    assert_eq!(
+        sts.calls()
+            .into_iter()
+            .map(|c| c.duration)
+            .collect::<Vec<Option<i32>>>(),
+        vec![Some(43200), Some(3600)]
+    );
+
+    Ok(())
+}
+
+#[test]
+fn reports_sts_errors() -> Result<()> {
+    let mut server = Server::new();
+    let _saml = server
+        .mock("GET", APP_PATH)
+        .with_body(common::saml_page(&[ROLE]))
+        .create();
+    let profile = okta_profile(&server, "failing", "");
+    let sts = FakeStsClient::new().with_error("Not authorized to perform sts:AssumeRoleWithSAML");
+
+    let provider = registry_with(&sts).create(&profile)?;
+    let error = provider.fetch_aws_credentials(&sts).unwrap_err();
+
+    assert_eq!(
+        format!("{:#}", error),
+        "Error assuming role: Not authorized to perform sts:AssumeRoleWithSAML"
+    );
+
+    Ok(())
+}
+
+#[test]
+fn logs_in_and_caches_credentials() -> Result<()> {
+    let _store = common::use_memory_store();
+
+    let mut server = Server::new();
+    let login = server
+        .mock("POST", "/api/v1/authn")
+        .match_body(Matcher::PartialJsonString(
+            r#"{"username": "jdoe", "password": "secret"}"#.to_string(),
+        ))
+        .with_body_from_file("tests/fixtures/okta/success_response.json")
+        .expect(1)
+        .create();
+    let saml = server
+        .mock("GET", APP_PATH)
+        .match_query(Matcher::UrlEncoded(
+            "sessionToken".into(),
+            "session-token".into(),
+        ))
+        .with_body(common::saml_page(&[ROLE]))
+        .expect(1)
+        .create();
+    let probe = server
+        .mock("GET", "/.well-known/okta-organization")
+        .expect(0)
+        .create();
+    let profile = okta_profile(&server, "caching", "");
+    let (_file, location) = write_config(&profile)?;
+    let sts = FakeStsClient::new();
+    let registry = registry_with(&sts);
+    common::store_password(&profile, "secret");
+
+    let fetch = || {
+        aws::fetch_aws_credentials_with_registry(
+            profile.name.clone(),
+            None,
+            None,
+            CrowbarConfig::with_location(Some(location.clone())),
+            false,
+            &registry,
+        )
+    };
+    let first = fetch();
+    let second = fetch();
+
+    assert_eq!(first?, second?);
+    login.assert();
+    saml.assert();
+    probe.assert();
+    assert_eq!(sts.calls().len(), 1);
+
+    Ok(())
+}
+
+#[test]
+fn keeps_detected_okta_pipeline() -> Result<()> {
+    let _store = common::use_memory_store();
+
+    let mut server = Server::new();
+    let probe = server
+        .mock("GET", "/.well-known/okta-organization")
+        .with_body(r#"{"id": "00o1b2c3d4", "pipeline": "v1"}"#)
+        .expect(1)
+        .create();
+    let login = server
+        .mock("POST", "/api/v1/authn")
+        .with_body_from_file("tests/fixtures/okta/success_response.json")
+        .expect(2)
+        .create();
+    let saml = server
+        .mock("GET", APP_PATH)
+        .match_query(Matcher::Any)
+        .with_body(common::saml_page(&[ROLE]))
+        .expect(2)
+        .create();
+    let mut profile = okta_profile(&server, "detecting", "");
+    profile.okta_pipeline = None;
+    let (_file, location) = write_config(&profile)?;
+    let sts = FakeStsClient::new();
+    let registry = registry_with(&sts);
+
+    // Nothing is cached for the second login, which has to go through Okta
+    for _ in 0..2 {
+        store::use_store(Arc::new(MemoryStore::default()));
+        common::store_password(&profile, "secret");
+        aws::fetch_aws_credentials_with_registry(
+            profile.name.clone(),
+            None,
+            None,
+            CrowbarConfig::with_location(Some(location.clone())),
+            false,
+            &registry,
+        )?;
+    }
+
+    probe.assert();
+    login.assert();
+    saml.assert();
+    assert_eq!(
+        CrowbarConfig::with_location(Some(location))
+            .read()?
+            .profiles[0]
+            .okta_pipeline,
+        Some(Pipeline::Classic)
+    );
+
+    Ok(())
+}
+
+#[test]
+fn switches_roles_with_cached_assertion() -> Result<()> {
+    let _store = common::use_memory_store();
+
+    let other_role = "arn:aws:iam::123456789012:role/auditor";
+    let mut server = Server::new();
+    let login = server
+        .mock("POST", "/api/v1/authn")
+        .with_body_from_file("tests/fixtures/okta/success_response.json")
+        .expect(1)
+        .create();
+    let saml = server
+        .mock("GET", APP_PATH)
+        .match_query(Matcher::Any)
+        .with_body(common::saml_page(&[ROLE, other_role]))
+        .expect(1)
+        .create();
+    let profile = okta_profile(&server, "switching", "");
+    let (_file, location) = write_config(&profile)?;
+    let sts = FakeStsClient::new();
+    let registry = registry_with(&sts);
+    common::store_password(&profile, "secret");
+
+    let fetch = |role: Option<&str>| {
+        aws::fetch_aws_credentials_with_registry(
+            profile.name.clone(),
+            role.map(|r| r.to_string()),
+            None,
+            CrowbarConfig::with_location(Some(location.clone())),
+            false,
+            &registry,
+        )
+    };
+    let first = fetch(None);
+    let second = fetch(Some(other_role));
+
+    assert_ne!(first?, second?);
+    login.assert();
+    saml.assert();
+    assert_eq!(
+        sts.calls()
+            .into_iter()
+            .map(|c| c.role_arn)
+            .collect::<Vec<String>>(),
+        vec![ROLE.to_string(), other_role.to_string()]
+    );
+
+    Ok(())
+}
+
+#[test]
+fn overrides_duration_per_call() -> Result<()> {
+    let _store = common::use_memory_store();
+
+    let mut server = Server::new();
+    let login = server
+        .mock("POST", "/api/v1/authn")
+        .with_body_from_file("tests/fixtures/okta/success_response.json")
+        .expect(1)
+        .create();
+    let saml = server
+        .mock("GET", APP_PATH)
+        .match_query(Matcher::Any)
+        .with_body(common::saml_page(&[ROLE]))
+        .expect(1)
+        .create();
+    let profile = okta_profile(&server, "durations", "");
+    let (_file, location) = write_config(&profile)?;
+    let sts = FakeStsClient::new();
+    let registry = registry_with(&sts);
+    common::store_password(&profile, "secret");
+
+    let fetch = |duration: Option<i32>| {
+        aws::fetch_aws_credentials_with_registry(
+            profile.name.clone(),
+            None,
+            duration,
+            CrowbarConfig::with_location(Some(location.clone())),
+            false,
+            &registry,
+        )
+    };
+    let default = fetch(None)?;
+    let longer = fetch(Some(7200))?;
+
+    // Credentials for different durations are cached apart
+    assert_ne!(default, longer);
+    assert_eq!(fetch(Some(7200))?, longer);
+    login.assert();
+    saml.assert();
+    assert_eq!(
+        sts.calls()
+            .into_iter()
+            .map(|c| c.duration)
+            .collect::<Vec<Option<i32>>>(),
+        vec![None, Some(7200)]
+    );
+
+    Ok(())
+}
+
+#[test]
+fn migrates_legacy_credentials_for_configured_role_only() -> Result<()> {
+    let _store = common::use_memory_store();
+
+    let server = Server::new();
+    let profile = okta_profile(&server, "legacy", "");
+    let (_file, location) = write_config(&profile)?;
+    let config = CrowbarConfig::with_location(Some(location));
+    let legacy_service = aws::legacy_credentials_as_service(&profile);
+    let credentials: HashMap<String, Option<String>> = common::create_credentials().into();
+    for (key, value) in credentials {
+        store::current().set(&legacy_service, &key, &value.unwrap())?;
+    }
+
+    let other_role = aws::find_profile(
+        &profile.name,
+        Some("arn:aws:iam::123456789012:role/auditor".to_string()),
+        None,
+        &config,
+    )?;
+    let configured = aws::find_profile(&profile.name, None, None, &config)?;
+
+    assert_eq!(
+        AwsCredentials::load(&other_role)?,
+        common::empty_credentials()
+    );
+    assert_eq!(
+        AwsCredentials::load(&configured)?,
+        common::create_credentials()
+    );
+
+    Ok(())
+}
+
+#[test]
+fn exec_runs_command_with_fake_credentials() -> Result<()> {
+    let store = common::use_file_store();
+
+    let mut server = Server::new();
+    let _login = server
+        .mock("POST", "/api/v1/authn")
+        .with_body_from_file("tests/fixtures/okta/success_response.json")
+        .create();
+    let _saml = server
+        .mock("GET", APP_PATH)
+        .match_query(Matcher::Any)
+        .with_body(common::saml_page(&[ROLE]))
+        .create();
+    let profile = okta_profile(&server, "exec", "");
+    let (_file, location) = write_config_with_store(
+        &profile,
+        StoreBackend::File {
+            path: Some(store.path.to_str().unwrap().to_owned()),
+        },
+    )?;
+    common::store_password(&profile, "secret");
+
+    let output = Command::new(env!("CARGO_BIN_EXE_crowbar"))
+        .args([
+            "-c",
+            &location,
+            "exec",
+            "exec",
+            "--",
+            "echo",
+            "$AWS_ACCESS_KEY_ID",
+        ])
+        .env(FAKE_STS_VARIABLE, "1")
+        .env(STORE_KEY_VARIABLE, common::STORE_KEY)
+        .env("SHELL", "/bin/sh")
+        .output();
+
+    let output = output?;
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    assert_eq!(
+        String::from_utf8_lossy(&output.stdout).trim(),
+        "ASIAFAKE000000000001"
+    );
+    // The fake credentials never reach the store
+    assert!(store::current()
+        .get(&aws::credentials_as_service(&profile), "access_key_id")
+        .is_err());
+
+    Ok(())
+}